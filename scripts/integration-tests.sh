@@ -0,0 +1,28 @@
+#!/usr/bin/env bash
+# Runs the backend's PocketIC integration tests against the ICRC-1 ledger.
+#
+# ICRC1_LEDGER_WASM    path to ic-icrc1-ledger.wasm(.gz); otherwise it is
+#                      downloaded from the IC release named by LEDGER_SUITE_TAG
+# POCKET_IC_BIN        optional path to the pocket-ic server binary
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+cd "$ROOT"
+
+cargo build -p rwa_backend --target wasm32-unknown-unknown --release
+export RWA_BACKEND_WASM="$ROOT/target/wasm32-unknown-unknown/release/rwa_backend.wasm"
+
+if [ -z "${ICRC1_LEDGER_WASM:-}" ]; then
+  if [ -z "${LEDGER_SUITE_TAG:-}" ]; then
+    echo "Set ICRC1_LEDGER_WASM or LEDGER_SUITE_TAG (an IC release tag, e.g. from https://github.com/dfinity/ic/releases)" >&2
+    exit 1
+  fi
+  ICRC1_LEDGER_WASM="$ROOT/target/ic-icrc1-ledger-$LEDGER_SUITE_TAG.wasm.gz"
+  if [ ! -f "$ICRC1_LEDGER_WASM" ]; then
+    curl -fsSL -o "$ICRC1_LEDGER_WASM" \
+      "https://github.com/dfinity/ic/releases/download/$LEDGER_SUITE_TAG/ic-icrc1-ledger.wasm.gz"
+  fi
+fi
+export ICRC1_LEDGER_WASM="$(cd "$(dirname "$ICRC1_LEDGER_WASM")" && pwd)/$(basename "$ICRC1_LEDGER_WASM")"
+
+cargo test -p rwa_backend --test payment_ledger -- --ignored "$@"
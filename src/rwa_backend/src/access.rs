@@ -0,0 +1,273 @@
+// Role-based access control. Principals hold any number of roles, each role
+// carries a fixed set of permissions, and every privileged endpoint checks a
+// permission. Grants and revocations are kept in an audit log.
+//
+// Canister controllers, bootstrap admins and users with the legacy Admin role
+// count as SuperAdmin.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use ic_stable_structures::StableLog;
+use crate::error::{RwaError, RwaResult};
+use crate::memory::{self, Memory};
+use crate::user;
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Role {
+    Issuer,
+    ComplianceOfficer,
+    Appraiser,
+    // Read-only access to every record
+    Auditor,
+    Support,
+    SuperAdmin,
+}
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Permission {
+    // Grant and revoke roles
+    ManageRoles,
+    // Read user records
+    ViewUsers,
+    ReviewKyc,
+    CreateAssets,
+    // Approve or reject assets and amendments
+    ReviewAssets,
+    // Act as the issuer of any asset and move it through its lifecycle
+    ManageAssets,
+    SubmitAppraisals,
+    ReviewAppraisals,
+    // Read other users' balances, orders, trades, portfolios and notifications
+    ViewAccounts,
+    // Cancel orders and trades, annotate portfolios and notifications for users
+    ManageAccounts,
+    // Transfer holdings or enter trades on another user's behalf
+    MoveHoldings,
+    // Credit off-chain funds to cash balances
+    CreditCash,
+    SendNotifications,
+    PostFxRates,
+    // Payment ledgers, ledger fees, FX oracles and staleness limits
+    ConfigurePlatform,
+    // Read the role audit log
+    ViewAudit,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 16] = [
+        Permission::ManageRoles,
+        Permission::ViewUsers,
+        Permission::ReviewKyc,
+        Permission::CreateAssets,
+        Permission::ReviewAssets,
+        Permission::ManageAssets,
+        Permission::SubmitAppraisals,
+        Permission::ReviewAppraisals,
+        Permission::ViewAccounts,
+        Permission::ManageAccounts,
+        Permission::MoveHoldings,
+        Permission::CreditCash,
+        Permission::SendNotifications,
+        Permission::PostFxRates,
+        Permission::ConfigurePlatform,
+        Permission::ViewAudit,
+    ];
+}
+
+impl Role {
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Issuer => &[CreateAssets],
+            Role::ComplianceOfficer => &[ViewUsers, ReviewKyc, ReviewAssets, ReviewAppraisals, ViewAccounts],
+            Role::Appraiser => &[SubmitAppraisals],
+            Role::Auditor => &[ViewUsers, ViewAccounts, ViewAudit],
+            Role::Support => &[ViewUsers, ViewAccounts, ManageAccounts, SendNotifications],
+            Role::SuperAdmin => &Permission::ALL,
+        }
+    }
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum RoleAction {
+    Granted,
+    Revoked,
+}
+
+// One grant or revocation, kept for auditing
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct RoleChange {
+    pub id: u64,
+    pub user_id: Principal,
+    pub role: Role,
+    pub action: RoleAction,
+    // Admin, or the canister itself for migrated grants
+    pub actor: Principal,
+    pub reason: Option<String>,
+    pub timestamp: u64,
+}
+
+thread_local! {
+    static GRANTS: RefCell<HashMap<Principal, BTreeSet<Role>>> = RefCell::new(HashMap::new());
+    // Append-only, in stable memory; an entry's id is its index
+    static AUDIT: RefCell<StableLog<RoleChange, Memory, Memory>> =
+        RefCell::new(memory::init_log(memory::ROLE_AUDIT_INDEX, memory::ROLE_AUDIT_DATA));
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct AccessState {
+    pub grants: HashMap<Principal, BTreeSet<Role>>,
+    // Only filled in snapshots before schema v6, which kept the log on the heap
+    pub audit: Vec<RoleChange>,
+}
+
+pub fn take_state() -> AccessState {
+    AccessState {
+        grants: GRANTS.with(|grants| std::mem::take(&mut *grants.borrow_mut())),
+        audit: Vec::new(),
+    }
+}
+
+pub fn restore_state(state: AccessState) {
+    GRANTS.with(|grants| *grants.borrow_mut() = state.grants);
+    for change in state.audit {
+        append_change(change);
+    }
+}
+
+fn is_super_admin(principal: &Principal) -> bool {
+    user::is_legacy_admin(principal) || holds(principal, Role::SuperAdmin)
+}
+
+fn holds(principal: &Principal, role: Role) -> bool {
+    GRANTS.with(|grants| grants.borrow().get(principal).is_some_and(|roles| roles.contains(&role)))
+}
+
+fn roles_of(principal: &Principal) -> Vec<Role> {
+    let mut roles: BTreeSet<Role> = GRANTS.with(|grants| grants.borrow().get(principal).cloned()).unwrap_or_default();
+    if user::is_legacy_admin(principal) {
+        roles.insert(Role::SuperAdmin);
+    }
+    roles.into_iter().collect()
+}
+
+// Helper: Check if `principal` holds a role carrying `permission`
+pub fn has_permission(principal: &Principal, permission: Permission) -> bool {
+    if is_super_admin(principal) {
+        return true;
+    }
+    GRANTS.with(|grants| {
+        grants.borrow().get(principal)
+            .is_some_and(|roles| roles.iter().any(|role| role.permissions().contains(&permission)))
+    })
+}
+
+// Helper: Fail with Unauthorized unless `principal` has `permission`
+pub fn require_permission(principal: &Principal, permission: Permission) -> RwaResult<()> {
+    if has_permission(principal, permission) { Ok(()) } else { Err(RwaError::Unauthorized) }
+}
+
+// Internal: Apply a grant or revocation without logging it. Returns false if
+// it changed nothing.
+pub fn apply_change(user_id: Principal, role: Role, action: &RoleAction) -> bool {
+    GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        match action {
+            RoleAction::Granted => grants.entry(user_id).or_default().insert(role),
+            RoleAction::Revoked => {
+                let removed = grants.get_mut(&user_id).is_some_and(|roles| roles.remove(&role));
+                grants.retain(|_, roles| !roles.is_empty());
+                removed
+            }
+        }
+    })
+}
+
+// Internal: Append a grant or revocation to the audit log
+pub fn log_change(user_id: Principal, role: Role, action: RoleAction, actor: Principal, reason: Option<String>) {
+    let id = AUDIT.with(|audit| audit.borrow().len());
+    append_change(RoleChange { id, user_id, role, action, actor, reason, timestamp: ic_cdk::api::time() });
+}
+
+fn append_change(change: RoleChange) {
+    AUDIT.with(|audit| audit.borrow().append(&change))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to append to the role audit log: {:?}", e)));
+}
+
+// Internal: Apply a grant or revocation and log it if it changed anything
+pub fn record_change(user_id: Principal, role: Role, action: RoleAction, actor: Principal, reason: Option<String>) -> bool {
+    let changed = apply_change(user_id, role, &action);
+    if changed {
+        log_change(user_id, role, action, actor, reason);
+    }
+    changed
+}
+
+fn change_role(user_id: Principal, role: Role, action: RoleAction, reason: Option<String>) -> RwaResult<Vec<Role>> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ManageRoles)?;
+    if reason.as_ref().is_some_and(|r| r.trim().is_empty()) {
+        return Err(RwaError::validation("reason", "must not be blank"));
+    }
+    let verb = if action == RoleAction::Granted { "granted" } else { "revoked" };
+    // Revoking would be logged but could not take effect
+    if role == Role::SuperAdmin && action == RoleAction::Revoked && user::is_permanent_admin(&user_id) {
+        return Err(RwaError::InvalidState(format!("{} is a controller or bootstrap admin and always holds SuperAdmin", user_id)));
+    }
+    // SuperAdmin may also be held through the legacy Admin user role
+    let demoted = role == Role::SuperAdmin && action == RoleAction::Revoked && user::demote_legacy_admin(&user_id);
+    if !apply_change(user_id, role, &action) && !demoted {
+        return Err(RwaError::InvalidState(format!("{:?} was already {} for {}", role, verb, user_id)));
+    }
+    log_change(user_id, role, action, caller, reason);
+    create_notification(user_id, NotificationType::Admin, format!("The {:?} role was {} for you", role, verb));
+    Ok(roles_of(&user_id))
+}
+
+// Admin: Give a principal a role
+#[ic_cdk::update]
+pub fn grant_role(user_id: Principal, role: Role, reason: Option<String>) -> RwaResult<Vec<Role>> {
+    change_role(user_id, role, RoleAction::Granted, reason)
+}
+
+// Admin: Take a role away from a principal
+#[ic_cdk::update]
+pub fn revoke_role(user_id: Principal, role: Role, reason: Option<String>) -> RwaResult<Vec<Role>> {
+    change_role(user_id, role, RoleAction::Revoked, reason)
+}
+
+#[ic_cdk::query]
+pub fn get_roles(user_id: Principal) -> RwaResult<Vec<Role>> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewUsers) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(roles_of(&user_id))
+}
+
+// Permissions the caller holds through all of their roles
+#[ic_cdk::query]
+pub fn my_permissions() -> Vec<Permission> {
+    let caller = ic_cdk::caller();
+    Permission::ALL.into_iter().filter(|p| has_permission(&caller, *p)).collect()
+}
+
+// Principals explicitly granted a role
+#[ic_cdk::query]
+pub fn list_role_holders(role: Role) -> RwaResult<Vec<Principal>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewUsers)?;
+    Ok(GRANTS.with(|grants| {
+        grants.borrow().iter().filter(|(_, roles)| roles.contains(&role)).map(|(p, _)| *p).collect()
+    }))
+}
+
+// Role grants and revocations, oldest first, optionally for one principal
+#[ic_cdk::query]
+pub fn list_role_changes(user_id: Option<Principal>) -> RwaResult<Vec<RoleChange>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAudit)?;
+    Ok(AUDIT.with(|audit| {
+        audit.borrow().iter().filter(|c| user_id.is_none_or(|u| c.user_id == u)).collect()
+    }))
+}
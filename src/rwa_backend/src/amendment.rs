@@ -0,0 +1,209 @@
+// Amendments to an approved asset's economics. The issuer proposes new values,
+// a reviewer checks them, and on approval every current token holder is told
+// what changed.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::access::{Permission, has_permission, require_permission};
+use crate::asset::{self, Asset, EconomicChanges};
+use crate::error::{RwaError, RwaResult};
+use crate::token;
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum AmendmentStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct Amendment {
+    pub id: u64,
+    pub asset_id: u64,
+    pub requested_by: Principal,
+    pub changes: EconomicChanges,
+    // Old and new values as they stood when the amendment was requested
+    pub diff: Vec<FieldChange>,
+    pub reason: String,
+    pub status: AmendmentStatus,
+    pub reviewer_id: Option<Principal>,
+    pub review_note: Option<String>,
+    pub created_at: u64,
+    pub reviewed_at: Option<u64>,
+}
+
+thread_local! {
+    static AMENDMENTS: RefCell<HashMap<u64, Amendment>> = RefCell::new(HashMap::new());
+    static AMENDMENT_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct AmendmentState {
+    pub amendments: HashMap<u64, Amendment>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> AmendmentState {
+    AmendmentState {
+        amendments: AMENDMENTS.with(|amendments| std::mem::take(&mut *amendments.borrow_mut())),
+        next_id: AMENDMENT_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: AmendmentState) {
+    AMENDMENTS.with(|amendments| *amendments.borrow_mut() = state.amendments);
+    AMENDMENT_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id.max(1));
+}
+
+fn save_amendment(amendment: &Amendment) {
+    AMENDMENTS.with(|amendments| amendments.borrow_mut().insert(amendment.id, amendment.clone()));
+}
+
+// Fields in `changes` whose value differs from the asset's current one
+pub fn diff(asset: &Asset, changes: &EconomicChanges) -> Vec<FieldChange> {
+    let fields = [
+        ("total_value", asset.total_value.to_string(), changes.total_value.map(|v| v.to_string())),
+        ("token_price", asset.token_price.to_string(), changes.token_price.map(|v| v.to_string())),
+        ("total_tokens", asset.total_tokens.to_string(), changes.total_tokens.map(|v| v.to_string())),
+        ("apy", asset.apy.to_string(), changes.apy.map(|v| v.to_string())),
+        ("monthly_income", asset.monthly_income.map_or_else(|| "none".to_string(), |v| v.to_string()), changes.monthly_income.map(|v| v.to_string())),
+    ];
+    fields.into_iter()
+        .filter_map(|(field, old_value, new_value)| {
+            new_value.filter(|new_value| *new_value != old_value).map(|new_value| FieldChange {
+                field: field.to_string(),
+                old_value,
+                new_value,
+            })
+        })
+        .collect()
+}
+
+// Issuer: Propose new economic terms for an approved asset
+#[ic_cdk::update]
+pub fn request_amendment(asset_id: u64, changes: EconomicChanges, reason: String) -> RwaResult<Amendment> {
+    let caller = ic_cdk::caller();
+    let asset = asset::get_asset(asset_id)?;
+    if asset.owner_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+        return Err(RwaError::Unauthorized);
+    }
+    if !asset.economics_locked() {
+        return Err(RwaError::InvalidState(format!("Asset #{} is {:?}; edit it with update_asset", asset_id, asset.status)));
+    }
+    if asset.is_closed() {
+        return Err(RwaError::InvalidState(format!("Asset #{} is {:?} and can no longer be edited", asset_id, asset.status)));
+    }
+    if reason.trim().is_empty() {
+        return Err(RwaError::validation("reason", "must not be empty"));
+    }
+    let diff = diff(&asset, &changes);
+    if diff.is_empty() {
+        return Err(RwaError::validation("changes", "must change at least one value"));
+    }
+    let id = AMENDMENT_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    let amendment = Amendment {
+        id,
+        asset_id,
+        requested_by: caller,
+        changes,
+        diff,
+        reason,
+        status: AmendmentStatus::Pending,
+        reviewer_id: None,
+        review_note: None,
+        created_at: ic_cdk::api::time(),
+        reviewed_at: None,
+    };
+    save_amendment(&amendment);
+    Ok(amendment)
+}
+
+// Reviewer: Apply a pending amendment and notify the asset's holders. Refused if
+// the asset changed since the amendment was requested.
+#[ic_cdk::update]
+pub fn approve_amendment(id: u64, note: Option<String>) -> RwaResult<Amendment> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewAssets)?;
+    let mut amendment = get_amendment(id)?;
+    if amendment.status != AmendmentStatus::Pending {
+        return Err(RwaError::InvalidState(format!("Amendment #{} is already {:?}", id, amendment.status)));
+    }
+    let current = asset::get_asset(amendment.asset_id)?;
+    if diff(&current, &amendment.changes) != amendment.diff {
+        return Err(RwaError::InvalidState(format!("Asset #{} changed since amendment #{} was requested", amendment.asset_id, id)));
+    }
+    let mut recipients: Vec<Principal> = token::holdings(current.id)?.into_keys().collect();
+    if !recipients.contains(&current.owner_id) {
+        recipients.push(current.owner_id);
+    }
+    let asset = asset::amend_economics(amendment.asset_id, amendment.changes.clone())?;
+    amendment.status = AmendmentStatus::Approved;
+    amendment.reviewer_id = Some(caller);
+    amendment.review_note = note;
+    amendment.reviewed_at = Some(ic_cdk::api::time());
+    save_amendment(&amendment);
+    let summary = amendment.diff.iter()
+        .map(|c| format!("{}: {} -> {}", c.field, c.old_value, c.new_value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let message = format!("The terms of '{}' were amended ({}): {}", asset.name, amendment.reason, summary);
+    for user in recipients {
+        create_notification(user, NotificationType::Investment, message.clone());
+    }
+    Ok(amendment)
+}
+
+// Reviewer: Turn down a pending amendment
+#[ic_cdk::update]
+pub fn reject_amendment(id: u64, note: String) -> RwaResult<Amendment> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewAssets)?;
+    if note.trim().is_empty() {
+        return Err(RwaError::validation("note", "must not be empty"));
+    }
+    let mut amendment = get_amendment(id)?;
+    if amendment.status != AmendmentStatus::Pending {
+        return Err(RwaError::InvalidState(format!("Amendment #{} is already {:?}", id, amendment.status)));
+    }
+    amendment.status = AmendmentStatus::Rejected;
+    amendment.reviewer_id = Some(caller);
+    amendment.review_note = Some(note.clone());
+    amendment.reviewed_at = Some(ic_cdk::api::time());
+    save_amendment(&amendment);
+    create_notification(amendment.requested_by, NotificationType::Admin, format!("Amendment #{} to asset #{} was rejected: {}", id, amendment.asset_id, note));
+    Ok(amendment)
+}
+
+#[ic_cdk::query]
+pub fn get_amendment(id: u64) -> RwaResult<Amendment> {
+    AMENDMENTS.with(|amendments| amendments.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("Amendment", id))
+}
+
+#[ic_cdk::query]
+pub fn list_amendments_by_asset(asset_id: u64) -> Vec<Amendment> {
+    AMENDMENTS.with(|amendments| amendments.borrow().values().filter(|a| a.asset_id == asset_id).cloned().collect())
+}
+
+// Reviewer: Amendments waiting for review
+#[ic_cdk::query]
+pub fn list_pending_amendments() -> RwaResult<Vec<Amendment>> {
+    require_permission(&ic_cdk::caller(), Permission::ReviewAssets)?;
+    Ok(AMENDMENTS.with(|amendments| {
+        amendments.borrow().values().filter(|a| a.status == AmendmentStatus::Pending).cloned().collect()
+    }))
+}
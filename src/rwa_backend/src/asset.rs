@@ -1,7 +1,14 @@
 use candid::Principal;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use crate::user::{is_admin, is_kyc_approved};
+use crate::access::{Permission, has_permission, require_permission};
+use crate::compliance::TransferRules;
+use crate::error::{RwaError, RwaResult};
+use crate::icrc;
+use crate::money::{BasisPoints, Currency, Money};
+use crate::orderbook;
+use crate::trade;
+use crate::user::require_kyc;
 use crate::notification::{create_notification, NotificationType};
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
@@ -14,17 +21,25 @@ pub struct Asset {
     pub location: String,
     pub images: Vec<String>,
     pub documents: Vec<String>,
-    pub total_value: u64,
-    pub token_price: u64,
+    // All amounts are in the currency of token_price
+    pub total_value: Money,
+    pub token_price: Money,
     pub total_tokens: u64,
     pub available_tokens: u64,
-    pub apy: f64,
+    pub apy: BasisPoints,
     pub status: AssetStatus,
-    pub launch_date: Option<String>,
-    pub funding_deadline: Option<String>,
-    pub monthly_income: Option<u64>,
+    // Nanoseconds since the epoch
+    pub launch_date: Option<u64>,
+    pub funding_deadline: Option<u64>,
+    pub monthly_income: Option<Money>,
     pub risk_rating: Option<String>,
     pub key_metrics: Option<KeyMetrics>,
+    // Valuation date of the accepted appraisal total_value comes from; None
+    // while it is still the issuer's figure
+    pub valued_at: Option<u64>,
+    // Restrictions on who may hold the asset and when units may move; None
+    // only requires holders to pass KYC
+    pub transfer_rules: Option<TransferRules>,
 }
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
@@ -35,19 +50,171 @@ pub enum AssetStatus {
     Active,
     Funding,
     Sold,
+    Delisted,
+}
+
+// One step in an asset's lifecycle, kept for auditing
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct AssetTransition {
+    pub asset_id: u64,
+    pub from: AssetStatus,
+    pub to: AssetStatus,
+    // Admin, issuer, or the canister itself for scheduled transitions
+    pub actor: Principal,
+    pub reason: Option<String>,
+    pub timestamp: u64,
 }
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
 pub struct KeyMetrics {
-    pub cap_rate: Option<f64>,
-    pub occupancy_rate: Option<f64>,
+    pub cap_rate: Option<BasisPoints>,
+    pub occupancy_rate: Option<BasisPoints>,
     pub location_score: Option<f64>,
     pub liquidity_rating: Option<String>,
 }
 
+// Changes to the fields that set an asset's economics. These are frozen once
+// the asset is approved and afterwards only change through an amendment.
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct EconomicChanges {
+    pub total_value: Option<Money>,
+    pub token_price: Option<Money>,
+    pub total_tokens: Option<u64>,
+    pub apy: Option<BasisPoints>,
+    pub monthly_income: Option<Money>,
+}
+
+impl EconomicChanges {
+    pub fn is_empty(&self) -> bool {
+        self.total_value.is_none() && self.token_price.is_none() && self.total_tokens.is_none()
+            && self.apy.is_none() && self.monthly_income.is_none()
+    }
+}
+
+impl Asset {
+    // Currency the asset is priced and valued in
+    pub fn currency(&self) -> Currency {
+        self.token_price.currency
+    }
+
+    // Tokens already minted against this asset
+    pub fn issued_tokens(&self) -> u64 {
+        self.total_tokens - self.available_tokens
+    }
+
+    // Statuses in which the asset has passed review and may carry tokens
+    pub fn is_issuable(&self) -> bool {
+        matches!(self.status, AssetStatus::Approved | AssetStatus::Funding | AssetStatus::Active)
+    }
+
+    // Economic fields are editable only until the asset passes review
+    pub fn economics_locked(&self) -> bool {
+        !matches!(self.status, AssetStatus::Pending | AssetStatus::Rejected)
+    }
+
+    // Sold and delisted assets are kept as a read-only record
+    pub fn is_closed(&self) -> bool {
+        matches!(self.status, AssetStatus::Sold | AssetStatus::Delisted)
+    }
+
+    // Orders and bilateral trades are only accepted and settled while the
+    // asset is past review, not closed, and has its ledger
+    pub fn check_tradable(&self) -> RwaResult<()> {
+        if !self.is_issuable() || !icrc::has_ledger(self.id) {
+            return Err(RwaError::InvalidState(format!("Asset #{} is {:?} and cannot be traded", self.id, self.status)));
+        }
+        Ok(())
+    }
+}
+
+pub const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
 thread_local! {
     static ASSETS: RefCell<HashMap<u64, Asset>> = RefCell::new(HashMap::new());
-    static ASSET_ID_COUNTER: RefCell<u64> = RefCell::new(1);
+    static ASSET_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+    static ASSET_HISTORY: RefCell<HashMap<u64, Vec<AssetTransition>>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct AssetState {
+    pub assets: HashMap<u64, Asset>,
+    pub next_id: u64,
+    pub history: Option<HashMap<u64, Vec<AssetTransition>>>,
+}
+
+pub fn take_state() -> AssetState {
+    AssetState {
+        assets: ASSETS.with(|assets| std::mem::take(&mut *assets.borrow_mut())),
+        next_id: ASSET_ID_COUNTER.with(|counter| *counter.borrow()),
+        history: Some(ASSET_HISTORY.with(|history| std::mem::take(&mut *history.borrow_mut()))),
+    }
+}
+
+pub fn restore_state(state: AssetState) {
+    ASSETS.with(|assets| *assets.borrow_mut() = state.assets);
+    ASSET_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id);
+    ASSET_HISTORY.with(|history| *history.borrow_mut() = state.history.unwrap_or_default());
+}
+
+// Lifecycle:
+//   Pending -> Approved | Rejected
+//   Approved -> Funding -> Active | Approved (failed round)
+//   Approved -> Active -> Sold
+//   anything but Funding -> Delisted
+fn is_allowed_transition(from: &AssetStatus, to: &AssetStatus) -> bool {
+    use AssetStatus::*;
+    matches!(
+        (from, to),
+        (Pending, Approved) | (Pending, Rejected)
+            | (Approved, Funding) | (Funding, Active) | (Funding, Approved)
+            | (Approved, Active) | (Active, Sold)
+            | (Pending | Approved | Active | Rejected | Sold, Delisted)
+    )
+}
+
+// Internal: Move an asset along its lifecycle, recording the step and telling
+// the owner. Callers check who may perform the transition.
+pub fn transition(asset_id: u64, to: AssetStatus, actor: Principal, reason: Option<String>) -> RwaResult<Asset> {
+    let message = match &reason {
+        Some(reason) => format!("is now {:?}: {}", to, reason),
+        None => format!("is now {:?}", to),
+    };
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let asset = assets.get_mut(&asset_id).ok_or_else(|| RwaError::not_found("Asset", asset_id))?;
+        if !is_allowed_transition(&asset.status, &to) {
+            return Err(RwaError::InvalidState(format!("Asset #{} cannot move from {:?} to {:?}", asset_id, asset.status, to)));
+        }
+        let step = AssetTransition {
+            asset_id,
+            from: std::mem::replace(&mut asset.status, to.clone()),
+            to,
+            actor,
+            reason,
+            timestamp: ic_cdk::api::time(),
+        };
+        ASSET_HISTORY.with(|history| history.borrow_mut().entry(asset_id).or_default().push(step));
+        Ok(asset.clone())
+    })?;
+    create_notification(asset.owner_id, NotificationType::Admin, format!("Your asset '{}' {}", asset.name, message));
+    Ok(asset)
+}
+
+// Schedule dates are the only times a client sets, and only ahead of now
+fn validate_schedule_date(field: &str, value: Option<u64>) -> RwaResult<()> {
+    match value {
+        Some(at) if at <= ic_cdk::api::time() => {
+            Err(RwaError::validation(field, "must be a future time in nanoseconds since the epoch"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn required_reason(reason: String) -> RwaResult<Option<String>> {
+    if reason.trim().is_empty() {
+        return Err(RwaError::validation("reason", "must not be empty"));
+    }
+    Ok(Some(reason))
 }
 
 #[ic_cdk::update]
@@ -58,20 +225,35 @@ pub fn create_asset(
     location: String,
     images: Vec<String>,
     documents: Vec<String>,
-    total_value: u64,
-    token_price: u64,
+    total_value: Money,
+    token_price: Money,
     total_tokens: u64,
-    apy: f64,
-    launch_date: Option<String>,
-    funding_deadline: Option<String>,
-    monthly_income: Option<u64>,
+    apy: BasisPoints,
+    launch_date: Option<u64>,
+    funding_deadline: Option<u64>,
+    monthly_income: Option<Money>,
     risk_rating: Option<String>,
     key_metrics: Option<KeyMetrics>,
-) -> Asset {
+) -> RwaResult<Asset> {
     let owner_id = ic_cdk::caller();
-    if !is_kyc_approved(&owner_id) {
-        ic_cdk::trap("KYC not approved");
+    require_permission(&owner_id, Permission::CreateAssets)?;
+    require_kyc(&owner_id)?;
+    if name.trim().is_empty() {
+        return Err(RwaError::validation("name", "must not be empty"));
     }
+    if total_tokens == 0 {
+        return Err(RwaError::validation("total_tokens", "must be greater than zero"));
+    }
+    if token_price.is_zero() {
+        return Err(RwaError::validation("token_price", "must be greater than zero"));
+    }
+    total_value.expect_currency(token_price.currency)?;
+    if let Some(income) = monthly_income {
+        income.expect_currency(token_price.currency)?;
+    }
+    validate_key_metrics(key_metrics.as_ref())?;
+    validate_schedule_date("launch_date", launch_date)?;
+    validate_schedule_date("funding_deadline", funding_deadline)?;
     let id = ASSET_ID_COUNTER.with(|counter| {
         let mut c = counter.borrow_mut();
         let id = *c;
@@ -98,14 +280,17 @@ pub fn create_asset(
         monthly_income,
         risk_rating,
         key_metrics,
+        valued_at: None,
+        transfer_rules: None,
     };
     ASSETS.with(|assets| assets.borrow_mut().insert(id, asset.clone()));
-    asset
+    Ok(asset)
 }
 
 #[ic_cdk::query]
-pub fn get_asset(id: u64) -> Option<Asset> {
+pub fn get_asset(id: u64) -> RwaResult<Asset> {
     ASSETS.with(|assets| assets.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("Asset", id))
 }
 
 #[ic_cdk::update]
@@ -117,40 +302,132 @@ pub fn update_asset(
     location: Option<String>,
     images: Option<Vec<String>>,
     documents: Option<Vec<String>>,
-    total_value: Option<u64>,
-    token_price: Option<u64>,
+    total_value: Option<Money>,
+    token_price: Option<Money>,
     total_tokens: Option<u64>,
-    apy: Option<f64>,
-    launch_date: Option<String>,
-    funding_deadline: Option<String>,
-    monthly_income: Option<u64>,
+    apy: Option<BasisPoints>,
+    launch_date: Option<u64>,
+    funding_deadline: Option<u64>,
+    monthly_income: Option<Money>,
     risk_rating: Option<String>,
     key_metrics: Option<KeyMetrics>,
-) -> Option<Asset> {
+) -> RwaResult<Asset> {
     let caller = ic_cdk::caller();
+    let economics = EconomicChanges { total_value, token_price, total_tokens, apy, monthly_income };
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let asset = assets.get_mut(&id).ok_or_else(|| RwaError::not_found("Asset", id))?;
+        if asset.owner_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+            return Err(RwaError::Unauthorized);
+        }
+        if asset.is_closed() {
+            return Err(RwaError::InvalidState(format!("Asset #{} is {:?} and can no longer be edited", id, asset.status)));
+        }
+        if asset.economics_locked() && !economics.is_empty() {
+            return Err(RwaError::validation("economics", "total_value, token_price, total_tokens, apy and monthly_income are frozen once the asset is approved; request an amendment instead"));
+        }
+        // A running round has already taken its deadline from the asset
+        if funding_deadline.is_some() && matches!(asset.status, AssetStatus::Funding | AssetStatus::Active) {
+            return Err(RwaError::validation("funding_deadline", format!("cannot change while the asset is {:?}", asset.status)));
+        }
+        validate_economics(asset, &economics)?;
+        validate_key_metrics(key_metrics.as_ref())?;
+        validate_schedule_date("launch_date", launch_date)?;
+        validate_schedule_date("funding_deadline", funding_deadline)?;
+        if let Some(v) = name { asset.name = v; }
+        if let Some(v) = description { asset.description = v; }
+        if let Some(v) = category { asset.category = v; }
+        if let Some(v) = location { asset.location = v; }
+        if let Some(v) = images { asset.images = v; }
+        if let Some(v) = documents { asset.documents = v; }
+        apply_economics(asset, economics);
+        if let Some(v) = launch_date { asset.launch_date = Some(v); }
+        if let Some(v) = funding_deadline { asset.funding_deadline = Some(v); }
+        if let Some(v) = risk_rating { asset.risk_rating = Some(v); }
+        if let Some(v) = key_metrics { asset.key_metrics = Some(v); }
+        Ok(asset.clone())
+    })
+}
+
+fn validate_economics(asset: &Asset, changes: &EconomicChanges) -> RwaResult<()> {
+    if changes.total_tokens == Some(0) {
+        return Err(RwaError::validation("total_tokens", "must be greater than zero"));
+    }
+    if changes.token_price.is_some_and(|v| v.is_zero()) {
+        return Err(RwaError::validation("token_price", "must be greater than zero"));
+    }
+    let issued = asset.issued_tokens();
+    if changes.total_tokens.is_some_and(|v| v < issued) {
+        return Err(RwaError::validation("total_tokens", format!("cannot be below the {} tokens already issued", issued)));
+    }
+    // Amounts left unchanged must already be in the (possibly new) currency
+    let currency = changes.token_price.map_or(asset.currency(), |v| v.currency);
+    changes.total_value.unwrap_or(asset.total_value).expect_currency(currency)?;
+    if let Some(income) = changes.monthly_income.or(asset.monthly_income) {
+        income.expect_currency(currency)?;
+    }
+    Ok(())
+}
+
+fn validate_key_metrics(metrics: Option<&KeyMetrics>) -> RwaResult<()> {
+    if metrics.and_then(|m| m.occupancy_rate).is_some_and(|v| v > BasisPoints::HUNDRED_PERCENT) {
+        return Err(RwaError::validation("occupancy_rate", "cannot exceed 100%"));
+    }
+    Ok(())
+}
+
+fn apply_economics(asset: &mut Asset, changes: EconomicChanges) {
+    let issued = asset.issued_tokens();
+    if let Some(v) = changes.total_value { asset.total_value = v; }
+    if let Some(v) = changes.token_price { asset.token_price = v; }
+    if let Some(v) = changes.total_tokens {
+        asset.total_tokens = v;
+        asset.available_tokens = v - issued;
+    }
+    if let Some(v) = changes.apy { asset.apy = v; }
+    if let Some(v) = changes.monthly_income { asset.monthly_income = Some(v); }
+}
+
+// Internal: Apply an approved amendment to an asset's frozen economics
+pub fn amend_economics(id: u64, changes: EconomicChanges) -> RwaResult<Asset> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let asset = assets.get_mut(&id).ok_or_else(|| RwaError::not_found("Asset", id))?;
+        if asset.is_closed() {
+            return Err(RwaError::InvalidState(format!("Asset #{} is {:?} and can no longer be edited", id, asset.status)));
+        }
+        validate_economics(asset, &changes)?;
+        apply_economics(asset, changes);
+        Ok(asset.clone())
+    })
+}
+
+// Internal: Record the value of an accepted appraisal
+pub fn revalue(id: u64, value: Money, valued_at: u64) -> RwaResult<Asset> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let asset = assets.get_mut(&id).ok_or_else(|| RwaError::not_found("Asset", id))?;
+        value.expect_currency(asset.currency())?;
+        asset.total_value = value;
+        asset.valued_at = Some(valued_at);
+        Ok(asset.clone())
+    })
+}
+
+// Internal: Replace the rules governing transfers of an asset
+pub fn attach_transfer_rules(id: u64, rules: Option<TransferRules>) -> RwaResult<Asset> {
     ASSETS.with(|assets| {
         let mut assets = assets.borrow_mut();
-        if let Some(asset) = assets.get_mut(&id) {
-            if asset.owner_id == caller || is_admin(&caller) {
-                if let Some(v) = name { asset.name = v; }
-                if let Some(v) = description { asset.description = v; }
-                if let Some(v) = category { asset.category = v; }
-                if let Some(v) = location { asset.location = v; }
-                if let Some(v) = images { asset.images = v; }
-                if let Some(v) = documents { asset.documents = v; }
-                if let Some(v) = total_value { asset.total_value = v; }
-                if let Some(v) = token_price { asset.token_price = v; }
-                if let Some(v) = total_tokens { asset.total_tokens = v; }
-                if let Some(v) = apy { asset.apy = v; }
-                if let Some(v) = launch_date { asset.launch_date = Some(v); }
-                if let Some(v) = funding_deadline { asset.funding_deadline = Some(v); }
-                if let Some(v) = monthly_income { asset.monthly_income = Some(v); }
-                if let Some(v) = risk_rating { asset.risk_rating = Some(v); }
-                if let Some(v) = key_metrics { asset.key_metrics = Some(v); }
-                return Some(asset.clone());
-            }
-        }
-        None
+        let asset = assets.get_mut(&id).ok_or_else(|| RwaError::not_found("Asset", id))?;
+        asset.transfer_rules = rules;
+        Ok(asset.clone())
+    })
+}
+
+// Internal: When the asset first went live, if it has
+pub fn went_live_at(id: u64) -> Option<u64> {
+    ASSET_HISTORY.with(|history| {
+        history.borrow().get(&id)?.iter().find(|step| step.to == AssetStatus::Active).map(|step| step.timestamp)
     })
 }
 
@@ -159,35 +436,205 @@ pub fn list_assets() -> Vec<Asset> {
     ASSETS.with(|assets| assets.borrow().values().cloned().collect())
 }
 
+// Internal: Open a ledger for every asset past review that has none, such as
+// assets approved before ledgers existed
+pub fn open_missing_ledgers() {
+    ASSETS.with(|assets| {
+        for asset in assets.borrow().values().filter(|a| a.is_issuable() || a.is_closed()) {
+            icrc::open_ledger(asset);
+        }
+    })
+}
+
+// Reviewer: Approve a pending asset and open its token ledger
+#[ic_cdk::update]
+pub fn approve_asset(id: u64) -> RwaResult<Asset> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewAssets)?;
+    let asset = transition(id, AssetStatus::Approved, caller, None)?;
+    icrc::open_ledger(&asset);
+    Ok(asset)
+}
+
+// Reviewer: Turn down a pending asset
+#[ic_cdk::update]
+pub fn reject_asset(id: u64, reason: String) -> RwaResult<Asset> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewAssets)?;
+    transition(id, AssetStatus::Rejected, caller, required_reason(reason)?)
+}
+
+// Admin: Mark an approved asset live without a funding round, or a funded one
+// whose round was closed
+#[ic_cdk::update]
+pub fn activate_asset(id: u64) -> RwaResult<Asset> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ManageAssets)?;
+    if get_asset(id)?.status == AssetStatus::Funding {
+        return Err(RwaError::InvalidState(format!("Asset #{} has an open funding round; close it instead", id)));
+    }
+    transition(id, AssetStatus::Active, caller, None)
+}
+
+// Closes an asset's market on its way to Sold or Delisted: pending trades and
+// open orders are cancelled and their escrow goes back before the transition
+fn close_market(id: u64, to: AssetStatus, actor: Principal, reason: Option<String>) -> RwaResult<Asset> {
+    let from = get_asset(id)?.status;
+    if !is_allowed_transition(&from, &to) {
+        return Err(RwaError::InvalidState(format!("Asset #{} cannot move from {:?} to {:?}", id, from, to)));
+    }
+    trade::cancel_asset_trades(id, &to)?;
+    orderbook::cancel_asset_orders(id, &to);
+    transition(id, to, actor, reason)
+}
+
+// Admin: Record that the underlying asset was sold off
+#[ic_cdk::update]
+pub fn mark_asset_sold(id: u64, reason: Option<String>) -> RwaResult<Asset> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ManageAssets)?;
+    close_market(id, AssetStatus::Sold, caller, reason)
+}
+
+// Admin: Take an asset off the platform. Its ledger stays readable but no new
+// orders, trades or issuance are accepted.
+#[ic_cdk::update]
+pub fn delist_asset(id: u64, reason: String) -> RwaResult<Asset> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ManageAssets)?;
+    close_market(id, AssetStatus::Delisted, caller, required_reason(reason)?)
+}
+
+#[ic_cdk::query]
+pub fn get_asset_history(id: u64) -> RwaResult<Vec<AssetTransition>> {
+    get_asset(id)?;
+    Ok(ASSET_HISTORY.with(|history| history.borrow().get(&id).cloned().unwrap_or_default()))
+}
+
 #[ic_cdk::update]
-pub fn approve_asset(id: u64) -> Option<Asset> {
+pub fn delete_asset(id: u64) -> RwaResult<()> {
     let caller = ic_cdk::caller();
-    if !is_admin(&caller) {
-        return None;
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let asset = assets.get(&id).ok_or_else(|| RwaError::not_found("Asset", id))?;
+        if asset.owner_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+            return Err(RwaError::Unauthorized);
+        }
+        if asset.issued_tokens() > 0 {
+            return Err(RwaError::InvalidState(format!("Asset #{} has {} tokens outstanding", id, asset.issued_tokens())));
+        }
+        if asset.status == AssetStatus::Funding {
+            return Err(RwaError::InvalidState(format!("Asset #{} has an open funding round", id)));
+        }
+        assets.remove(&id);
+        Ok(())
+    })
+}
+
+// Internal: Take `amount` out of an asset's unissued supply on behalf of `caller`.
+// Only the issuer or an asset manager may issue, only once the asset is approved, and
+// never beyond total_tokens.
+pub fn issue_supply(asset_id: u64, caller: &Principal, amount: u64) -> RwaResult<Asset> {
+    let asset = get_asset(asset_id)?;
+    if asset.owner_id != *caller && !has_permission(caller, Permission::ManageAssets) {
+        return Err(RwaError::Unauthorized);
     }
+    allocate_supply(asset_id, amount)
+}
+
+// Internal: Take `amount` out of an approved asset's unissued supply, e.g. for
+// a funding round subscription. Callers check permissions themselves.
+pub fn allocate_supply(asset_id: u64, amount: u64) -> RwaResult<Asset> {
     ASSETS.with(|assets| {
         let mut assets = assets.borrow_mut();
-        if let Some(asset) = assets.get_mut(&id) {
-            asset.status = AssetStatus::Approved;
-            // Notify asset owner
-            create_notification(asset.owner_id, NotificationType::Admin, format!("Your asset '{}' has been approved", asset.name), ic_cdk::api::time().to_string());
-            return Some(asset.clone());
+        let asset = assets.get_mut(&asset_id).ok_or_else(|| RwaError::not_found("Asset", asset_id))?;
+        if !asset.is_issuable() {
+            return Err(RwaError::InvalidState(format!("Asset #{} is {:?}, not approved for issuance", asset_id, asset.status)));
+        }
+        if amount > asset.available_tokens {
+            return Err(RwaError::InsufficientBalance { required: amount, available: asset.available_tokens });
         }
-        None
+        asset.available_tokens -= amount;
+        Ok(asset.clone())
     })
 }
 
-#[ic_cdk::update]
-pub fn delete_asset(id: u64) -> bool {
-    let caller = ic_cdk::caller();
+// Internal: Put `amount` allocated but never issued back into the unissued supply
+pub fn return_supply(asset_id: u64, amount: u64) -> RwaResult<Asset> {
     ASSETS.with(|assets| {
         let mut assets = assets.borrow_mut();
-        if let Some(asset) = assets.get(&id) {
-            if asset.owner_id == caller || is_admin(&caller) {
-                assets.remove(&id);
-                return true;
-            }
+        let asset = assets.get_mut(&asset_id).ok_or_else(|| RwaError::not_found("Asset", asset_id))?;
+        if asset.issued_tokens() < amount {
+            return Err(RwaError::InsufficientBalance { required: amount, available: asset.issued_tokens() });
         }
-        false
+        asset.available_tokens += amount;
+        Ok(asset.clone())
     })
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn asset(id: u64, status: AssetStatus) -> Asset {
+        Asset {
+            id,
+            owner_id: Principal::anonymous(),
+            name: format!("Asset {}", id),
+            description: String::new(),
+            category: String::new(),
+            location: String::new(),
+            images: vec![],
+            documents: vec![],
+            total_value: Money::new(1_000_000, Currency::USD),
+            token_price: Money::new(1_000, Currency::USD),
+            total_tokens: 1_000,
+            available_tokens: 1_000,
+            apy: BasisPoints(500),
+            status,
+            launch_date: None,
+            funding_deadline: None,
+            monthly_income: None,
+            risk_rating: None,
+            key_metrics: None,
+            valued_at: None,
+            transfer_rules: None,
+        }
+    }
+
+    #[test]
+    fn reviewed_assets_without_a_ledger_get_one_on_restore() {
+        use AssetStatus::*;
+        let statuses = [(101, Pending), (102, Approved), (103, Rejected), (104, Active), (105, Funding), (106, Sold), (107, Delisted)];
+        let assets = statuses.iter().map(|(id, status)| (*id, asset(*id, status.clone()))).collect();
+        restore_state(AssetState { assets, next_id: 108, history: None });
+        open_missing_ledgers();
+        let opened: Vec<u64> = statuses.iter().map(|(id, _)| *id).filter(|id| icrc::has_ledger(*id)).collect();
+        assert_eq!(opened, vec![102, 104, 105, 106, 107]);
+    }
+
+    #[test]
+    fn only_reviewed_open_assets_with_a_ledger_trade() {
+        use AssetStatus::*;
+        let unopened = asset(201, Approved);
+        assert!(unopened.check_tradable().is_err());
+        for (id, status, tradable) in [(202, Approved, true), (203, Active, true), (204, Funding, true), (205, Sold, false), (206, Delisted, false)] {
+            let asset = asset(id, status);
+            icrc::open_ledger(&asset);
+            assert_eq!(asset.check_tradable().is_ok(), tradable, "{:?}", asset.status);
+        }
+        assert!(asset(207, Pending).check_tradable().is_err());
+    }
+
+    #[test]
+    fn lifecycle_allows_only_forward_steps() {
+        use AssetStatus::*;
+        assert!(is_allowed_transition(&Pending, &Approved));
+        assert!(is_allowed_transition(&Funding, &Approved));
+        assert!(is_allowed_transition(&Active, &Delisted));
+        assert!(!is_allowed_transition(&Approved, &Approved));
+        assert!(!is_allowed_transition(&Funding, &Delisted));
+        assert!(!is_allowed_transition(&Sold, &Active));
+        assert!(!is_allowed_transition(&Rejected, &Approved));
+    }
+}
@@ -0,0 +1,324 @@
+// Canister lifecycle hooks: persists every module's store in stable memory across
+// upgrades. The append-only stores live in stable structures (see memory.rs);
+// everything else is snapshotted in pre_upgrade and restored in post_upgrade.
+
+use candid::{CandidType, Deserialize, Principal};
+use crate::user::UserState;
+use crate::access::AccessState;
+use crate::kyc::KycState;
+use crate::kyc_review::KycReviewState;
+use crate::asset::AssetState;
+use crate::icrc::LedgerState;
+use crate::orderbook::OrderState;
+use crate::cash::CashState;
+use crate::payment::PaymentState;
+use crate::funding::FundingState;
+use crate::amendment::AmendmentState;
+use crate::distribution::DistributionState;
+use crate::trade::TradeState;
+use crate::portfolio::PortfolioState;
+use crate::fx::FxState;
+use crate::valuation::ValuationState;
+use crate::notification::NotificationState;
+use crate::migration::{StableStateV1, StableStateV2, StableStateV3, StableStateV4, StableStateV6, TokenStateV5};
+
+// Bump whenever StableState changes in a way older snapshots cannot decode into,
+// and add a migration arm to `post_upgrade`.
+pub const SCHEMA_VERSION: u32 = 7;
+
+// Arguments accepted by both install and upgrade
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct InitArgs {
+    pub admins: Vec<Principal>,
+}
+
+// Fields added after schema v5 must be `opt` so older snapshots still decode.
+#[derive(CandidType, Deserialize)]
+pub struct StableState {
+    pub users: UserState,
+    pub assets: AssetState,
+    pub trades: TradeState,
+    pub portfolios: PortfolioState,
+    pub notifications: NotificationState,
+    pub ledgers: Option<LedgerState>,
+    pub orders: Option<OrderState>,
+    pub cash: Option<CashState>,
+    pub payments: Option<PaymentState>,
+    pub funding: Option<FundingState>,
+    pub amendments: Option<AmendmentState>,
+    pub distributions: Option<DistributionState>,
+    pub fx: Option<FxState>,
+    pub valuations: Option<ValuationState>,
+    pub access: Option<AccessState>,
+    pub kyc: Option<KycState>,
+    pub kyc_applications: Option<KycReviewState>,
+}
+
+fn take_state() -> StableState {
+    StableState {
+        users: crate::user::take_state(),
+        assets: crate::asset::take_state(),
+        trades: crate::trade::take_state(),
+        portfolios: crate::portfolio::take_state(),
+        notifications: crate::notification::take_state(),
+        ledgers: Some(crate::icrc::take_state()),
+        orders: Some(crate::orderbook::take_state()),
+        cash: Some(crate::cash::take_state()),
+        payments: Some(crate::payment::take_state()),
+        funding: Some(crate::funding::take_state()),
+        amendments: Some(crate::amendment::take_state()),
+        distributions: Some(crate::distribution::take_state()),
+        fx: Some(crate::fx::take_state()),
+        valuations: Some(crate::valuation::take_state()),
+        access: Some(crate::access::take_state()),
+        kyc: Some(crate::kyc::take_state()),
+        kyc_applications: Some(crate::kyc_review::take_state()),
+    }
+}
+
+fn restore_state(state: StableState) {
+    crate::user::restore_state(state.users);
+    // Before any module that migrates grants into roles
+    crate::access::restore_state(state.access.unwrap_or_default());
+    match state.kyc {
+        Some(kyc) => crate::kyc::restore_state(kyc),
+        // Approvals granted before KYC records existed carry no details
+        None => crate::kyc::adopt_legacy_approvals(),
+    }
+    crate::kyc_review::restore_state(state.kyc_applications.unwrap_or_default());
+    crate::asset::restore_state(state.assets);
+    crate::trade::restore_state(state.trades);
+    crate::portfolio::restore_state(state.portfolios);
+    crate::notification::restore_state(state.notifications);
+    crate::icrc::restore_state(state.ledgers.unwrap_or_default());
+    crate::orderbook::restore_state(state.orders.unwrap_or_default());
+    crate::cash::restore_state(state.cash.unwrap_or_default());
+    crate::payment::restore_state(state.payments.unwrap_or_default());
+    crate::funding::restore_state(state.funding.unwrap_or_default());
+    crate::amendment::restore_state(state.amendments.unwrap_or_default());
+    crate::distribution::restore_state(state.distributions.unwrap_or_default());
+    crate::fx::restore_state(state.fx.unwrap_or_default());
+    crate::valuation::restore_state(state.valuations.unwrap_or_default());
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    apply_init_args(args);
+    crate::scheduler::arm();
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        crate::user::add_admins(args.admins);
+    }
+}
+
+// The snapshot is stored as (schema_version, candid-encoded StableState) so the
+// version can be read before deciding how to decode the payload.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let payload = candid::encode_one(take_state())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to encode state: {}", e)));
+    let snapshot = candid::encode_args((SCHEMA_VERSION, payload))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to encode state: {}", e)));
+    crate::memory::save_snapshot(snapshot);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    restore_snapshot();
+    apply_init_args(args);
+    // Timers are dropped on upgrade
+    crate::scheduler::arm();
+}
+
+fn restore_snapshot() {
+    // Up to v5 the snapshot was saved straight into stable memory and also held
+    // the append-only stores, which restore_state moves into stable structures
+    let (version, payload): (u32, Vec<u8>) = if crate::memory::has_legacy_snapshot() {
+        ic_cdk::storage::stable_restore()
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to read state: {}", e)))
+    } else {
+        // Builds that predate stable persistence never wrote a snapshot
+        let Some(snapshot) = crate::memory::load_snapshot() else {
+            return;
+        };
+        candid::decode_args(&snapshot)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to read state: {}", e)))
+    };
+    let (state, lots) = decode_state(version, &payload)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode state v{}: {}", version, e)));
+    restore_state(state);
+    // Ledgers were first opened on approval, so older approved assets lack
+    // one; their holdings are the lots minted next
+    crate::asset::open_missing_ledgers();
+    crate::migration::mint_lots(lots);
+}
+
+// The current state and, from snapshots up to v6, the token lots that restore
+// still has to mint into the asset ledgers
+fn decode_state(version: u32, payload: &[u8]) -> Result<(StableState, TokenStateV5), String> {
+    let state = match version {
+        // v1 kept timestamps as strings
+        1 => candid::decode_one::<StableStateV1>(payload)
+            .map(|v1| StableStateV6::from(StableStateV3::from(StableStateV2::from(v1)))),
+        // v2 kept amounts as bare numbers and rates as f64
+        2 => candid::decode_one::<StableStateV2>(payload)
+            .map(|v2| StableStateV6::from(StableStateV3::from(v2))),
+        // v3 summed portfolio values across currencies
+        3 => candid::decode_one::<StableStateV3>(payload).map(StableStateV6::from),
+        // v4 kept trade, order, funding and distribution amounts as bare numbers
+        4 => candid::decode_one::<StableStateV4>(payload).map(StableStateV6::from),
+        // v5 kept the append-only stores in the snapshot, in fields v6 leaves
+        // empty; v5 snapshots taken before the lot store was dropped hold lots
+        5 | 6 => candid::decode_one::<StableStateV6>(payload),
+        SCHEMA_VERSION => return candid::decode_one::<StableState>(payload)
+            .map(|state| (state, TokenStateV5::default()))
+            .map_err(|e| e.to_string()),
+        v => return Err(format!("unsupported schema version {}", v)),
+    };
+    state.map(StableStateV6::into_parts).map_err(|e| e.to_string())
+}
+
+// Schema version of the state layout this build reads and writes
+#[ic_cdk::query]
+pub fn get_schema_version() -> u32 {
+    SCHEMA_VERSION
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::AssetStatus;
+    use crate::migration::{AssetStateV1, AssetV1, NotificationStateV1, PortfolioStateV3, TokenLot, TokenStatus, TradeStateV1, TradeV1};
+    use crate::money::{BasisPoints, Currency, Money};
+    use crate::trade::TradeStatus;
+    use std::collections::HashMap;
+
+    fn holder() -> Principal {
+        Principal::from_slice(&[7; 29])
+    }
+
+    fn snapshot_v1() -> StableStateV1 {
+        let asset = AssetV1 {
+            id: 1,
+            owner_id: holder(),
+            name: "Depot".to_string(),
+            description: String::new(),
+            category: "Industrial".to_string(),
+            location: "Rotterdam".to_string(),
+            images: vec![],
+            documents: vec![],
+            total_value: 1_000_000,
+            token_price: 1_000,
+            total_tokens: 1_000,
+            available_tokens: 960,
+            apy: 7.25,
+            status: AssetStatus::Approved,
+            launch_date: Some("2024-03-01".to_string()),
+            funding_deadline: Some("not a date".to_string()),
+            monthly_income: None,
+            risk_rating: None,
+            key_metrics: None,
+        };
+        let trade = TradeV1 {
+            id: 1,
+            buyer_id: holder(),
+            seller_id: Principal::anonymous(),
+            token_id: 1,
+            asset_id: 1,
+            quantity: 4,
+            price: 1_000,
+            currency: Currency::USD,
+            status: TradeStatus::Completed,
+            created_at: "1700000000".to_string(),
+            filled: 4,
+            buy_order_id: None,
+            sell_order_id: None,
+            escrow: None,
+            expires_at: None,
+        };
+        let lot = TokenLot { id: 1, asset_id: 1, owner_id: holder(), amount: 40, price: 1_000, status: TokenStatus::Available };
+        StableStateV1 {
+            users: Default::default(),
+            assets: AssetStateV1 { assets: HashMap::from([(1, asset)]), next_id: 2, history: None },
+            tokens: TokenStateV5 { tokens: HashMap::from([(1, lot)]), next_id: 2 },
+            trades: TradeStateV1 { trades: HashMap::from([(1, trade)]), next_id: 2 },
+            portfolios: PortfolioStateV3 { portfolios: HashMap::new(), cost_basis: None, flows: None, snapshots: None },
+            notifications: NotificationStateV1 { notifications: HashMap::new(), next_id: 1 },
+            ledgers: None,
+            orders: None,
+            cash: None,
+            payments: None,
+            funding: None,
+            amendments: None,
+            distributions: None,
+        }
+    }
+
+    #[test]
+    fn v1_snapshot_migrates_and_keeps_its_token_lots() {
+        let payload = candid::encode_one(snapshot_v1()).unwrap();
+        let (state, lots) = decode_state(1, &payload).unwrap();
+
+        let lot = &lots.tokens[&1];
+        assert_eq!((lot.asset_id, lot.owner_id, lot.amount), (1, holder(), 40));
+        let asset = &state.assets.assets[&1];
+        assert_eq!(asset.token_price, Money::new(1_000, Currency::USD));
+        assert_eq!(asset.apy, BasisPoints(725));
+        assert_eq!(asset.launch_date, Some(1_709_251_200_000_000_000));
+        assert_eq!(asset.funding_deadline, None);
+        let trade = &state.trades.trades[&1];
+        assert_eq!(trade.created_at, 1_700_000_000_000_000_000);
+        assert_eq!(trade.price, Money::new(1_000, Currency::USD));
+    }
+
+    #[test]
+    fn migrated_state_round_trips_through_the_current_schema() {
+        let (state, _) = decode_state(1, &candid::encode_one(snapshot_v1()).unwrap()).unwrap();
+        let payload = candid::encode_one(&state).unwrap();
+        let (restored, lots) = decode_state(SCHEMA_VERSION, &payload).unwrap();
+
+        assert!(lots.tokens.is_empty());
+        assert_eq!(candid::encode_one(&restored.assets.assets[&1]).unwrap(), candid::encode_one(&state.assets.assets[&1]).unwrap());
+        assert_eq!(candid::encode_one(&restored.trades.trades[&1]).unwrap(), candid::encode_one(&state.trades.trades[&1]).unwrap());
+        assert_eq!(restored.assets.next_id, 2);
+    }
+
+    #[test]
+    fn v5_snapshots_keep_lots_and_v6_snapshots_have_none() {
+        let (state, lots) = decode_state(1, &candid::encode_one(snapshot_v1()).unwrap()).unwrap();
+        let v5 = StableStateV6 {
+            users: state.users,
+            assets: state.assets,
+            tokens: Some(lots),
+            trades: state.trades,
+            portfolios: state.portfolios,
+            notifications: state.notifications,
+            ledgers: None,
+            orders: None,
+            cash: None,
+            payments: None,
+            funding: None,
+            amendments: None,
+            distributions: None,
+            fx: None,
+            valuations: None,
+            access: None,
+            kyc: None,
+            kyc_applications: None,
+        };
+        let payload = candid::encode_one(&v5).unwrap();
+        assert_eq!(decode_state(5, &payload).unwrap().1.tokens.len(), 1);
+
+        let (state, _) = v5.into_parts();
+        let payload = candid::encode_one(&state).unwrap();
+        assert!(decode_state(6, &payload).unwrap().1.tokens.is_empty());
+    }
+
+    #[test]
+    fn unknown_schema_versions_are_refused() {
+        assert!(decode_state(SCHEMA_VERSION + 1, &[]).is_err());
+        assert!(decode_state(0, &[]).is_err());
+    }
+}
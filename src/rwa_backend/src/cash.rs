@@ -0,0 +1,228 @@
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use ic_stable_structures::StableLog;
+use crate::access::{Permission, has_permission, require_permission};
+use crate::error::{RwaError, RwaResult};
+use crate::memory::{self, Memory};
+use crate::money::Currency;
+use crate::notification::{create_notification, NotificationType};
+use crate::payment;
+
+// Internal cash balance of one user in one currency. `reserved` funds back open
+// buy orders and cannot be spent elsewhere until released.
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, PartialEq)]
+pub struct CashBalance {
+    pub available: u64,
+    pub reserved: u64,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct ClaimableBalance {
+    pub currency: Currency,
+    pub available: u64,
+    pub reserved: u64,
+}
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum CashEntryKind {
+    Credit,
+    Debit,
+    Reserve,
+    Release,
+    DebitReserved,
+}
+
+// One movement on a user's balance sheet, with the balance it left behind
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct CashEntry {
+    pub id: u64,
+    pub user_id: Principal,
+    pub currency: Currency,
+    pub kind: CashEntryKind,
+    pub amount: u64,
+    pub available_after: u64,
+    pub reserved_after: u64,
+    pub memo: String,
+    pub timestamp: u64,
+}
+
+const MAX_JOURNAL_PAGE: u64 = 500;
+
+thread_local! {
+    static BALANCES: RefCell<HashMap<(Principal, Currency), CashBalance>> = RefCell::new(HashMap::new());
+    // Append-only, in stable memory; an entry's id is its index
+    static JOURNAL: RefCell<StableLog<CashEntry, Memory, Memory>> =
+        RefCell::new(memory::init_log(memory::CASH_JOURNAL_INDEX, memory::CASH_JOURNAL_DATA));
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct CashState {
+    pub balances: HashMap<(Principal, Currency), CashBalance>,
+    // Only set in snapshots before schema v6, which kept the journal on the heap
+    pub journal: Option<Vec<CashEntry>>,
+}
+
+pub fn take_state() -> CashState {
+    CashState {
+        balances: BALANCES.with(|balances| std::mem::take(&mut *balances.borrow_mut())),
+        journal: None,
+    }
+}
+
+pub fn restore_state(state: CashState) {
+    BALANCES.with(|balances| *balances.borrow_mut() = state.balances);
+    for entry in state.journal.unwrap_or_default() {
+        append_entry(entry);
+    }
+}
+
+fn append_entry(entry: CashEntry) {
+    JOURNAL.with(|journal| journal.borrow().append(&entry))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to append to the cash journal: {:?}", e)));
+}
+
+// Applies `f` to one balance and journals the movement if it succeeds
+fn with_balance(user: Principal, currency: Currency, kind: CashEntryKind, amount: u64, memo: String, f: impl FnOnce(&mut CashBalance) -> RwaResult<()>) -> RwaResult<()> {
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances.entry((user, currency)).or_default();
+        let result = f(balance);
+        let after = balance.clone();
+        if after == CashBalance::default() {
+            balances.remove(&(user, currency));
+        }
+        result?;
+        append_entry(CashEntry {
+            id: JOURNAL.with(|journal| journal.borrow().len()),
+            user_id: user,
+            currency,
+            kind,
+            amount,
+            available_after: after.available,
+            reserved_after: after.reserved,
+            memo,
+            timestamp: ic_cdk::api::time(),
+        });
+        Ok(())
+    })
+}
+
+pub fn balance_of(user: Principal, currency: Currency) -> CashBalance {
+    BALANCES.with(|balances| balances.borrow().get(&(user, currency)).cloned().unwrap_or_default())
+}
+
+// Internal: Add funds to a user's available balance
+pub fn credit(user: Principal, currency: Currency, amount: u64, memo: impl Into<String>) -> RwaResult<()> {
+    with_balance(user, currency, CashEntryKind::Credit, amount, memo.into(), |balance| {
+        balance.available = balance.available.checked_add(amount)
+            .ok_or_else(|| RwaError::validation("amount", "balance overflow"))?;
+        Ok(())
+    })
+}
+
+// Internal: Spend from a user's available balance
+pub fn debit(user: Principal, currency: Currency, amount: u64, memo: impl Into<String>) -> RwaResult<()> {
+    with_balance(user, currency, CashEntryKind::Debit, amount, memo.into(), |balance| {
+        if balance.available < amount {
+            return Err(RwaError::InsufficientBalance { required: amount, available: balance.available });
+        }
+        balance.available -= amount;
+        Ok(())
+    })
+}
+
+// Internal: Set funds aside for an open order
+pub fn reserve(user: Principal, currency: Currency, amount: u64, memo: impl Into<String>) -> RwaResult<()> {
+    with_balance(user, currency, CashEntryKind::Reserve, amount, memo.into(), |balance| {
+        if balance.available < amount {
+            return Err(RwaError::InsufficientBalance { required: amount, available: balance.available });
+        }
+        balance.available -= amount;
+        balance.reserved += amount;
+        Ok(())
+    })
+}
+
+// Internal: Return reserved funds to the available balance
+pub fn release(user: Principal, currency: Currency, amount: u64, memo: impl Into<String>) -> RwaResult<()> {
+    with_balance(user, currency, CashEntryKind::Release, amount, memo.into(), |balance| {
+        if balance.reserved < amount {
+            return Err(RwaError::InsufficientBalance { required: amount, available: balance.reserved });
+        }
+        balance.reserved -= amount;
+        balance.available += amount;
+        Ok(())
+    })
+}
+
+// Internal: Spend from reserved funds when an order fills
+pub fn debit_reserved(user: Principal, currency: Currency, amount: u64, memo: impl Into<String>) -> RwaResult<()> {
+    with_balance(user, currency, CashEntryKind::DebitReserved, amount, memo.into(), |balance| {
+        if balance.reserved < amount {
+            return Err(RwaError::InsufficientBalance { required: amount, available: balance.reserved });
+        }
+        balance.reserved -= amount;
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_cash_balance(user_id: Principal, currency: Currency) -> RwaResult<CashBalance> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(balance_of(user_id, currency))
+}
+
+// Every currency in which the user has funds, with what can be withdrawn now
+#[ic_cdk::query]
+pub fn get_claimable(user_id: Principal) -> RwaResult<Vec<ClaimableBalance>> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    let mut claimable: Vec<ClaimableBalance> = BALANCES.with(|balances| {
+        balances.borrow().iter()
+            .filter(|((owner, _), _)| *owner == user_id)
+            .map(|((_, currency), balance)| ClaimableBalance { currency: *currency, available: balance.available, reserved: balance.reserved })
+            .collect()
+    });
+    claimable.sort_by_key(|c| c.currency);
+    Ok(claimable)
+}
+
+// A user's balance movements, oldest first, starting after entry `after`
+#[ic_cdk::query]
+pub fn get_cash_journal(user_id: Principal, after: Option<u64>, limit: u64) -> RwaResult<Vec<CashEntry>> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    let start = after.map_or(0, |id| id + 1);
+    Ok(JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        (start..journal.len()).filter_map(|id| journal.get(id))
+            .filter(|entry| entry.user_id == user_id)
+            .take(limit.min(MAX_JOURNAL_PAGE) as usize)
+            .collect()
+    }))
+}
+
+// Admin: Credit off-chain funds (e.g. a fiat wire) to a user's cash balance.
+// Currencies settled on a ledger are only credited by deposit_funds, so every
+// such balance is backed by tokens the canister holds.
+#[ic_cdk::update]
+pub fn deposit_cash(user_id: Principal, currency: Currency, amount: u64) -> RwaResult<CashBalance> {
+    require_permission(&ic_cdk::caller(), Permission::CreditCash)?;
+    if amount == 0 {
+        return Err(RwaError::validation("amount", "must be greater than zero"));
+    }
+    if payment::ledger_for(currency).is_some() {
+        return Err(RwaError::validation("currency", "is settled on a ledger; deposit it with deposit_funds"));
+    }
+    credit(user_id, currency, amount, "Off-chain deposit")?;
+    create_notification(user_id, NotificationType::Investment, format!("{} {:?} was credited to your cash balance", amount, currency));
+    Ok(balance_of(user_id, currency))
+}
@@ -0,0 +1,212 @@
+// Per-asset transfer restrictions. An issuer attaches a rule set to an asset
+// and every path that moves units to a holder checks it before any balance
+// changes; a refusal names the rule it broke. Assets without rules only need
+// the receiver to hold a current KYC approval.
+
+use candid::Principal;
+use std::collections::BTreeMap;
+use crate::access::{Permission, has_permission};
+use crate::asset::{self, Asset, NANOS_PER_DAY};
+use crate::error::{RwaError, RwaResult};
+use crate::kyc::{self, is_country_code, KycRecord};
+use crate::token;
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct TransferRules {
+    // ISO 3166-1 alpha-2 codes holders may reside in; empty allows any
+    pub allowed_jurisdictions: Vec<String>,
+    pub blocked_jurisdictions: Vec<String>,
+    pub accredited_only: bool,
+    // Most distinct holders the asset may have at once
+    pub max_holders: Option<u64>,
+    // Most units a single investor may hold
+    pub max_holding: Option<u64>,
+    // Fewest units a holder may keep short of selling out
+    pub min_holding: Option<u64>,
+    // Time after the asset goes live before holders may sell or transfer
+    pub lockup_nanos: Option<u64>,
+}
+
+// The rule a refused transfer broke
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum TransferRule {
+    Jurisdiction,
+    AccreditedOnly,
+    MaxHolders,
+    HoldingCap,
+    MinimumHolding,
+    LockUp,
+}
+
+fn validate_rules(rules: &TransferRules) -> RwaResult<()> {
+    for (field, codes) in [("allowed_jurisdictions", &rules.allowed_jurisdictions), ("blocked_jurisdictions", &rules.blocked_jurisdictions)] {
+        if let Some(code) = codes.iter().find(|c| !is_country_code(c)) {
+            return Err(RwaError::validation(field, format!("\"{}\" is not an ISO 3166-1 alpha-2 code", code)));
+        }
+    }
+    if let Some(code) = rules.blocked_jurisdictions.iter().find(|c| rules.allowed_jurisdictions.contains(c)) {
+        return Err(RwaError::validation("blocked_jurisdictions", format!("{} is also allowed", code)));
+    }
+    if rules.max_holders == Some(0) {
+        return Err(RwaError::validation("max_holders", "must be greater than zero"));
+    }
+    if rules.max_holding == Some(0) {
+        return Err(RwaError::validation("max_holding", "must be greater than zero"));
+    }
+    if rules.min_holding == Some(0) {
+        return Err(RwaError::validation("min_holding", "must be greater than zero"));
+    }
+    if let (Some(min), Some(max)) = (rules.min_holding, rules.max_holding) {
+        if min > max {
+            return Err(RwaError::validation("min_holding", format!("cannot exceed max_holding of {}", max)));
+        }
+    }
+    if rules.lockup_nanos == Some(0) {
+        return Err(RwaError::validation("lockup_nanos", "must be greater than zero"));
+    }
+    Ok(())
+}
+
+// Internal: Fail unless the investor behind `record` may hold `asset` at all
+pub fn check_investor(record: &KycRecord, asset: &Asset) -> RwaResult<()> {
+    let Some(rules) = &asset.transfer_rules else { return Ok(()) };
+    if !rules.allowed_jurisdictions.is_empty() || !rules.blocked_jurisdictions.is_empty() {
+        let country = record.country.as_ref().ok_or_else(|| {
+            RwaError::restricted(TransferRule::Jurisdiction, "country of residence is not on record; KYC must be renewed")
+        })?;
+        let allowed = rules.allowed_jurisdictions.is_empty() || rules.allowed_jurisdictions.contains(country);
+        if !allowed || rules.blocked_jurisdictions.contains(country) {
+            return Err(RwaError::restricted(TransferRule::Jurisdiction, format!("residents of {} may not hold asset #{}", country, asset.id)));
+        }
+    }
+    if rules.accredited_only && !record.accredited {
+        return Err(RwaError::restricted(TransferRule::AccreditedOnly, format!("asset #{} is open to accredited investors only", asset.id)));
+    }
+    Ok(())
+}
+
+// When the asset's lock-up ends; None while it has not gone live
+fn lockup_ends(asset: &Asset, lockup: u64) -> Option<u64> {
+    asset::went_live_at(asset.id).or(asset.launch_date).map(|at| at.saturating_add(lockup))
+}
+
+fn check_send(asset: &Asset, rules: &TransferRules, holdings: &BTreeMap<Principal, u64>, from: &Principal, amount: u64) -> RwaResult<()> {
+    if let Some(lockup) = rules.lockup_nanos {
+        match lockup_ends(asset, lockup) {
+            Some(ends) if ends <= ic_cdk::api::time() => {}
+            Some(ends) => {
+                return Err(RwaError::restricted(TransferRule::LockUp, format!("units of asset #{} are locked up until {}", asset.id, ends)));
+            }
+            None => {
+                return Err(RwaError::restricted(TransferRule::LockUp, format!("units of asset #{} are locked up for {} days after it goes live", asset.id, lockup / NANOS_PER_DAY)));
+            }
+        }
+    }
+    let held = holdings.get(from).copied().unwrap_or(0);
+    let left = held.saturating_sub(amount);
+    if let Some(min) = rules.min_holding {
+        if left > 0 && left < min {
+            return Err(RwaError::restricted(TransferRule::MinimumHolding, format!("would leave {} units of asset #{}, below the minimum of {}; move all {} or at most {}", left, asset.id, min, held, held - min.min(held))));
+        }
+    }
+    Ok(())
+}
+
+fn check_receive(asset: &Asset, rules: &TransferRules, holdings: &BTreeMap<Principal, u64>, to: &Principal, amount: u64) -> RwaResult<()> {
+    let after = holdings.get(to).copied().unwrap_or(0).saturating_add(amount);
+    if let Some(max) = rules.max_holding {
+        if after > max {
+            return Err(RwaError::restricted(TransferRule::HoldingCap, format!("would hold {} units of asset #{}, above the cap of {}", after, asset.id, max)));
+        }
+    }
+    if let Some(min) = rules.min_holding {
+        if after < min {
+            return Err(RwaError::restricted(TransferRule::MinimumHolding, format!("would hold {} units of asset #{}, below the minimum of {}", after, asset.id, min)));
+        }
+    }
+    Ok(())
+}
+
+fn check_holder_count(asset: &Asset, max: u64, holdings: &BTreeMap<Principal, u64>, from: Option<&Principal>, to: &Principal, amount: u64) -> RwaResult<()> {
+    let held = |holder: &Principal| holdings.get(holder).copied().unwrap_or(0);
+    if held(to) > 0 {
+        return Ok(());
+    }
+    // A sender moving out entirely frees its place for the receiver
+    let leaving = from.is_some_and(|from| held(from) <= amount);
+    let holders = holdings.values().filter(|units| **units > 0).count() as u64;
+    if holders + 1 - u64::from(leaving) > max {
+        return Err(RwaError::restricted(TransferRule::MaxHolders, format!("asset #{} already has its maximum of {} holders", asset.id, max)));
+    }
+    Ok(())
+}
+
+// Internal: Fail unless `amount` units of `asset` may move from `from` to `to`;
+// `from` is None for units coming out of unissued supply. `pending` lists
+// units promised to investors but not yet delivered, which count as held.
+pub fn check_transfer_with(asset: &Asset, pending: &[(Principal, u64)], from: Option<Principal>, to: Principal, amount: u64) -> RwaResult<()> {
+    kyc::may_hold_asset(&to, asset)?;
+    let Some(rules) = &asset.transfer_rules else { return Ok(()) };
+    if from == Some(to) {
+        return Ok(());
+    }
+    let mut holdings = token::holdings(asset.id)?;
+    for (holder, units) in pending {
+        *holdings.entry(*holder).or_default() += units;
+    }
+    if let Some(from) = &from {
+        check_send(asset, rules, &holdings, from, amount)?;
+    }
+    check_receive(asset, rules, &holdings, &to, amount)?;
+    if let Some(max) = rules.max_holders {
+        check_holder_count(asset, max, &holdings, from.as_ref(), &to, amount)?;
+    }
+    Ok(())
+}
+
+// Internal: `check_transfer_with` when nothing is pending
+pub fn check_transfer(asset: &Asset, from: Option<Principal>, to: Principal, amount: u64) -> RwaResult<()> {
+    check_transfer_with(asset, &[], from, to, amount)
+}
+
+// Internal: The sending side alone, for orders placed before a counterparty is known
+pub fn check_sender(asset: &Asset, from: Principal, amount: u64) -> RwaResult<()> {
+    let Some(rules) = &asset.transfer_rules else { return Ok(()) };
+    check_send(asset, rules, &token::holdings(asset.id)?, &from, amount)
+}
+
+// Internal: The receiving side alone, for orders placed before a counterparty is known
+pub fn check_receiver(asset: &Asset, to: Principal, amount: u64) -> RwaResult<()> {
+    kyc::may_hold_asset(&to, asset)?;
+    let Some(rules) = &asset.transfer_rules else { return Ok(()) };
+    check_receive(asset, rules, &token::holdings(asset.id)?, &to, amount)
+}
+
+// Issuer: Attach transfer rules to an asset, or clear them with None. Rules
+// only govern transfers from now on; existing holdings are left as they are.
+#[ic_cdk::update]
+pub fn set_transfer_rules(asset_id: u64, rules: Option<TransferRules>) -> RwaResult<Asset> {
+    let caller = ic_cdk::caller();
+    let asset = asset::get_asset(asset_id)?;
+    if asset.owner_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+        return Err(RwaError::Unauthorized);
+    }
+    if asset.is_closed() {
+        return Err(RwaError::InvalidState(format!("Asset #{} is {:?} and can no longer be edited", asset_id, asset.status)));
+    }
+    if let Some(rules) = &rules {
+        validate_rules(rules)?;
+    }
+    asset::attach_transfer_rules(asset_id, rules)
+}
+
+// Whether `amount` units of an asset could move between two users right now;
+// `from` is None for a primary subscription. The error names the rule.
+#[ic_cdk::query]
+pub fn check_transfer_rules(asset_id: u64, from: Option<Principal>, to: Principal, amount: u64) -> RwaResult<()> {
+    let caller = ic_cdk::caller();
+    if caller != to && from != Some(caller) && !has_permission(&caller, Permission::ViewUsers) {
+        return Err(RwaError::Unauthorized);
+    }
+    check_transfer(&asset::get_asset(asset_id)?, from, to, amount)
+}
@@ -0,0 +1,295 @@
+// Income distribution: an issuer deposits a period's rental income or dividend
+// for an asset, holder balances are snapshotted at the record date, and the
+// amount is split pro rata across holders.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use crate::access::{Permission, has_permission};
+use crate::asset;
+use crate::cash;
+use crate::error::{RwaError, RwaResult};
+use crate::money::{Currency, Money};
+use crate::payment;
+use crate::portfolio;
+use crate::scheduler::{self, Job};
+use crate::token;
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum PayoutMode {
+    // Credit each share to the holder's cash balance to withdraw later
+    Credit,
+    // Send each share to the holder's account on the currency's payment ledger
+    Push,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum DistributionStatus {
+    Scheduled,
+    Paid,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct DistributionPayment {
+    pub holder_id: Principal,
+    pub units: u64,
+    pub amount: Money,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct Distribution {
+    pub id: u64,
+    pub asset_id: u64,
+    pub depositor_id: Principal,
+    pub amount: Money,
+    // Free-form label for the income period, e.g. "2024-06"
+    pub period: String,
+    pub record_date: u64,
+    pub payout_mode: PayoutMode,
+    pub status: DistributionStatus,
+    // Filled in at the record date
+    pub total_units: u64,
+    pub payments: Vec<DistributionPayment>,
+    pub created_at: u64,
+    pub paid_at: Option<u64>,
+}
+
+impl Distribution {
+    pub fn currency(&self) -> Currency {
+        self.amount.currency
+    }
+}
+
+thread_local! {
+    static DISTRIBUTIONS: RefCell<HashMap<u64, Distribution>> = RefCell::new(HashMap::new());
+    static DISTRIBUTION_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct DistributionState {
+    pub distributions: HashMap<u64, Distribution>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> DistributionState {
+    DistributionState {
+        distributions: DISTRIBUTIONS.with(|distributions| std::mem::take(&mut *distributions.borrow_mut())),
+        next_id: DISTRIBUTION_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: DistributionState) {
+    DISTRIBUTIONS.with(|distributions| *distributions.borrow_mut() = state.distributions);
+    DISTRIBUTION_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id.max(1));
+}
+
+fn save_distribution(distribution: &Distribution) {
+    DISTRIBUTIONS.with(|distributions| distributions.borrow_mut().insert(distribution.id, distribution.clone()));
+}
+
+// Internal: (id, record_date) of every distribution not yet paid, for the scheduler
+pub fn scheduled_record_dates() -> Vec<(u64, u64)> {
+    DISTRIBUTIONS.with(|distributions| {
+        distributions.borrow().values()
+            .filter(|d| d.status == DistributionStatus::Scheduled)
+            .map(|d| (d.id, d.record_date))
+            .collect()
+    })
+}
+
+// Splits `amount` over `holdings` in proportion to units held. Each holder gets
+// the floor of their exact share; the units left over go one each to the
+// holders with the largest fractional parts, ties broken by principal, so the
+// shares always add up to `amount` and the result is reproducible.
+fn pro_rata(total: Money, holdings: &BTreeMap<Principal, u64>) -> Vec<DistributionPayment> {
+    let amount = total.amount;
+    let total_units: u128 = holdings.values().map(|units| *units as u128).sum();
+    if total_units == 0 {
+        return Vec::new();
+    }
+    let mut payments = Vec::with_capacity(holdings.len());
+    let mut fractions = Vec::with_capacity(holdings.len());
+    let mut paid: u128 = 0;
+    for (index, (holder_id, units)) in holdings.iter().enumerate() {
+        let exact = amount as u128 * *units as u128;
+        let share = exact / total_units;
+        paid += share;
+        fractions.push((exact % total_units, index));
+        payments.push(DistributionPayment { holder_id: *holder_id, units: *units, amount: Money::new(share as u64, total.currency) });
+    }
+    let remainder = (amount as u128 - paid) as usize;
+    fractions.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for (_, index) in fractions.into_iter().take(remainder) {
+        payments[index].amount.amount += 1;
+    }
+    payments
+}
+
+// Internal: Snapshot holders and pay out a distribution whose record date has
+// come. With no holders at all the deposit goes back to the depositor.
+pub fn record_and_pay(id: u64) -> RwaResult<Distribution> {
+    let mut distribution = get_distribution(id)?;
+    if distribution.status != DistributionStatus::Scheduled {
+        return Err(RwaError::InvalidState(format!("Distribution #{} is already {:?}", id, distribution.status)));
+    }
+    let asset = asset::get_asset(distribution.asset_id)?;
+    let holdings = token::holdings(distribution.asset_id)?;
+    let payments = pro_rata(distribution.amount, &holdings);
+    if payments.is_empty() {
+        cash::credit(distribution.depositor_id, distribution.currency(), distribution.amount.amount, format!("Distribution #{} refund", id))?;
+        distribution.status = DistributionStatus::Cancelled;
+        create_notification(distribution.depositor_id, NotificationType::Investment, format!("Distribution #{} for '{}' had no holders at its record date and was refunded", id, asset.name));
+        save_distribution(&distribution);
+        return Ok(distribution);
+    }
+    let ledger = match distribution.payout_mode {
+        PayoutMode::Push => payment::ledger_for(distribution.currency()),
+        PayoutMode::Credit => None,
+    };
+    for p in payments.iter().filter(|p| !p.amount.is_zero()) {
+        let message = format!("{} income from '{}' for {} ({} tokens held)", p.amount, asset.name, distribution.period, p.units);
+        match &ledger {
+            // The holder bears the ledger fee; shares too small to cover it are credited instead
+            Some(ledger) if p.amount.amount > ledger.fee => {
+                payment::spawn_payout(ledger.clone(), None, p.holder_id, p.amount.amount, format!("Distribution #{}", id));
+            }
+            _ => cash::credit(p.holder_id, p.amount.currency, p.amount.amount, format!("Distribution #{} income", id))?,
+        }
+        portfolio::record_income(p.holder_id, p.amount)?;
+        create_notification(p.holder_id, NotificationType::Investment, message);
+    }
+    distribution.total_units = holdings.values().sum();
+    distribution.payments = payments;
+    distribution.status = DistributionStatus::Paid;
+    distribution.paid_at = Some(ic_cdk::api::time());
+    save_distribution(&distribution);
+    Ok(distribution)
+}
+
+// Issuer: Deposit `amount` of income for an asset from the caller's cash
+// balance. Holders are snapshotted at `record_date` (default: now); Push
+// payouts need a payment ledger for the currency.
+#[ic_cdk::update]
+pub fn declare_distribution(
+    asset_id: u64,
+    amount: Money,
+    period: String,
+    record_date: Option<u64>,
+    payout_mode: PayoutMode,
+) -> RwaResult<Distribution> {
+    let caller = ic_cdk::caller();
+    let asset = asset::get_asset(asset_id)?;
+    if asset.owner_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+        return Err(RwaError::Unauthorized);
+    }
+    if !asset.is_issuable() || asset.issued_tokens() == 0 {
+        return Err(RwaError::InvalidState(format!("Asset #{} has no tokens in circulation", asset_id)));
+    }
+    if amount.is_zero() {
+        return Err(RwaError::validation("amount", "must be greater than zero"));
+    }
+    if period.trim().is_empty() {
+        return Err(RwaError::validation("period", "must not be empty"));
+    }
+    let now = ic_cdk::api::time();
+    let record_date = record_date.unwrap_or(now);
+    if record_date < now {
+        return Err(RwaError::validation("record_date", "must not be in the past"));
+    }
+    let currency = amount.currency;
+    if payout_mode == PayoutMode::Push && payment::ledger_for(currency).is_none() {
+        return Err(RwaError::validation("payout_mode", format!("{:?} is not settled on a ledger; use Credit", currency)));
+    }
+    let id = DISTRIBUTION_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    cash::debit(caller, currency, amount.amount, format!("Distribution #{} deposit", id))?;
+    let distribution = Distribution {
+        id,
+        asset_id,
+        depositor_id: caller,
+        amount,
+        period,
+        record_date,
+        payout_mode,
+        status: DistributionStatus::Scheduled,
+        total_units: 0,
+        payments: Vec::new(),
+        created_at: now,
+        paid_at: None,
+    };
+    save_distribution(&distribution);
+    if record_date > now {
+        scheduler::schedule(Job::PayDistribution(id), record_date);
+        return Ok(distribution);
+    }
+    // The deposit was taken above; trap to return it if the payout fails
+    Ok(record_and_pay(id).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to pay distribution #{}: {}", id, e))))
+}
+
+// Issuer: Withdraw a distribution before its record date
+#[ic_cdk::update]
+pub fn cancel_distribution(id: u64) -> RwaResult<Distribution> {
+    let caller = ic_cdk::caller();
+    let mut distribution = get_distribution(id)?;
+    if distribution.depositor_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+        return Err(RwaError::Unauthorized);
+    }
+    if distribution.status != DistributionStatus::Scheduled {
+        return Err(RwaError::InvalidState(format!("Distribution #{} is already {:?}", id, distribution.status)));
+    }
+    cash::credit(distribution.depositor_id, distribution.currency(), distribution.amount.amount, format!("Distribution #{} cancelled", id))?;
+    distribution.status = DistributionStatus::Cancelled;
+    save_distribution(&distribution);
+    Ok(distribution)
+}
+
+#[ic_cdk::query]
+pub fn get_distribution(id: u64) -> RwaResult<Distribution> {
+    DISTRIBUTIONS.with(|distributions| distributions.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("Distribution", id))
+}
+
+#[ic_cdk::query]
+pub fn list_distributions_by_asset(asset_id: u64) -> Vec<Distribution> {
+    DISTRIBUTIONS.with(|distributions| {
+        distributions.borrow().values().filter(|d| d.asset_id == asset_id).cloned().collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shares(total: u64, units: &[u64]) -> Vec<u64> {
+        let holdings = units.iter().enumerate().map(|(i, u)| (Principal::from_slice(&[i as u8 + 1]), *u)).collect();
+        pro_rata(Money::new(total, Currency::USD), &holdings).iter().map(|p| p.amount.amount).collect()
+    }
+
+    #[test]
+    fn leftover_units_go_to_the_largest_remainders() {
+        // Exact shares 1.43, 2.86 and 5.71
+        assert_eq!(shares(10, &[1, 2, 4]), vec![1, 3, 6]);
+        assert_eq!(shares(700, &[1, 2, 4]), vec![100, 200, 400]);
+    }
+
+    #[test]
+    fn equal_remainders_favour_holders_in_order() {
+        assert_eq!(shares(100, &[1, 1, 1]), vec![34, 33, 33]);
+        assert_eq!(shares(2, &[1, 1, 1]), vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn the_whole_amount_is_paid_and_never_more() {
+        for (total, units) in [(u64::MAX, vec![u64::MAX, 1, 7]), (1, vec![5, 5]), (999_999, vec![3, 0, 11, 13])] {
+            assert_eq!(shares(total, &units).iter().map(|s| *s as u128).sum::<u128>(), total as u128);
+        }
+        assert!(shares(100, &[]).is_empty());
+        assert!(shares(100, &[0, 0]).is_empty());
+    }
+}
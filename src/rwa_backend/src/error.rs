@@ -0,0 +1,56 @@
+// Shared error type returned by every fallible endpoint
+
+use std::fmt;
+use crate::compliance::TransferRule;
+use crate::money::Currency;
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum RwaError {
+    NotFound(String),
+    AlreadyExists(String),
+    Unauthorized,
+    KycRequired,
+    InvalidState(String),
+    InsufficientBalance { required: u64, available: u64 },
+    Validation { field: String, reason: String },
+    CurrencyMismatch { expected: Currency, found: Currency },
+    // A financial calculation would not fit; carries the operation
+    Overflow(String),
+    // The asset's transfer rules refuse the balance change
+    TransferRestricted { rule: TransferRule, reason: String },
+}
+
+pub type RwaResult<T> = Result<T, RwaError>;
+
+impl RwaError {
+    pub fn not_found(what: &str, id: impl fmt::Display) -> Self {
+        RwaError::NotFound(format!("{} {}", what, id))
+    }
+
+    pub fn validation(field: &str, reason: impl Into<String>) -> Self {
+        RwaError::Validation { field: field.to_string(), reason: reason.into() }
+    }
+
+    pub fn restricted(rule: TransferRule, reason: impl Into<String>) -> Self {
+        RwaError::TransferRestricted { rule, reason: reason.into() }
+    }
+}
+
+impl fmt::Display for RwaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RwaError::NotFound(what) => write!(f, "{} not found", what),
+            RwaError::AlreadyExists(what) => write!(f, "{} already exists", what),
+            RwaError::Unauthorized => write!(f, "Caller is not authorized"),
+            RwaError::KycRequired => write!(f, "KYC not approved"),
+            RwaError::InvalidState(reason) => write!(f, "Invalid state: {}", reason),
+            RwaError::InsufficientBalance { required, available } => {
+                write!(f, "Insufficient balance: required {}, available {}", required, available)
+            }
+            RwaError::Validation { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            RwaError::CurrencyMismatch { expected, found } => write!(f, "Expected an amount in {:?}, got {:?}", expected, found),
+            RwaError::Overflow(operation) => write!(f, "Amount out of range: {}", operation),
+            RwaError::TransferRestricted { rule, reason } => write!(f, "Transfer refused by the {:?} rule: {}", rule, reason),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+// Primary issuance: an issuer opens a funding round on an approved asset and
+// investors subscribe at token_price. Subscriptions reserve the investor's cash
+// and the asset's supply; at funding_deadline the round either delivers the
+// tokens and pays the issuer, or refunds everyone.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::access::{Permission, has_permission};
+use crate::asset::{self, AssetStatus};
+use crate::cash;
+use crate::scheduler::{self, Job};
+use crate::error::{RwaError, RwaResult};
+use crate::compliance;
+use crate::money::{Currency, Money};
+use crate::token;
+use crate::user::require_kyc;
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum FundingRoundStatus {
+    Open,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct Subscription {
+    pub investor_id: Principal,
+    pub tokens: u64,
+    // token_price at the time of subscription
+    pub price: Money,
+    pub amount: Money,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct FundingRound {
+    pub id: u64,
+    pub asset_id: u64,
+    pub issuer_id: Principal,
+    // Total subscriptions needed by the deadline for the round to succeed
+    pub min_raise: Money,
+    pub raised: Money,
+    pub tokens_subscribed: u64,
+    pub deadline: u64,
+    pub status: FundingRoundStatus,
+    pub subscriptions: Vec<Subscription>,
+    pub created_at: u64,
+    pub closed_at: Option<u64>,
+}
+
+impl FundingRound {
+    pub fn currency(&self) -> Currency {
+        self.min_raise.currency
+    }
+}
+
+thread_local! {
+    static ROUNDS: RefCell<HashMap<u64, FundingRound>> = RefCell::new(HashMap::new());
+    static ROUND_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct FundingState {
+    pub rounds: HashMap<u64, FundingRound>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> FundingState {
+    FundingState {
+        rounds: ROUNDS.with(|rounds| std::mem::take(&mut *rounds.borrow_mut())),
+        next_id: ROUND_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: FundingState) {
+    ROUNDS.with(|rounds| *rounds.borrow_mut() = state.rounds);
+    ROUND_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id.max(1));
+}
+
+fn save_round(round: &FundingRound) {
+    ROUNDS.with(|rounds| rounds.borrow_mut().insert(round.id, round.clone()));
+}
+
+fn open_round_for(asset_id: u64) -> Option<FundingRound> {
+    ROUNDS.with(|rounds| {
+        rounds.borrow().values().find(|r| r.asset_id == asset_id && r.status == FundingRoundStatus::Open).cloned()
+    })
+}
+
+// Internal: (id, deadline) of every round still open, for the scheduler
+pub fn open_round_deadlines() -> Vec<(u64, u64)> {
+    ROUNDS.with(|rounds| {
+        rounds.borrow().values().filter(|r| r.status == FundingRoundStatus::Open).map(|r| (r.id, r.deadline)).collect()
+    })
+}
+
+// Internal: Settle a round whose deadline has passed. Meeting min_raise
+// delivers every subscription and pays the issuer; otherwise all reserved cash
+// and supply go back. Transfer rules were checked on subscription.
+pub fn close_round(id: u64) -> RwaResult<FundingRound> {
+    let mut round = get_funding_round(id)?;
+    if round.status != FundingRoundStatus::Open {
+        return Err(RwaError::InvalidState(format!("Funding round #{} is already {:?}", id, round.status)));
+    }
+    let asset = asset::get_asset(round.asset_id)?;
+    if round.raised.amount >= round.min_raise.amount {
+        for sub in &round.subscriptions {
+            cash::debit_reserved(sub.investor_id, round.currency(), sub.amount.amount, format!("Funding round #{} subscription", id))?;
+            token::deliver(round.asset_id, sub.investor_id, sub.tokens, sub.price)?;
+            create_notification(sub.investor_id, NotificationType::Investment, format!("Funding round for '{}' succeeded: {} tokens delivered", asset.name, sub.tokens));
+        }
+        cash::credit(round.issuer_id, round.currency(), round.raised.amount, format!("Funding round #{} proceeds", id))?;
+        asset::transition(round.asset_id, AssetStatus::Active, ic_cdk::id(), Some(format!("Funding round #{} succeeded", id)))?;
+        round.status = FundingRoundStatus::Succeeded;
+        create_notification(round.issuer_id, NotificationType::Investment, format!("Funding round for '{}' raised {}; the asset is now active", asset.name, round.raised));
+    } else {
+        for sub in &round.subscriptions {
+            cash::release(sub.investor_id, round.currency(), sub.amount.amount, format!("Funding round #{} refund", id))?;
+            create_notification(sub.investor_id, NotificationType::Investment, format!("Funding round for '{}' did not reach its minimum; {} refunded", asset.name, sub.amount));
+        }
+        asset::return_supply(round.asset_id, round.tokens_subscribed)?;
+        asset::transition(round.asset_id, AssetStatus::Approved, ic_cdk::id(), Some(format!("Funding round #{} failed to reach its minimum raise", id)))?;
+        round.status = FundingRoundStatus::Failed;
+        create_notification(round.issuer_id, NotificationType::Investment, format!("Funding round for '{}' failed: raised {} of {}", asset.name, round.raised, round.min_raise));
+    }
+    round.closed_at = Some(ic_cdk::api::time());
+    save_round(&round);
+    Ok(round)
+}
+
+// Issuer: Open a funding round on an approved asset. The round runs until the
+// asset's funding_deadline and succeeds if subscriptions reach `min_raise`.
+#[ic_cdk::update]
+pub fn open_funding_round(asset_id: u64, min_raise: Money) -> RwaResult<FundingRound> {
+    let caller = ic_cdk::caller();
+    let asset = asset::get_asset(asset_id)?;
+    if asset.owner_id != caller && !has_permission(&caller, Permission::ManageAssets) {
+        return Err(RwaError::Unauthorized);
+    }
+    if asset.status != AssetStatus::Approved {
+        return Err(RwaError::InvalidState(format!("Asset #{} is {:?}; only approved assets can be funded", asset_id, asset.status)));
+    }
+    if open_round_for(asset_id).is_some() {
+        return Err(RwaError::AlreadyExists(format!("Open funding round for asset #{}", asset_id)));
+    }
+    let now = ic_cdk::api::time();
+    let deadline = asset.funding_deadline
+        .ok_or_else(|| RwaError::validation("funding_deadline", "asset needs a funding deadline"))?;
+    if deadline <= now {
+        return Err(RwaError::validation("funding_deadline", "must be in the future"));
+    }
+    // Subscriptions are paid at token_price, so the round raises in the asset's currency
+    min_raise.expect_currency(asset.currency())?;
+    let max_raise = asset.token_price.checked_mul(asset.available_tokens)?;
+    if min_raise.is_zero() || min_raise.amount > max_raise.amount {
+        return Err(RwaError::validation("min_raise", format!("must be between 1 and the offering value of {}", max_raise)));
+    }
+    let id = ROUND_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    asset::transition(asset_id, AssetStatus::Funding, caller, Some(format!("Funding round #{} opened", id)))?;
+    let round = FundingRound {
+        id,
+        asset_id,
+        issuer_id: asset.owner_id,
+        min_raise,
+        raised: Money::new(0, min_raise.currency),
+        tokens_subscribed: 0,
+        deadline,
+        status: FundingRoundStatus::Open,
+        subscriptions: Vec::new(),
+        created_at: now,
+        closed_at: None,
+    };
+    save_round(&round);
+    scheduler::schedule(Job::CloseFundingRound(id), deadline);
+    Ok(round)
+}
+
+// Investor: Subscribe for `tokens` at the asset's token_price. The cost is
+// reserved from the caller's cash balance until the round closes.
+#[ic_cdk::update]
+pub fn subscribe(round_id: u64, tokens: u64) -> RwaResult<FundingRound> {
+    let caller = ic_cdk::caller();
+    require_kyc(&caller)?;
+    if tokens == 0 {
+        return Err(RwaError::validation("tokens", "must be greater than zero"));
+    }
+    let mut round = get_funding_round(round_id)?;
+    let now = ic_cdk::api::time();
+    if round.status != FundingRoundStatus::Open || now >= round.deadline {
+        return Err(RwaError::InvalidState(format!("Funding round #{} is closed for subscriptions", round_id)));
+    }
+    if caller == round.issuer_id {
+        return Err(RwaError::validation("caller", "issuers cannot subscribe to their own round"));
+    }
+    let asset = asset::get_asset(round.asset_id)?;
+    // Subscriptions not yet delivered count as held under the asset's rules
+    let pending: Vec<(Principal, u64)> = round.subscriptions.iter().map(|s| (s.investor_id, s.tokens)).collect();
+    compliance::check_transfer_with(&asset, &pending, None, caller, tokens)?;
+    let amount = asset.token_price.checked_mul(tokens)?;
+    let raised = round.raised.checked_add(amount)?;
+    if tokens > asset.available_tokens {
+        return Err(RwaError::InsufficientBalance { required: tokens, available: asset.available_tokens });
+    }
+    cash::reserve(caller, round.currency(), amount.amount, format!("Funding round #{} subscription", round_id))?;
+    // Cash is reserved; trap to roll it back if the supply moved underneath us
+    asset::allocate_supply(round.asset_id, tokens)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to allocate tokens for round #{}: {}", round_id, e)));
+    round.raised = raised;
+    round.tokens_subscribed += tokens;
+    round.subscriptions.push(Subscription {
+        investor_id: caller,
+        tokens,
+        price: asset.token_price,
+        amount,
+        created_at: now,
+    });
+    save_round(&round);
+    Ok(round)
+}
+
+// Close a round once its deadline has passed. The scheduler does this on its
+// own; anyone may trigger it sooner, as the outcome depends only on the
+// subscriptions received.
+#[ic_cdk::update]
+pub fn close_funding_round(id: u64) -> RwaResult<FundingRound> {
+    let round = get_funding_round(id)?;
+    if round.status != FundingRoundStatus::Open {
+        return Err(RwaError::InvalidState(format!("Funding round #{} is already {:?}", id, round.status)));
+    }
+    if ic_cdk::api::time() < round.deadline {
+        return Err(RwaError::InvalidState(format!("Funding round #{} runs until its deadline", id)));
+    }
+    // Reservations were checked on subscription; trap to roll back on failure
+    Ok(close_round(id).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to close funding round #{}: {}", id, e))))
+}
+
+#[ic_cdk::query]
+pub fn get_funding_round(id: u64) -> RwaResult<FundingRound> {
+    ROUNDS.with(|rounds| rounds.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("Funding round", id))
+}
+
+#[ic_cdk::query]
+pub fn list_funding_rounds_by_asset(asset_id: u64) -> Vec<FundingRound> {
+    ROUNDS.with(|rounds| rounds.borrow().values().filter(|r| r.asset_id == asset_id).cloned().collect())
+}
@@ -0,0 +1,260 @@
+// Foreign exchange rates posted by admins or whitelisted oracles. Every posted
+// rate is kept, so an amount can be converted as of any past time the same way
+// it was then. A rate older than the staleness limit is not used.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use crate::access::{Permission, has_permission, require_permission};
+use crate::error::{RwaError, RwaResult};
+use crate::money::{Currency, Money};
+
+// Rates are quoted to 8 decimal places: 1 USD = 83.12 INR is 8_312_000_000
+pub const RATE_DECIMALS: u32 = 8;
+const RATE_SCALE: u128 = 10u128.pow(RATE_DECIMALS);
+
+// Rates older than this at the time of use are refused unless changed by an admin
+const DEFAULT_MAX_AGE_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct FxRate {
+    pub base: Currency,
+    pub quote: Currency,
+    // Units of `quote` per unit of `base`, scaled by 10^RATE_DECIMALS
+    pub rate: u64,
+    pub posted_by: Principal,
+    pub posted_at: u64,
+}
+
+thread_local! {
+    // Append-only log per (base, quote), oldest first
+    static RATES: RefCell<HashMap<(Currency, Currency), Vec<FxRate>>> = RefCell::new(HashMap::new());
+    static ORACLES: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    static MAX_AGE_NANOS: RefCell<u64> = const { RefCell::new(DEFAULT_MAX_AGE_NANOS) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct FxState {
+    pub rates: HashMap<(Currency, Currency), Vec<FxRate>>,
+    pub oracles: BTreeSet<Principal>,
+    pub max_age_nanos: Option<u64>,
+}
+
+pub fn take_state() -> FxState {
+    FxState {
+        rates: RATES.with(|rates| std::mem::take(&mut *rates.borrow_mut())),
+        oracles: ORACLES.with(|oracles| std::mem::take(&mut *oracles.borrow_mut())),
+        max_age_nanos: Some(MAX_AGE_NANOS.with(|max_age| *max_age.borrow())),
+    }
+}
+
+pub fn restore_state(state: FxState) {
+    RATES.with(|rates| *rates.borrow_mut() = state.rates);
+    ORACLES.with(|oracles| *oracles.borrow_mut() = state.oracles);
+    MAX_AGE_NANOS.with(|max_age| *max_age.borrow_mut() = state.max_age_nanos.unwrap_or(DEFAULT_MAX_AGE_NANOS));
+}
+
+fn pair_name(from: Currency, to: Currency) -> String {
+    format!("FX rate {:?}/{:?}", from, to)
+}
+
+// Latest rate for converting `from` into `to` posted at or before `at`, taken
+// from either direction of the pair, as (numerator, denominator)
+fn lookup(from: Currency, to: Currency, at: u64) -> Option<(FxRate, u128, u128)> {
+    let latest = |base: Currency, quote: Currency| -> Option<FxRate> {
+        RATES.with(|rates| {
+            rates.borrow().get(&(base, quote))?.iter().rev().find(|r| r.posted_at <= at).cloned()
+        })
+    };
+    let direct = latest(from, to).map(|r| (r.rate as u128, RATE_SCALE, r));
+    let inverse = latest(to, from).map(|r| (RATE_SCALE, r.rate as u128, r));
+    let (numerator, denominator, rate) = match (direct, inverse) {
+        (Some(d), Some(i)) => if d.2.posted_at >= i.2.posted_at { d } else { i },
+        (d, i) => d.or(i)?,
+    };
+    Some((rate, numerator, denominator))
+}
+
+fn apply(amount: Money, to: Currency, numerator: u128, denominator: u128) -> RwaResult<Money> {
+    let overflow = || RwaError::Overflow(format!("{} in {:?}", amount, to));
+    // Minor units of `to` = amount x rate x 10^to_decimals / 10^from_decimals, rounded down
+    let scaled = (amount.amount as u128)
+        .checked_mul(numerator)
+        .and_then(|v| v.checked_mul(10u128.pow(to.decimals())))
+        .ok_or_else(overflow)?;
+    let divisor = denominator * 10u128.pow(amount.currency.decimals());
+    u64::try_from(scaled / divisor).map(|v| Money::new(v, to)).map_err(|_| overflow())
+}
+
+// Internal: Convert `amount` into `to` at the rate in force at `at`, refusing
+// a missing or stale rate. The result is rounded down to `to`'s minor unit.
+pub fn convert(amount: Money, to: Currency, at: u64) -> RwaResult<Money> {
+    if amount.currency == to {
+        return Ok(amount);
+    }
+    let (rate, numerator, denominator) = lookup(amount.currency, to, at)
+        .ok_or_else(|| RwaError::NotFound(pair_name(amount.currency, to)))?;
+    let max_age = MAX_AGE_NANOS.with(|max_age| *max_age.borrow());
+    if at.saturating_sub(rate.posted_at) > max_age {
+        return Err(RwaError::InvalidState(format!("{} was last posted at {} and is stale", pair_name(amount.currency, to), rate.posted_at)));
+    }
+    apply(amount, to, numerator, denominator)
+}
+
+// Internal: Convert at the most recent rate however old it is. Only for
+// booking a fill whose pair was checked when the order or trade was entered.
+pub fn convert_at_last_rate(amount: Money, to: Currency) -> RwaResult<Money> {
+    if amount.currency == to {
+        return Ok(amount);
+    }
+    let (_, numerator, denominator) = lookup(amount.currency, to, u64::MAX)
+        .ok_or_else(|| RwaError::NotFound(pair_name(amount.currency, to)))?;
+    apply(amount, to, numerator, denominator)
+}
+
+// Internal: Refuse entry of an order or trade priced in `from` for an asset
+// priced in `to` unless a fresh rate connects the two
+pub fn require_fresh_rate(from: Currency, to: Currency) -> RwaResult<()> {
+    convert(Money::new(0, from), to, ic_cdk::api::time()).map(|_| ())
+}
+
+fn require_poster(caller: &Principal) -> RwaResult<()> {
+    if has_permission(caller, Permission::PostFxRates) || ORACLES.with(|oracles| oracles.borrow().contains(caller)) {
+        return Ok(());
+    }
+    Err(RwaError::Unauthorized)
+}
+
+// Oracle or admin: Post the current rate for a currency pair
+#[ic_cdk::update]
+pub fn post_fx_rate(base: Currency, quote: Currency, rate: u64) -> RwaResult<FxRate> {
+    let caller = ic_cdk::caller();
+    require_poster(&caller)?;
+    if base == quote {
+        return Err(RwaError::validation("quote", "must differ from base"));
+    }
+    if rate == 0 {
+        return Err(RwaError::validation("rate", "must be greater than zero"));
+    }
+    let posted = FxRate { base, quote, rate, posted_by: caller, posted_at: ic_cdk::api::time() };
+    RATES.with(|rates| rates.borrow_mut().entry((base, quote)).or_default().push(posted.clone()));
+    Ok(posted)
+}
+
+// Rate for converting `base` into `quote` as of `at` (default: now), derived
+// from the reverse pair if that was posted more recently
+#[ic_cdk::query]
+pub fn get_fx_rate(base: Currency, quote: Currency, at: Option<u64>) -> RwaResult<FxRate> {
+    let at = at.unwrap_or_else(ic_cdk::api::time);
+    let (rate, numerator, denominator) = lookup(base, quote, at)
+        .ok_or_else(|| RwaError::NotFound(pair_name(base, quote)))?;
+    if rate.base == base {
+        return Ok(rate);
+    }
+    let inverted = u64::try_from(numerator * RATE_SCALE / denominator)
+        .map_err(|_| RwaError::Overflow(format!("inverse of {}", pair_name(quote, base))))?;
+    Ok(FxRate { base, quote, rate: inverted, ..rate })
+}
+
+// Rates posted for a pair between `from` and `to` (nanoseconds), oldest first
+#[ic_cdk::query]
+pub fn get_fx_rate_history(base: Currency, quote: Currency, from: u64, to: u64) -> Vec<FxRate> {
+    RATES.with(|rates| {
+        rates.borrow().get(&(base, quote)).into_iter().flatten()
+            .filter(|r| r.posted_at >= from && r.posted_at <= to)
+            .cloned()
+            .collect()
+    })
+}
+
+// Convert an amount as of `at` (default: now) using the rate log
+#[ic_cdk::query]
+pub fn convert_amount(amount: Money, to: Currency, at: Option<u64>) -> RwaResult<Money> {
+    convert(amount, to, at.unwrap_or_else(ic_cdk::api::time))
+}
+
+// Admin: Allow a principal to post rates
+#[ic_cdk::update]
+pub fn add_fx_oracle(oracle: Principal) -> RwaResult<Vec<Principal>> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    ORACLES.with(|oracles| oracles.borrow_mut().insert(oracle));
+    list_fx_oracles()
+}
+
+#[ic_cdk::update]
+pub fn remove_fx_oracle(oracle: Principal) -> RwaResult<Vec<Principal>> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    ORACLES.with(|oracles| oracles.borrow_mut().remove(&oracle));
+    list_fx_oracles()
+}
+
+#[ic_cdk::query]
+pub fn list_fx_oracles() -> RwaResult<Vec<Principal>> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    Ok(ORACLES.with(|oracles| oracles.borrow().iter().copied().collect()))
+}
+
+// Admin: How old a rate may be when it is used, in nanoseconds
+#[ic_cdk::update]
+pub fn set_fx_max_age(max_age_nanos: u64) -> RwaResult<u64> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    if max_age_nanos == 0 {
+        return Err(RwaError::validation("max_age_nanos", "must be greater than zero"));
+    }
+    MAX_AGE_NANOS.with(|max_age| *max_age.borrow_mut() = max_age_nanos);
+    Ok(max_age_nanos)
+}
+
+#[ic_cdk::query]
+pub fn get_fx_max_age() -> u64 {
+    MAX_AGE_NANOS.with(|max_age| *max_age.borrow())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(base: Currency, quote: Currency, rate: u64, posted_at: u64) -> FxRate {
+        FxRate { base, quote, rate, posted_by: Principal::anonymous(), posted_at }
+    }
+
+    fn restore(rates: Vec<FxRate>, max_age_nanos: u64) {
+        let mut log: HashMap<(Currency, Currency), Vec<FxRate>> = HashMap::new();
+        for r in rates {
+            log.entry((r.base, r.quote)).or_default().push(r);
+        }
+        restore_state(FxState { rates: log, oracles: BTreeSet::new(), max_age_nanos: Some(max_age_nanos) });
+    }
+
+    #[test]
+    fn amounts_convert_at_the_rate_in_force_at_the_time() {
+        restore(vec![rate(Currency::USD, Currency::INR, 8_312_000_000, 100), rate(Currency::USD, Currency::INR, 8_400_000_000, 200)], 1_000);
+        let dollar = Money::new(100, Currency::USD);
+        assert_eq!(convert(dollar, Currency::INR, 150).unwrap(), Money::new(8_312, Currency::INR));
+        assert_eq!(convert(dollar, Currency::INR, 250).unwrap(), Money::new(8_400, Currency::INR));
+        // The inverse direction uses the same rate, rounded down
+        assert_eq!(convert(Money::new(8_312, Currency::INR), Currency::USD, 150).unwrap(), dollar);
+        assert_eq!(convert(Money::new(83, Currency::INR), Currency::USD, 150).unwrap(), Money::new(0, Currency::USD));
+        assert_eq!(convert(dollar, Currency::USD, 0).unwrap(), dollar);
+    }
+
+    #[test]
+    fn missing_and_stale_rates_are_refused() {
+        restore(vec![rate(Currency::USD, Currency::INR, 8_312_000_000, 100)], 1_000);
+        let dollar = Money::new(100, Currency::USD);
+        assert!(matches!(convert(dollar, Currency::INR, 50), Err(RwaError::NotFound(_))));
+        assert!(matches!(convert(dollar, Currency::ICP, 150), Err(RwaError::NotFound(_))));
+        assert!(convert(dollar, Currency::INR, 1_100).is_ok());
+        assert!(matches!(convert(dollar, Currency::INR, 1_101), Err(RwaError::InvalidState(_))));
+        // Booking a checked fill ignores the age
+        assert_eq!(convert_at_last_rate(dollar, Currency::INR).unwrap(), Money::new(8_312, Currency::INR));
+    }
+
+    #[test]
+    fn the_newer_direction_of_a_pair_wins() {
+        restore(vec![rate(Currency::USD, Currency::INR, 8_000_000_000, 100), rate(Currency::INR, Currency::USD, 1_250_000, 200)], 1_000);
+        // 1 INR = 0.0125 USD, so 1 USD = 80 INR either way; 100 INR = 1.25 USD
+        assert_eq!(convert(Money::new(10_000, Currency::INR), Currency::USD, 300).unwrap(), Money::new(125, Currency::USD));
+        assert_eq!(convert(Money::new(100, Currency::USD), Currency::INR, 300).unwrap(), Money::new(8_000, Currency::INR));
+    }
+}
@@ -0,0 +1,880 @@
+// ICRC-1/ICRC-2 fungible ledger kept for every approved asset. Balances live
+// here, where compliance and escrow can see them; each asset also gets its own
+// thin ledger canister (src/rwa_ledger) that serves the standard ICRC-1/2
+// methods unchanged and forwards them to the `ledger_*` endpoints below.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use ic_stable_structures::StableBTreeMap;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+};
+use crate::access::{Permission, require_permission};
+use crate::asset::{self, Asset};
+use crate::compliance;
+use crate::error::{RwaError, RwaResult};
+use crate::memory::{self, Memory};
+use crate::portfolio;
+use crate::user::require_kyc;
+use crate::notification::{create_notification, NotificationType};
+
+pub type Subaccount = [u8; 32];
+
+const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+const MAX_MEMO_LENGTH: usize = 32;
+// Deduplication window and allowed clock drift for `created_at_time`, in nanoseconds
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn of(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+
+    // The default subaccount and an absent one name the same account
+    pub fn normalized(&self) -> Self {
+        match self.subaccount {
+            Some(sub) if sub == DEFAULT_SUBACCOUNT => Account::of(self.owner),
+            _ => *self,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Maps an RwaError onto ICRC's GenericError with a stable numeric code
+fn generic_error(err: RwaError) -> (Nat, String) {
+    let error_code: u64 = match err {
+        RwaError::NotFound(_) => 1,
+        RwaError::AlreadyExists(_) => 2,
+        RwaError::Unauthorized => 3,
+        RwaError::KycRequired => 4,
+        RwaError::InvalidState(_) => 5,
+        RwaError::InsufficientBalance { .. } => 6,
+        RwaError::Validation { .. } => 7,
+        RwaError::CurrencyMismatch { .. } => 8,
+        RwaError::Overflow(_) => 9,
+        RwaError::TransferRestricted { .. } => 10,
+    };
+    (Nat::from(error_code), err.to_string())
+}
+
+impl From<RwaError> for TransferFromError {
+    fn from(err: RwaError) -> Self {
+        let (error_code, message) = generic_error(err);
+        TransferFromError::GenericError { error_code, message }
+    }
+}
+
+impl From<RwaError> for ApproveError {
+    fn from(err: RwaError) -> Self {
+        let (error_code, message) = generic_error(err);
+        ApproveError::GenericError { error_code, message }
+    }
+}
+
+// Only the variants the shared checks can produce in approve
+impl From<TransferFromError> for ApproveError {
+    fn from(err: TransferFromError) -> Self {
+        match err {
+            TransferFromError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            TransferFromError::InsufficientFunds { balance } => ApproveError::InsufficientFunds { balance },
+            TransferFromError::TooOld => ApproveError::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            TransferFromError::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            TransferFromError::TemporarilyUnavailable => ApproveError::TemporarilyUnavailable,
+            TransferFromError::GenericError { error_code, message } => ApproveError::GenericError { error_code, message },
+            TransferFromError::BadBurn { .. } | TransferFromError::InsufficientAllowance { .. } => {
+                ApproveError::GenericError { error_code: Nat::from(0u64), message: format!("{:?}", err) }
+            }
+        }
+    }
+}
+
+// Plain transfers have no spender, so an allowance error cannot occur
+impl From<TransferFromError> for TransferError {
+    fn from(err: TransferFromError) -> Self {
+        match err {
+            TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            TransferFromError::BadBurn { min_burn_amount } => TransferError::BadBurn { min_burn_amount },
+            TransferFromError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+            TransferFromError::TooOld => TransferError::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+            TransferFromError::GenericError { error_code, message } => TransferError::GenericError { error_code, message },
+            TransferFromError::InsufficientAllowance { .. } => {
+                TransferError::GenericError { error_code: Nat::from(0u64), message: format!("{:?}", err) }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum TransactionKind {
+    Mint,
+    Transfer,
+    Approve,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LedgerTransaction {
+    pub kind: TransactionKind,
+    pub from: Option<Account>,
+    pub to: Option<Account>,
+    pub spender: Option<Account>,
+    pub amount: u64,
+    pub fee: u64,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AssetLedger {
+    pub asset_id: u64,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u64,
+    pub total_supply: u64,
+    pub balances: HashMap<Account, u64>,
+    // Only filled in snapshots before schema v6; transactions now live in stable memory
+    pub transactions: Vec<LedgerTransaction>,
+}
+
+impl AssetLedger {
+    fn balance(&self, account: &Account) -> u64 {
+        self.balances.get(&account.normalized()).copied().unwrap_or(0)
+    }
+
+    fn credit(&mut self, account: &Account, amount: u64) {
+        *self.balances.entry(account.normalized()).or_insert(0) += amount;
+    }
+
+    fn debit(&mut self, account: &Account, amount: u64) -> RwaResult<()> {
+        let key = account.normalized();
+        let available = self.balances.get(&key).copied().unwrap_or(0);
+        if available < amount {
+            return Err(RwaError::InsufficientBalance { required: amount, available });
+        }
+        if available == amount {
+            self.balances.remove(&key);
+        } else {
+            self.balances.insert(key, available - amount);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoredAllowance {
+    pub amount: u64,
+    pub expires_at: Option<u64>,
+}
+
+// Keyed by (asset_id, owner, spender), both accounts normalized
+type AllowanceKey = (u64, Account, Account);
+
+// Init argument of an asset's ledger canister
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LedgerCanisterArgs {
+    pub backend: Principal,
+    pub asset_id: u64,
+}
+
+// Everything the ledger canister's metadata queries need in one read
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LedgerInfo {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: Nat,
+    pub total_supply: Nat,
+    pub minting_account: Account,
+    pub metadata: Vec<(String, MetadataValue)>,
+}
+
+// Cycles each new ledger canister is created with
+const LEDGER_CANISTER_CYCLES: u128 = 1_000_000_000_000;
+
+thread_local! {
+    static LEDGERS: RefCell<HashMap<u64, AssetLedger>> = RefCell::new(HashMap::new());
+    // Keyed by (asset_id, index), in stable memory
+    static TRANSACTIONS: RefCell<StableBTreeMap<(u64, u64), LedgerTransaction, Memory>> =
+        RefCell::new(memory::init_map(memory::LEDGER_TRANSACTIONS));
+    static ALLOWANCES: RefCell<HashMap<AllowanceKey, StoredAllowance>> = RefCell::new(HashMap::new());
+    // Ledger canister serving each asset's ICRC methods
+    static LEDGER_CANISTERS: RefCell<HashMap<u64, Principal>> = RefCell::new(HashMap::new());
+    // Module installed into new ledger canisters
+    static LEDGER_WASM: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+    // Assets whose ledger canister is being deployed
+    static DEPLOYING: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct LedgerState {
+    pub ledgers: HashMap<u64, AssetLedger>,
+    pub allowances: Option<HashMap<AllowanceKey, StoredAllowance>>,
+    pub canisters: Option<HashMap<u64, Principal>>,
+    pub ledger_wasm: Option<Vec<u8>>,
+}
+
+pub fn take_state() -> LedgerState {
+    LedgerState {
+        ledgers: LEDGERS.with(|ledgers| std::mem::take(&mut *ledgers.borrow_mut())),
+        allowances: Some(ALLOWANCES.with(|allowances| std::mem::take(&mut *allowances.borrow_mut()))),
+        canisters: Some(LEDGER_CANISTERS.with(|canisters| std::mem::take(&mut *canisters.borrow_mut()))),
+        ledger_wasm: LEDGER_WASM.with(|wasm| wasm.borrow_mut().take()),
+    }
+}
+
+pub fn restore_state(mut state: LedgerState) {
+    for ledger in state.ledgers.values_mut() {
+        for tx in std::mem::take(&mut ledger.transactions) {
+            record(ledger.asset_id, tx);
+        }
+    }
+    LEDGERS.with(|ledgers| *ledgers.borrow_mut() = state.ledgers);
+    ALLOWANCES.with(|allowances| *allowances.borrow_mut() = state.allowances.unwrap_or_default());
+    LEDGER_CANISTERS.with(|canisters| *canisters.borrow_mut() = state.canisters.unwrap_or_default());
+    LEDGER_WASM.with(|wasm| *wasm.borrow_mut() = state.ledger_wasm);
+}
+
+fn with_ledger<R>(asset_id: u64, f: impl FnOnce(&AssetLedger) -> R) -> RwaResult<R> {
+    LEDGERS.with(|ledgers| {
+        ledgers.borrow().get(&asset_id).map(f).ok_or_else(|| RwaError::not_found("Ledger for asset", asset_id))
+    })
+}
+
+fn with_ledger_mut<R>(asset_id: u64, f: impl FnOnce(&mut AssetLedger) -> RwaResult<R>) -> RwaResult<R> {
+    LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        let ledger = ledgers.get_mut(&asset_id).ok_or_else(|| RwaError::not_found("Ledger for asset", asset_id))?;
+        f(ledger)
+    })
+}
+
+// Appends a transaction to an asset's history and returns its index
+fn record(asset_id: u64, tx: LedgerTransaction) -> u64 {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        let index = transactions.range((asset_id, 0)..=(asset_id, u64::MAX)).next_back()
+            .map_or(0, |((_, last), _)| last + 1);
+        transactions.insert((asset_id, index), tx);
+        index
+    })
+}
+
+// Index of the latest transaction on an asset matching `is_duplicate`. Only
+// transactions recent enough to share a `created_at_time` that still passes
+// `check_created_at` are searched.
+fn find_duplicate(asset_id: u64, now: u64, is_duplicate: impl Fn(&LedgerTransaction) -> bool) -> Option<u64> {
+    TRANSACTIONS.with(|transactions| {
+        transactions.borrow().range((asset_id, 0)..=(asset_id, u64::MAX)).rev()
+            .take_while(|(_, tx)| tx.timestamp.saturating_add(TX_WINDOW_NANOS + 2 * PERMITTED_DRIFT_NANOS) >= now)
+            .find(|(_, tx)| is_duplicate(tx))
+            .map(|((_, index), _)| index)
+    })
+}
+
+fn nat_to_u64(field: &str, value: &Nat) -> RwaResult<u64> {
+    u64::try_from(value.0.clone()).map_err(|_| RwaError::validation(field, "exceeds nat64 range"))
+}
+
+// Account that mints and burns an asset's tokens: the canister itself
+pub fn minting_account() -> Account {
+    Account::of(ic_cdk::id())
+}
+
+// Subaccount tags for canister-owned escrow accounts
+pub const ESCROW_ORDER: u8 = 1;
+pub const ESCROW_TRADE: u8 = 2;
+
+// Internal: Canister-owned account that holds tokens on behalf of an order,
+// trade or offering identified by `tag` and `id`
+pub fn escrow_account(tag: u8, id: u64) -> Account {
+    Account { owner: ic_cdk::id(), subaccount: Some(escrow_subaccount(tag, id)) }
+}
+
+// Internal: Subaccount behind `escrow_account`, also used on payment ledgers
+pub fn escrow_subaccount(tag: u8, id: u64) -> Subaccount {
+    let mut subaccount = DEFAULT_SUBACCOUNT;
+    subaccount[0] = tag;
+    subaccount[24..].copy_from_slice(&id.to_be_bytes());
+    subaccount
+}
+
+// Internal: (tag, id) of an account created by `escrow_account`
+pub fn parse_escrow(account: &Account) -> Option<(u8, u64)> {
+    if account.owner != ic_cdk::id() {
+        return None;
+    }
+    let subaccount = account.subaccount?;
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&subaccount[24..]);
+    Some((subaccount[0], u64::from_be_bytes(id)))
+}
+
+// Internal: Assets that have a ledger
+pub fn ledger_ids() -> Vec<u64> {
+    LEDGERS.with(|ledgers| ledgers.borrow().keys().copied().collect())
+}
+
+// Internal: Every non-zero balance on an asset's ledger
+pub fn balances(asset_id: u64) -> RwaResult<Vec<(Account, u64)>> {
+    with_ledger(asset_id, |ledger| ledger.balances.iter().map(|(account, amount)| (*account, *amount)).collect())
+}
+
+// Internal: Create the ledger for a newly approved asset (no-op if it already exists)
+pub fn open_ledger(asset: &Asset) {
+    LEDGERS.with(|ledgers| {
+        ledgers.borrow_mut().entry(asset.id).or_insert_with(|| AssetLedger {
+            asset_id: asset.id,
+            name: asset.name.clone(),
+            symbol: format!("RWA{}", asset.id),
+            decimals: 0,
+            fee: 0,
+            total_supply: 0,
+            balances: HashMap::new(),
+            transactions: Vec::new(),
+        });
+    });
+}
+
+// Internal: Memos of every mint on an asset's ledger
+pub fn mint_memos(asset_id: u64) -> HashSet<Vec<u8>> {
+    TRANSACTIONS.with(|transactions| {
+        transactions.borrow().range((asset_id, 0)..=(asset_id, u64::MAX))
+            .filter_map(|(_, tx)| if tx.kind == TransactionKind::Mint { tx.memo } else { None })
+            .collect()
+    })
+}
+
+pub fn has_ledger(asset_id: u64) -> bool {
+    LEDGERS.with(|ledgers| ledgers.borrow().contains_key(&asset_id))
+}
+
+// Internal: Issue new tokens to an account
+pub fn mint(asset_id: u64, to: &Account, amount: u64, memo: Option<Vec<u8>>) -> RwaResult<u64> {
+    with_ledger_mut(asset_id, |ledger| {
+        ledger.total_supply = ledger.total_supply.checked_add(amount)
+            .ok_or_else(|| RwaError::validation("amount", "total supply overflow"))?;
+        ledger.credit(to, amount);
+        Ok(record(asset_id, LedgerTransaction {
+            kind: TransactionKind::Mint,
+            from: None,
+            to: Some(to.normalized()),
+            spender: None,
+            amount,
+            fee: 0,
+            memo,
+            created_at_time: None,
+            timestamp: ic_cdk::api::time(),
+        }))
+    })
+}
+
+// Internal: Fee-free balance move performed by the canister on a user's behalf
+pub fn move_balance(asset_id: u64, from: &Account, to: &Account, amount: u64, memo: Option<Vec<u8>>) -> RwaResult<u64> {
+    with_ledger_mut(asset_id, |ledger| {
+        ledger.debit(from, amount)?;
+        ledger.credit(to, amount);
+        Ok(record(asset_id, LedgerTransaction {
+            kind: TransactionKind::Transfer,
+            from: Some(from.normalized()),
+            to: Some(to.normalized()),
+            spender: None,
+            amount,
+            fee: 0,
+            memo,
+            created_at_time: None,
+            timestamp: ic_cdk::api::time(),
+        }))
+    })
+}
+
+// Rejects `created_at_time` values outside the deduplication window
+fn check_created_at(created_at_time: Option<u64>, now: u64) -> Result<(), TransferFromError> {
+    if let Some(created_at) = created_at_time {
+        if created_at.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+            return Err(TransferFromError::TooOld);
+        }
+        if created_at > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(TransferFromError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    Ok(())
+}
+
+fn check_memo(memo: &Option<Vec<u8>>) -> RwaResult<()> {
+    if memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_LENGTH) {
+        return Err(RwaError::validation("memo", format!("must be at most {} bytes", MAX_MEMO_LENGTH)));
+    }
+    Ok(())
+}
+
+fn check_fee(ledger: &AssetLedger, fee: &Option<Nat>) -> Result<u64, TransferFromError> {
+    match fee {
+        Some(fee) if nat_to_u64("fee", fee)? != ledger.fee => {
+            Err(TransferFromError::BadFee { expected_fee: Nat::from(ledger.fee) })
+        }
+        _ => Ok(ledger.fee),
+    }
+}
+
+// Internal: Fee-charging transfer shared by icrc1_transfer and icrc2_transfer_from.
+// When `spender` is set the amount plus fee is also drawn from its allowance.
+fn checked_transfer(
+    asset_id: u64,
+    from: Account,
+    spender: Option<Account>,
+    to: Account,
+    amount: &Nat,
+    fee: &Option<Nat>,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> Result<u64, TransferFromError> {
+    let amount = nat_to_u64("amount", amount)?;
+    let to = to.normalized();
+    let from = from.normalized();
+    let spender = spender.map(|spender| spender.normalized());
+    if to == minting_account() || from == minting_account() {
+        return Err(RwaError::validation("to", "minting and burning go through the issuer endpoints").into());
+    }
+    check_memo(memo)?;
+    compliance::check_transfer(&asset::get_asset(asset_id)?, Some(from.owner), to.owner, amount)?;
+    let now = ic_cdk::api::time();
+    check_created_at(created_at_time, now)?;
+    LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        let ledger = ledgers.get_mut(&asset_id).ok_or_else(|| RwaError::not_found("Ledger for asset", asset_id))?;
+        let fee = check_fee(ledger, fee)?;
+        if created_at_time.is_some() {
+            let duplicate = find_duplicate(asset_id, now, |tx| {
+                tx.kind == TransactionKind::Transfer
+                    && tx.from == Some(from)
+                    && tx.to == Some(to)
+                    && tx.spender == spender
+                    && tx.amount == amount
+                    && tx.created_at_time == created_at_time
+                    && tx.memo == *memo
+            });
+            if let Some(index) = duplicate {
+                return Err(TransferFromError::Duplicate { duplicate_of: Nat::from(index) });
+            }
+        }
+        let required = amount.checked_add(fee).ok_or_else(|| RwaError::validation("amount", "overflow"))?;
+        if let Some(spender) = &spender {
+            let allowance = current_allowance(asset_id, &from, spender, now);
+            if allowance < required {
+                return Err(TransferFromError::InsufficientAllowance { allowance: Nat::from(allowance) });
+            }
+        }
+        let balance = ledger.balance(&from);
+        if balance < required {
+            return Err(TransferFromError::InsufficientFunds { balance: Nat::from(balance) });
+        }
+        ledger.debit(&from, required)?;
+        ledger.credit(&to, amount);
+        if let Some(spender) = &spender {
+            consume_allowance(asset_id, &from, spender, required);
+        }
+        // Fees are burned
+        ledger.total_supply -= fee;
+        Ok(record(asset_id, LedgerTransaction {
+            kind: TransactionKind::Transfer,
+            from: Some(from),
+            to: Some(to),
+            spender,
+            amount,
+            fee,
+            memo: memo.clone(),
+            created_at_time,
+            timestamp: now,
+        }))
+    })
+}
+
+// Allowance of `spender` over `owner`, zero once expired
+fn current_allowance(asset_id: u64, owner: &Account, spender: &Account, now: u64) -> u64 {
+    ALLOWANCES.with(|allowances| {
+        allowances.borrow().get(&(asset_id, owner.normalized(), spender.normalized()))
+            .filter(|a| a.expires_at.is_none_or(|expires_at| expires_at > now))
+            .map_or(0, |a| a.amount)
+    })
+}
+
+fn consume_allowance(asset_id: u64, owner: &Account, spender: &Account, amount: u64) {
+    ALLOWANCES.with(|allowances| {
+        let mut allowances = allowances.borrow_mut();
+        let key = (asset_id, owner.normalized(), spender.normalized());
+        if let Some(allowance) = allowances.get_mut(&key) {
+            allowance.amount = allowance.amount.saturating_sub(amount);
+            if allowance.amount == 0 {
+                allowances.remove(&key);
+            }
+        }
+    })
+}
+
+// Resolves the user a ledger call is made for. The asset's ledger canister
+// calls on behalf of its own callers; anyone else may only act for themselves.
+fn on_behalf_of(asset_id: u64, caller: Principal) -> RwaResult<Principal> {
+    let direct = ic_cdk::caller();
+    if direct == caller || asset_ledger_canister(asset_id) == Some(direct) {
+        return Ok(caller);
+    }
+    Err(RwaError::Unauthorized)
+}
+
+// Internal: Ledger canister serving an asset's ICRC methods, if deployed
+pub fn asset_ledger_canister(asset_id: u64) -> Option<Principal> {
+    LEDGER_CANISTERS.with(|canisters| canisters.borrow().get(&asset_id).copied())
+}
+
+// Name, symbol, decimals, fee, supply, minting account and ICRC-1 metadata of
+// an asset ledger, read by its ledger canister
+#[ic_cdk::query]
+pub fn ledger_info(asset_id: u64) -> RwaResult<LedgerInfo> {
+    with_ledger(asset_id, |ledger| LedgerInfo {
+        name: ledger.name.clone(),
+        symbol: ledger.symbol.clone(),
+        decimals: ledger.decimals,
+        fee: Nat::from(ledger.fee),
+        total_supply: Nat::from(ledger.total_supply),
+        minting_account: minting_account(),
+        metadata: vec![
+            ("icrc1:name".to_string(), MetadataValue::Text(ledger.name.clone())),
+            ("icrc1:symbol".to_string(), MetadataValue::Text(ledger.symbol.clone())),
+            ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(ledger.decimals))),
+            ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(ledger.fee))),
+            ("rwa:asset_id".to_string(), MetadataValue::Nat(Nat::from(ledger.asset_id))),
+        ],
+    })
+}
+
+#[ic_cdk::query]
+pub fn ledger_balance_of(asset_id: u64, account: Account) -> RwaResult<Nat> {
+    with_ledger(asset_id, |ledger| Nat::from(ledger.balance(&account)))
+}
+
+// Books a transfer between users in their portfolios. The units have moved,
+// so a failure traps to roll the transfer back.
+fn book_transfer(from: Principal, to: Principal, asset_id: u64, amount: &Nat) {
+    nat_to_u64("amount", amount)
+        .and_then(|amount| portfolio::record_transfer(from, to, asset_id, amount))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to book transfer of asset #{}: {}", asset_id, e)));
+}
+
+// icrc1_transfer made by `caller`
+#[ic_cdk::update]
+pub fn ledger_transfer(asset_id: u64, caller: Principal, arg: TransferArg) -> Result<Nat, TransferError> {
+    let caller = on_behalf_of(asset_id, caller).map_err(TransferFromError::from)?;
+    let from = Account { owner: caller, subaccount: arg.from_subaccount };
+    let index = checked_transfer(asset_id, from, None, arg.to, &arg.amount, &arg.fee, &arg.memo, arg.created_at_time)?;
+    if arg.to.owner != caller {
+        book_transfer(caller, arg.to.owner, asset_id, &arg.amount);
+        create_notification(arg.to.owner, NotificationType::Investment, format!("You received {} tokens of asset #{}", arg.amount, asset_id));
+    }
+    Ok(Nat::from(index))
+}
+
+// icrc2_approve made by `caller`
+#[ic_cdk::update]
+pub fn ledger_approve(asset_id: u64, caller: Principal, arg: ApproveArgs) -> Result<Nat, ApproveError> {
+    let caller = on_behalf_of(asset_id, caller)?;
+    let from = Account { owner: caller, subaccount: arg.from_subaccount }.normalized();
+    let spender = arg.spender.normalized();
+    if from.owner == spender.owner {
+        return Err(RwaError::validation("spender", "cannot approve an account of the caller").into());
+    }
+    // Spenders receive custody of the tokens, so they pass the same KYC gate as
+    // recipients; the canister itself settles trades and is exempt.
+    if spender.owner != ic_cdk::id() {
+        require_kyc(&spender.owner)?;
+    }
+    check_memo(&arg.memo)?;
+    let amount = nat_to_u64("amount", &arg.amount)?;
+    let now = ic_cdk::api::time();
+    check_created_at(arg.created_at_time, now)?;
+    if arg.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(ApproveError::Expired { ledger_time: now });
+    }
+    if let Some(expected) = &arg.expected_allowance {
+        let current = current_allowance(asset_id, &from, &spender, now);
+        if nat_to_u64("expected_allowance", expected)? != current {
+            return Err(ApproveError::AllowanceChanged { current_allowance: Nat::from(current) });
+        }
+    }
+    let index = LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        let ledger = ledgers.get_mut(&asset_id).ok_or_else(|| RwaError::not_found("Ledger for asset", asset_id))?;
+        let fee = check_fee(ledger, &arg.fee)?;
+        if arg.created_at_time.is_some() {
+            let duplicate = find_duplicate(asset_id, now, |tx| {
+                tx.kind == TransactionKind::Approve
+                    && tx.from == Some(from)
+                    && tx.spender == Some(spender)
+                    && tx.amount == amount
+                    && tx.created_at_time == arg.created_at_time
+                    && tx.memo == arg.memo
+            });
+            if let Some(index) = duplicate {
+                return Err(ApproveError::Duplicate { duplicate_of: Nat::from(index) });
+            }
+        }
+        let balance = ledger.balance(&from);
+        if balance < fee {
+            return Err(ApproveError::InsufficientFunds { balance: Nat::from(balance) });
+        }
+        ledger.debit(&from, fee)?;
+        ledger.total_supply -= fee;
+        ALLOWANCES.with(|allowances| {
+            let mut allowances = allowances.borrow_mut();
+            let key = (asset_id, from, spender);
+            if amount == 0 {
+                allowances.remove(&key);
+            } else {
+                allowances.insert(key, StoredAllowance { amount, expires_at: arg.expires_at });
+            }
+        });
+        Ok(record(asset_id, LedgerTransaction {
+            kind: TransactionKind::Approve,
+            from: Some(from),
+            to: None,
+            spender: Some(spender),
+            amount,
+            fee,
+            memo: arg.memo.clone(),
+            created_at_time: arg.created_at_time,
+            timestamp: now,
+        }))
+    })?;
+    Ok(Nat::from(index))
+}
+
+#[ic_cdk::query]
+pub fn ledger_allowance(asset_id: u64, arg: AllowanceArgs) -> RwaResult<Allowance> {
+    with_ledger(asset_id, |_| {
+        let now = ic_cdk::api::time();
+        let amount = current_allowance(asset_id, &arg.account, &arg.spender, now);
+        let expires_at = ALLOWANCES.with(|allowances| {
+            allowances.borrow().get(&(asset_id, arg.account.normalized(), arg.spender.normalized())).and_then(|a| a.expires_at)
+        });
+        Allowance {
+            allowance: Nat::from(amount),
+            expires_at: if amount == 0 { None } else { expires_at },
+        }
+    })
+}
+
+// icrc2_transfer_from made by `caller`
+#[ic_cdk::update]
+pub fn ledger_transfer_from(asset_id: u64, caller: Principal, arg: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let caller = on_behalf_of(asset_id, caller)?;
+    let spender = Account { owner: caller, subaccount: arg.spender_subaccount };
+    let index = checked_transfer(asset_id, arg.from, Some(spender), arg.to, &arg.amount, &arg.fee, &arg.memo, arg.created_at_time)?;
+    if arg.to.owner != arg.from.owner {
+        book_transfer(arg.from.owner, arg.to.owner, asset_id, &arg.amount);
+        create_notification(arg.to.owner, NotificationType::Investment, format!("You received {} tokens of asset #{}", arg.amount, asset_id));
+        create_notification(arg.from.owner, NotificationType::Investment, format!("{} tokens of asset #{} were transferred from your account by {}", arg.amount, asset_id, caller));
+    }
+    Ok(Nat::from(index))
+}
+
+// Admin: Set the transfer fee of an asset ledger
+#[ic_cdk::update]
+pub fn set_ledger_fee(asset_id: u64, fee: Nat) -> RwaResult<Nat> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    let fee = nat_to_u64("fee", &fee)?;
+    with_ledger_mut(asset_id, |ledger| {
+        ledger.fee = fee;
+        Ok(Nat::from(fee))
+    })
+}
+
+// Admin: Upload the ledger canister module (the rwa_ledger wasm) that
+// `deploy_asset_ledger` installs
+#[ic_cdk::update]
+pub fn set_ledger_wasm(wasm: Vec<u8>) -> RwaResult<()> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    if wasm.is_empty() {
+        return Err(RwaError::validation("wasm", "must not be empty"));
+    }
+    LEDGER_WASM.with(|module| *module.borrow_mut() = Some(wasm));
+    Ok(())
+}
+
+// Marks an asset's ledger canister as being deployed for the duration of the calls
+struct DeployGuard(u64);
+
+impl DeployGuard {
+    fn acquire(asset_id: u64) -> RwaResult<Self> {
+        DEPLOYING.with(|deploying| {
+            if deploying.borrow_mut().insert(asset_id) {
+                Ok(DeployGuard(asset_id))
+            } else {
+                Err(RwaError::InvalidState(format!("Ledger canister of asset #{} is already being deployed", asset_id)))
+            }
+        })
+    }
+}
+
+impl Drop for DeployGuard {
+    fn drop(&mut self) {
+        DEPLOYING.with(|deploying| deploying.borrow_mut().remove(&self.0));
+    }
+}
+
+// Admin: Create the ledger canister of an asset and install the uploaded
+// module, or reinstall it into the asset's existing canister. The canister
+// keeps no state of its own, so reinstalling is how it is upgraded.
+#[ic_cdk::update]
+pub async fn deploy_asset_ledger(asset_id: u64) -> RwaResult<Principal> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    if !has_ledger(asset_id) {
+        return Err(RwaError::not_found("Ledger for asset", asset_id));
+    }
+    let wasm_module = LEDGER_WASM.with(|wasm| wasm.borrow().clone())
+        .ok_or_else(|| RwaError::InvalidState("No ledger canister module uploaded; call set_ledger_wasm first".to_string()))?;
+    let _guard = DeployGuard::acquire(asset_id)?;
+    let (canister_id, mode) = match asset_ledger_canister(asset_id) {
+        Some(canister_id) => (canister_id, CanisterInstallMode::Reinstall),
+        None => {
+            let settings = CanisterSettings { controllers: Some(vec![ic_cdk::id()]), ..Default::default() };
+            let (record,) = create_canister(CreateCanisterArgument { settings: Some(settings) }, LEDGER_CANISTER_CYCLES)
+                .await
+                .map_err(|e| RwaError::InvalidState(format!("Failed to create ledger canister: {:?}", e)))?;
+            // Recorded straight away so a failed install is retried into the same canister
+            LEDGER_CANISTERS.with(|canisters| canisters.borrow_mut().insert(asset_id, record.canister_id));
+            (record.canister_id, CanisterInstallMode::Install)
+        }
+    };
+    let arg = candid::encode_one(LedgerCanisterArgs { backend: ic_cdk::id(), asset_id })
+        .map_err(|e| RwaError::InvalidState(format!("Failed to encode ledger canister arguments: {}", e)))?;
+    install_code(InstallCodeArgument { mode, canister_id, wasm_module, arg })
+        .await
+        .map_err(|e| RwaError::InvalidState(format!("Failed to install ledger canister {}: {:?}", canister_id, e)))?;
+    Ok(canister_id)
+}
+
+// Admin: Point an asset at a ledger canister deployed by other means, or
+// detach it with None
+#[ic_cdk::update]
+pub fn register_asset_ledger(asset_id: u64, canister_id: Option<Principal>) -> RwaResult<()> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    if !has_ledger(asset_id) {
+        return Err(RwaError::not_found("Ledger for asset", asset_id));
+    }
+    LEDGER_CANISTERS.with(|canisters| {
+        let mut canisters = canisters.borrow_mut();
+        match canister_id {
+            Some(canister_id) => canisters.insert(asset_id, canister_id),
+            None => canisters.remove(&asset_id),
+        }
+    });
+    Ok(())
+}
+
+// Ledger canister to use as the ICRC-1/2 token of an asset
+#[ic_cdk::query]
+pub fn get_asset_ledger(asset_id: u64) -> RwaResult<Principal> {
+    asset_ledger_canister(asset_id).ok_or_else(|| RwaError::not_found("Ledger canister for asset", asset_id))
+}
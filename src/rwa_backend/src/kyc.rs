@@ -0,0 +1,295 @@
+// Know-your-customer records. An approval carries the investor's tier,
+// country of residence and accreditation, and expires: the scheduler lapses
+// it at `expires_at` and the user has to be verified again. The user's
+// kyc_status mirrors the outcome; this module keeps the details.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::access::{Permission, has_permission, require_permission};
+use crate::asset::{self, Asset};
+use crate::compliance;
+use crate::error::{RwaError, RwaResult};
+use crate::scheduler::{self, Job};
+use crate::user::{self, KycStatus};
+use crate::notification::{create_notification, NotificationType};
+
+// Levels of verification, each implying the ones below it
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KycTier {
+    // Identity checked
+    Basic,
+    // Identity and address checked
+    Standard,
+    // Source of funds checked as well
+    Enhanced,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct KycRecord {
+    pub user_id: Principal,
+    pub tier: KycTier,
+    // ISO 3166-1 alpha-2 code; None for approvals that predate tiered KYC
+    pub country: Option<String>,
+    pub accredited: bool,
+    // The verification provider's reference for the check
+    pub provider_ref: Option<String>,
+    pub approved_by: Option<Principal>,
+    pub approved_at: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
+// What a compliance officer records when approving a user
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct KycApproval {
+    pub tier: KycTier,
+    pub country: String,
+    pub accredited: bool,
+    pub provider_ref: Option<String>,
+    pub expires_at: u64,
+}
+
+thread_local! {
+    static RECORDS: RefCell<HashMap<Principal, KycRecord>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct KycState {
+    pub records: HashMap<Principal, KycRecord>,
+}
+
+pub fn take_state() -> KycState {
+    KycState {
+        records: RECORDS.with(|records| std::mem::take(&mut *records.borrow_mut())),
+    }
+}
+
+pub fn restore_state(state: KycState) {
+    RECORDS.with(|records| *records.borrow_mut() = state.records);
+}
+
+// Internal: Give users approved before tiered KYC a Basic record without
+// country or expiry. Runs once, on the upgrade that introduces KYC records.
+pub fn adopt_legacy_approvals() {
+    let approved: Vec<Principal> = user::users_with_kyc_status(KycStatus::Approved);
+    RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        for user_id in approved {
+            records.entry(user_id).or_insert(KycRecord {
+                user_id,
+                tier: KycTier::Basic,
+                country: None,
+                accredited: false,
+                provider_ref: None,
+                approved_by: None,
+                approved_at: None,
+                expires_at: None,
+            });
+        }
+    });
+}
+
+// Helper: Check for an ISO 3166-1 alpha-2 code such as "US"
+pub fn is_country_code(value: &str) -> bool {
+    value.len() == 2 && value.chars().all(|c| c.is_ascii_uppercase())
+}
+
+// Internal: The record behind a user's current approval; None unless the user
+// is approved and the approval has not run out
+pub fn current_record(user_id: &Principal) -> Option<KycRecord> {
+    record_granting(user_id, KycTier::Basic, ic_cdk::api::time())
+}
+
+// The record behind a user's approval if it is of at least `tier` and still
+// runs at `now`
+fn record_granting(user_id: &Principal, tier: KycTier, now: u64) -> Option<KycRecord> {
+    if user::get_user(*user_id).ok()?.kyc_status != KycStatus::Approved {
+        return None;
+    }
+    RECORDS.with(|records| records.borrow().get(user_id).cloned())
+        .filter(|r| r.tier >= tier && r.expires_at.is_none_or(|at| at > now))
+}
+
+// Helper: Check if a user's approval has passed its expiry, whether or not the
+// scheduler has lapsed it yet
+pub fn has_lapsed(user_id: &Principal, now: u64) -> bool {
+    RECORDS.with(|records| {
+        records.borrow().get(user_id).and_then(|r| r.expires_at).is_some_and(|at| at <= now)
+    })
+}
+
+// Helper: Fail with KycRequired unless the user holds a current approval of
+// at least `tier`
+pub fn require_kyc_tier(user_id: &Principal, tier: KycTier) -> RwaResult<KycRecord> {
+    record_granting(user_id, tier, ic_cdk::api::time()).ok_or(RwaError::KycRequired)
+}
+
+// Helper: Fail unless `user_id` may receive units of `asset`: a current
+// approval whose jurisdiction and accreditation the asset's rules accept
+pub fn may_hold_asset(user_id: &Principal, asset: &Asset) -> RwaResult<()> {
+    compliance::check_investor(&require_kyc_tier(user_id, KycTier::Basic)?, asset)
+}
+
+// Internal: Approve a user with the given details and schedule the lapse
+pub fn approve(user_id: Principal, approval: KycApproval, reviewer: Principal) -> RwaResult<KycRecord> {
+    let now = ic_cdk::api::time();
+    if !is_country_code(&approval.country) {
+        return Err(RwaError::validation("country", "must be an ISO 3166-1 alpha-2 code such as \"US\""));
+    }
+    if approval.expires_at <= now {
+        return Err(RwaError::validation("expires_at", "must be in the future"));
+    }
+    if approval.provider_ref.as_ref().is_some_and(|r| r.trim().is_empty()) {
+        return Err(RwaError::validation("provider_ref", "must not be blank"));
+    }
+    user::set_kyc_status_internal(user_id, KycStatus::Approved)?;
+    let record = KycRecord {
+        user_id,
+        tier: approval.tier,
+        country: Some(approval.country),
+        accredited: approval.accredited,
+        provider_ref: approval.provider_ref,
+        approved_by: Some(reviewer),
+        approved_at: Some(now),
+        expires_at: Some(approval.expires_at),
+    };
+    RECORDS.with(|records| records.borrow_mut().insert(user_id, record.clone()));
+    scheduler::schedule(Job::ExpireKyc(user_id), approval.expires_at);
+    Ok(record)
+}
+
+// Internal: Approved users whose approval runs out, with the time it does
+pub fn kyc_expiries() -> Vec<(Principal, u64)> {
+    RECORDS.with(|records| {
+        records.borrow().values()
+            .filter_map(|r| r.expires_at.map(|at| (r.user_id, at)))
+            .filter(|(user_id, _)| user::get_user(*user_id).is_ok_and(|u| u.kyc_status == KycStatus::Approved))
+            .collect()
+    })
+}
+
+// Internal: Lapse an approval that has passed its expiry
+pub fn expire(user_id: Principal) -> RwaResult<()> {
+    if !has_lapsed(&user_id, ic_cdk::api::time()) {
+        return Err(RwaError::InvalidState(format!("KYC approval of {} has not expired", user_id)));
+    }
+    user::set_kyc_status_internal(user_id, KycStatus::Expired)?;
+    create_notification(user_id, NotificationType::Kyc, "Your KYC approval has expired; please verify your identity again".to_string());
+    Ok(())
+}
+
+// Compliance: Approve a user's KYC with tier, country, accreditation and expiry
+#[ic_cdk::update]
+pub fn approve_kyc(user_id: Principal, approval: KycApproval) -> RwaResult<KycRecord> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewKyc)?;
+    let record = approve(user_id, approval, caller)?;
+    create_notification(user_id, NotificationType::Kyc, format!("Your KYC was approved at {:?} tier", record.tier));
+    Ok(record)
+}
+
+#[ic_cdk::query]
+pub fn get_kyc_record(user_id: Principal) -> RwaResult<KycRecord> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewUsers) {
+        return Err(RwaError::Unauthorized);
+    }
+    RECORDS.with(|records| records.borrow().get(&user_id).cloned())
+        .ok_or_else(|| RwaError::not_found("KYC record for", user_id))
+}
+
+// Compliance: Approvals running out within `within_nanos` from now, soonest first
+#[ic_cdk::query]
+pub fn list_expiring_kyc(within_nanos: u64) -> RwaResult<Vec<KycRecord>> {
+    require_permission(&ic_cdk::caller(), Permission::ReviewKyc)?;
+    let until = ic_cdk::api::time().saturating_add(within_nanos);
+    let mut expiring: Vec<KycRecord> = kyc_expiries().into_iter()
+        .filter(|(_, at)| *at <= until)
+        .filter_map(|(user_id, _)| RECORDS.with(|records| records.borrow().get(&user_id).cloned()))
+        .collect();
+    expiring.sort_by_key(|r| r.expires_at);
+    Ok(expiring)
+}
+
+// Whether a user may currently hold units of an asset; the error says why not
+#[ic_cdk::query]
+pub fn check_asset_eligibility(user_id: Principal, asset_id: u64) -> RwaResult<()> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewUsers) {
+        return Err(RwaError::Unauthorized);
+    }
+    may_hold_asset(&user_id, &asset::get_asset(asset_id)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{tests::asset, AssetStatus};
+    use crate::compliance::{TransferRule, TransferRules};
+    use crate::user::{User, UserRole, UserState};
+
+    fn user(id: Principal, kyc_status: KycStatus) -> User {
+        User {
+            id,
+            username: id.to_text(),
+            email: String::new(),
+            wallet_address: String::new(),
+            kyc_status,
+            role: UserRole::User,
+            profile: None,
+            notifications: vec![],
+            reporting_currency: None,
+        }
+    }
+
+    fn record(user_id: Principal, tier: KycTier, country: Option<&str>, accredited: bool) -> KycRecord {
+        KycRecord {
+            user_id,
+            tier,
+            country: country.map(str::to_string),
+            accredited,
+            provider_ref: None,
+            approved_by: None,
+            approved_at: Some(0),
+            expires_at: Some(1_000),
+        }
+    }
+
+    #[test]
+    fn approvals_grant_their_tier_and_those_below_until_they_expire() {
+        let (approved, pending) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let users = [(approved, user(approved, KycStatus::Approved)), (pending, user(pending, KycStatus::Pending))];
+        user::restore_state(UserState { users: users.into_iter().collect(), admins: None });
+        let records = [approved, pending].map(|id| (id, record(id, KycTier::Standard, Some("US"), false)));
+        restore_state(KycState { records: records.into_iter().collect() });
+
+        assert!(record_granting(&approved, KycTier::Basic, 999).is_some());
+        assert!(record_granting(&approved, KycTier::Standard, 999).is_some());
+        assert!(record_granting(&approved, KycTier::Enhanced, 999).is_none());
+        assert!(record_granting(&approved, KycTier::Basic, 1_000).is_none());
+        // A record alone is not an approval
+        assert!(record_granting(&pending, KycTier::Basic, 0).is_none());
+    }
+
+    #[test]
+    fn transfer_rules_gate_investors_by_jurisdiction_and_accreditation() {
+        let id = Principal::from_slice(&[3]);
+        let mut restricted = asset(301, AssetStatus::Active);
+        restricted.transfer_rules = Some(TransferRules {
+            allowed_jurisdictions: vec!["US".to_string(), "GB".to_string()],
+            blocked_jurisdictions: vec![],
+            accredited_only: true,
+            ..Default::default()
+        });
+        let refused = |record: &KycRecord| match compliance::check_investor(record, &restricted) {
+            Err(RwaError::TransferRestricted { rule, .. }) => Some(rule),
+            _ => None,
+        };
+        assert_eq!(refused(&record(id, KycTier::Basic, Some("DE"), true)), Some(TransferRule::Jurisdiction));
+        assert_eq!(refused(&record(id, KycTier::Basic, None, true)), Some(TransferRule::Jurisdiction));
+        assert_eq!(refused(&record(id, KycTier::Basic, Some("US"), false)), Some(TransferRule::AccreditedOnly));
+        assert!(compliance::check_investor(&record(id, KycTier::Basic, Some("GB"), true), &restricted).is_ok());
+        // Assets without rules take any approved investor
+        assert!(compliance::check_investor(&record(id, KycTier::Basic, None, false), &asset(302, AssetStatus::Active)).is_ok());
+    }
+}
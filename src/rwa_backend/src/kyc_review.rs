@@ -0,0 +1,359 @@
+// KYC applications and their review queue. A user submits identity details
+// with references to the supporting documents; a compliance officer claims
+// the application, then approves it, rejects it with a reason, or returns it
+// for more information. The user hears of every decision.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::access::{Permission, has_permission, require_permission};
+use crate::asset::NANOS_PER_DAY;
+use crate::error::{RwaError, RwaResult};
+use crate::kyc::{self, is_country_code, KycApproval, KycRecord, KycTier};
+use crate::migration::days_from_civil;
+use crate::user::{self, KycStatus};
+use crate::valuation::is_sha256_hex;
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum ApplicationStatus {
+    // Waiting for an officer to claim it
+    Submitted,
+    InReview,
+    // Returned to the applicant for more information
+    NeedsInfo,
+    Approved,
+    Rejected,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct IdentityDetails {
+    pub legal_name: String,
+    // YYYY-MM-DD
+    pub date_of_birth: String,
+    // ISO 3166-1 alpha-2 codes
+    pub nationality: String,
+    pub country_of_residence: String,
+    pub address: String,
+    // Tier applied for; Enhanced needs source-of-funds evidence
+    pub requested_tier: KycTier,
+    // Applicant claims accredited-investor status
+    pub accredited: bool,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum DocumentKind {
+    Passport,
+    NationalId,
+    DriversLicense,
+    ProofOfAddress,
+    AccreditationLetter,
+    SourceOfFunds,
+    Other(String),
+}
+
+// A document held off-canister, identified by its hash
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct DocumentRef {
+    pub kind: DocumentKind,
+    // Hex SHA-256 of the file
+    pub sha256: String,
+    // Where reviewers can fetch it, e.g. a blob store key
+    pub location: Option<String>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct KycApplication {
+    pub id: u64,
+    pub user_id: Principal,
+    pub identity: IdentityDetails,
+    pub documents: Vec<DocumentRef>,
+    pub status: ApplicationStatus,
+    // Officer who claimed the application
+    pub reviewer_id: Option<Principal>,
+    // Rejection reason or the information asked for
+    pub review_note: Option<String>,
+    pub submitted_at: u64,
+    pub updated_at: u64,
+    pub decided_at: Option<u64>,
+}
+
+impl KycApplication {
+    // Applications still moving through the queue
+    pub fn is_open(&self) -> bool {
+        matches!(self.status, ApplicationStatus::Submitted | ApplicationStatus::InReview | ApplicationStatus::NeedsInfo)
+    }
+}
+
+// Queue filter; unset fields match everything
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct ApplicationFilter {
+    pub status: Option<ApplicationStatus>,
+    pub reviewer_id: Option<Principal>,
+    pub country_of_residence: Option<String>,
+    pub requested_tier: Option<KycTier>,
+}
+
+thread_local! {
+    static APPLICATIONS: RefCell<HashMap<u64, KycApplication>> = RefCell::new(HashMap::new());
+    static APPLICATION_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct KycReviewState {
+    pub applications: HashMap<u64, KycApplication>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> KycReviewState {
+    KycReviewState {
+        applications: APPLICATIONS.with(|applications| std::mem::take(&mut *applications.borrow_mut())),
+        next_id: APPLICATION_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: KycReviewState) {
+    APPLICATIONS.with(|applications| *applications.borrow_mut() = state.applications);
+    APPLICATION_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id.max(1));
+}
+
+fn save_application(application: &KycApplication) {
+    APPLICATIONS.with(|applications| applications.borrow_mut().insert(application.id, application.clone()));
+}
+
+fn validate_application(identity: &IdentityDetails, documents: &[DocumentRef]) -> RwaResult<()> {
+    if identity.legal_name.trim().is_empty() {
+        return Err(RwaError::validation("legal_name", "must not be empty"));
+    }
+    let today = (ic_cdk::api::time() / NANOS_PER_DAY) as i64;
+    if days_from_civil(&identity.date_of_birth).is_none_or(|days| days >= today) {
+        return Err(RwaError::validation("date_of_birth", "must be a past date as YYYY-MM-DD"));
+    }
+    if !is_country_code(&identity.nationality) {
+        return Err(RwaError::validation("nationality", "must be an ISO 3166-1 alpha-2 code"));
+    }
+    if !is_country_code(&identity.country_of_residence) {
+        return Err(RwaError::validation("country_of_residence", "must be an ISO 3166-1 alpha-2 code"));
+    }
+    if identity.address.trim().is_empty() {
+        return Err(RwaError::validation("address", "must not be empty"));
+    }
+    for document in documents {
+        if !is_sha256_hex(&document.sha256) {
+            return Err(RwaError::validation("documents", "each sha256 must be a hex SHA-256 digest"));
+        }
+        if document.location.as_ref().is_some_and(|l| l.trim().is_empty()) {
+            return Err(RwaError::validation("documents", "a location must not be blank"));
+        }
+        if matches!(&document.kind, DocumentKind::Other(name) if name.trim().is_empty()) {
+            return Err(RwaError::validation("documents", "other document kinds must be named"));
+        }
+    }
+    let has = |kinds: &[DocumentKind]| documents.iter().any(|d| kinds.contains(&d.kind));
+    if !has(&[DocumentKind::Passport, DocumentKind::NationalId, DocumentKind::DriversLicense]) {
+        return Err(RwaError::validation("documents", "must include a passport, national ID or driver's license"));
+    }
+    if identity.requested_tier >= KycTier::Standard && !has(&[DocumentKind::ProofOfAddress]) {
+        return Err(RwaError::validation("documents", "must include proof of address for Standard tier and above"));
+    }
+    if identity.requested_tier == KycTier::Enhanced && !has(&[DocumentKind::SourceOfFunds]) {
+        return Err(RwaError::validation("documents", "must include source of funds for Enhanced tier"));
+    }
+    if identity.accredited && !has(&[DocumentKind::AccreditationLetter]) {
+        return Err(RwaError::validation("documents", "must include an accreditation letter to claim accredited status"));
+    }
+    Ok(())
+}
+
+// An application the caller has claimed, in review
+fn claimed_application(id: u64, caller: &Principal) -> RwaResult<KycApplication> {
+    require_permission(caller, Permission::ReviewKyc)?;
+    let application = get_application_internal(id)?;
+    if application.status != ApplicationStatus::InReview {
+        return Err(RwaError::InvalidState(format!("KYC application #{} is {:?}", id, application.status)));
+    }
+    if application.reviewer_id != Some(*caller) {
+        return Err(RwaError::InvalidState(format!("KYC application #{} is claimed by another reviewer", id)));
+    }
+    Ok(application)
+}
+
+fn get_application_internal(id: u64) -> RwaResult<KycApplication> {
+    APPLICATIONS.with(|applications| applications.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("KYC application", id))
+}
+
+// Applicant: Submit identity details and document references for review
+#[ic_cdk::update]
+pub fn submit_kyc_application(identity: IdentityDetails, documents: Vec<DocumentRef>) -> RwaResult<KycApplication> {
+    let caller = ic_cdk::caller();
+    let user = user::get_user(caller)?;
+    let open = APPLICATIONS.with(|applications| {
+        applications.borrow().values().find(|a| a.user_id == caller && a.is_open()).map(|a| a.id)
+    });
+    if let Some(open) = open {
+        return Err(RwaError::AlreadyExists(format!("Open KYC application #{}", open)));
+    }
+    validate_application(&identity, &documents)?;
+    let id = APPLICATION_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    let now = ic_cdk::api::time();
+    let application = KycApplication {
+        id,
+        user_id: caller,
+        identity,
+        documents,
+        status: ApplicationStatus::Submitted,
+        reviewer_id: None,
+        review_note: None,
+        submitted_at: now,
+        updated_at: now,
+        decided_at: None,
+    };
+    save_application(&application);
+    // A renewal leaves a current approval in place until it is decided
+    if user.kyc_status != KycStatus::Approved {
+        user::set_kyc_status_internal(caller, KycStatus::Pending)?;
+    }
+    create_notification(caller, NotificationType::Kyc, format!("Your KYC application #{} was received", id));
+    Ok(application)
+}
+
+// Applicant: Answer a request for more information and return the
+// application to its reviewer
+#[ic_cdk::update]
+pub fn resubmit_kyc_application(id: u64, identity: IdentityDetails, documents: Vec<DocumentRef>) -> RwaResult<KycApplication> {
+    let caller = ic_cdk::caller();
+    let mut application = get_application_internal(id)?;
+    if application.user_id != caller {
+        return Err(RwaError::Unauthorized);
+    }
+    if application.status != ApplicationStatus::NeedsInfo {
+        return Err(RwaError::InvalidState(format!("KYC application #{} is {:?}", id, application.status)));
+    }
+    validate_application(&identity, &documents)?;
+    application.identity = identity;
+    application.documents = documents;
+    application.status = if application.reviewer_id.is_some() { ApplicationStatus::InReview } else { ApplicationStatus::Submitted };
+    application.updated_at = ic_cdk::api::time();
+    save_application(&application);
+    Ok(application)
+}
+
+#[ic_cdk::query]
+pub fn get_kyc_application(id: u64) -> RwaResult<KycApplication> {
+    let caller = ic_cdk::caller();
+    let application = get_application_internal(id)?;
+    if application.user_id != caller && !has_permission(&caller, Permission::ViewUsers) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(application)
+}
+
+// The caller's applications, newest first
+#[ic_cdk::query]
+pub fn list_my_kyc_applications() -> Vec<KycApplication> {
+    let caller = ic_cdk::caller();
+    let mut mine: Vec<KycApplication> = APPLICATIONS.with(|applications| {
+        applications.borrow().values().filter(|a| a.user_id == caller).cloned().collect()
+    });
+    mine.sort_by_key(|a| std::cmp::Reverse(a.id));
+    mine
+}
+
+// Compliance: The review queue, oldest submission first
+#[ic_cdk::query]
+pub fn list_kyc_applications(filter: ApplicationFilter) -> RwaResult<Vec<KycApplication>> {
+    require_permission(&ic_cdk::caller(), Permission::ReviewKyc)?;
+    let mut queue: Vec<KycApplication> = APPLICATIONS.with(|applications| {
+        applications.borrow().values()
+            .filter(|a| filter.status.as_ref().is_none_or(|s| a.status == *s))
+            .filter(|a| filter.reviewer_id.is_none_or(|r| a.reviewer_id == Some(r)))
+            .filter(|a| filter.country_of_residence.as_ref().is_none_or(|c| a.identity.country_of_residence == *c))
+            .filter(|a| filter.requested_tier.is_none_or(|t| a.identity.requested_tier == t))
+            .cloned()
+            .collect()
+    });
+    queue.sort_by_key(|a| (a.submitted_at, a.id));
+    Ok(queue)
+}
+
+// Compliance: Take a submitted application for review
+#[ic_cdk::update]
+pub fn claim_kyc_application(id: u64) -> RwaResult<KycApplication> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewKyc)?;
+    let mut application = get_application_internal(id)?;
+    if application.status != ApplicationStatus::Submitted {
+        return Err(RwaError::InvalidState(format!("KYC application #{} is {:?}", id, application.status)));
+    }
+    if application.user_id == caller {
+        return Err(RwaError::validation("id", "reviewers cannot review their own application"));
+    }
+    application.status = ApplicationStatus::InReview;
+    application.reviewer_id = Some(caller);
+    application.updated_at = ic_cdk::api::time();
+    save_application(&application);
+    Ok(application)
+}
+
+// Compliance: Approve a claimed application. The approval records the tier,
+// country and accreditation the reviewer verified, which may be less than
+// what was applied for.
+#[ic_cdk::update]
+pub fn approve_kyc_application(id: u64, approval: KycApproval) -> RwaResult<KycRecord> {
+    let caller = ic_cdk::caller();
+    let mut application = claimed_application(id, &caller)?;
+    let record = kyc::approve(application.user_id, approval, caller)?;
+    let now = ic_cdk::api::time();
+    application.status = ApplicationStatus::Approved;
+    application.review_note = None;
+    application.updated_at = now;
+    application.decided_at = Some(now);
+    save_application(&application);
+    create_notification(application.user_id, NotificationType::Kyc, format!("Your KYC application #{} was approved at {:?} tier", id, record.tier));
+    Ok(record)
+}
+
+// Compliance: Reject a claimed application
+#[ic_cdk::update]
+pub fn reject_kyc_application(id: u64, reason: String) -> RwaResult<KycApplication> {
+    let caller = ic_cdk::caller();
+    if reason.trim().is_empty() {
+        return Err(RwaError::validation("reason", "must not be empty"));
+    }
+    let mut application = claimed_application(id, &caller)?;
+    let now = ic_cdk::api::time();
+    application.status = ApplicationStatus::Rejected;
+    application.review_note = Some(reason.clone());
+    application.updated_at = now;
+    application.decided_at = Some(now);
+    save_application(&application);
+    // A rejected renewal leaves the current approval to run out on its own
+    if kyc::current_record(&application.user_id).is_none() {
+        user::set_kyc_status_internal(application.user_id, KycStatus::Rejected)?;
+    }
+    create_notification(application.user_id, NotificationType::Kyc, format!("Your KYC application #{} was rejected: {}", id, reason));
+    Ok(application)
+}
+
+// Compliance: Return a claimed application to the applicant, saying what is
+// missing. The reviewer keeps the claim.
+#[ic_cdk::update]
+pub fn request_kyc_information(id: u64, message: String) -> RwaResult<KycApplication> {
+    let caller = ic_cdk::caller();
+    if message.trim().is_empty() {
+        return Err(RwaError::validation("message", "must not be empty"));
+    }
+    let mut application = claimed_application(id, &caller)?;
+    application.status = ApplicationStatus::NeedsInfo;
+    application.review_note = Some(message.clone());
+    application.updated_at = ic_cdk::api::time();
+    save_application(&application);
+    create_notification(application.user_id, NotificationType::Kyc, format!("Your KYC application #{} needs more information: {}", id, message));
+    Ok(application)
+}
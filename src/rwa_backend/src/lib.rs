@@ -1,9 +1,31 @@
 // Main entry for the RWA backend canister
 
+// Candid endpoints take their arguments positionally
+#![allow(clippy::too_many_arguments)]
+
+mod canister;
+mod migration;
+mod memory;
+mod error;
+mod money;
+mod fx;
+mod access;
 mod user;
+mod kyc;
+mod kyc_review;
+mod compliance;
 mod asset;
+mod valuation;
+mod amendment;
 mod token;
+mod icrc;
 mod trade;
+mod orderbook;
+mod cash;
+mod distribution;
+mod payment;
+mod funding;
+mod scheduler;
 mod portfolio;
 mod notification;
 
@@ -0,0 +1,95 @@
+// Stable memory layout. A memory manager splits stable memory into virtual
+// memories: one holds the candid snapshot of the config state written in
+// pre_upgrade, the others back the append-only stores (ledger transactions,
+// cash journal, notifications, portfolio snapshots and the role audit log).
+// Those are written in place, so they survive upgrades without being copied
+// through the heap and do not bound how much history an upgrade can carry.
+
+use std::borrow::Cow;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable};
+use crate::access::RoleChange;
+use crate::cash::CashEntry;
+use crate::icrc::LedgerTransaction;
+use crate::notification::Notification;
+use crate::portfolio::PortfolioSnapshot;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Ids name data already in stable memory: never reuse or renumber one
+const SNAPSHOT: MemoryId = MemoryId::new(0);
+pub const LEDGER_TRANSACTIONS: MemoryId = MemoryId::new(1);
+pub const CASH_JOURNAL_INDEX: MemoryId = MemoryId::new(2);
+pub const CASH_JOURNAL_DATA: MemoryId = MemoryId::new(3);
+pub const NOTIFICATIONS: MemoryId = MemoryId::new(4);
+pub const PORTFOLIO_SNAPSHOTS: MemoryId = MemoryId::new(5);
+pub const ROLE_AUDIT_INDEX: MemoryId = MemoryId::new(6);
+pub const ROLE_AUDIT_DATA: MemoryId = MemoryId::new(7);
+
+// Written at offset 0 of stable memory by the memory manager
+const MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> = MemoryManager::init(DefaultMemoryImpl::default());
+}
+
+fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.get(id))
+}
+
+pub fn init_map<K: Storable + Ord + Clone, V: Storable>(id: MemoryId) -> StableBTreeMap<K, V, Memory> {
+    StableBTreeMap::init(get(id))
+}
+
+pub fn init_log<T: Storable>(index: MemoryId, data: MemoryId) -> StableLog<T, Memory, Memory> {
+    StableLog::init(get(index), get(data))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to open stable log: {:?}", e)))
+}
+
+// Internal: True if stable memory holds a snapshot saved with `stable_save` by
+// a build from before the memory manager (schema v5 and earlier). Must run
+// before anything touches the memory manager, which takes over offset 0.
+pub fn has_legacy_snapshot() -> bool {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    &magic != MANAGER_MAGIC
+}
+
+// Internal: Replace the config snapshot
+pub fn save_snapshot(bytes: Vec<u8>) {
+    StableCell::new(get(SNAPSHOT), bytes)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to save state: {:?}", e)));
+}
+
+// Internal: The config snapshot, if one was ever saved
+pub fn load_snapshot() -> Option<Vec<u8>> {
+    let cell = StableCell::init(get(SNAPSHOT), Vec::new())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to read state: {:?}", e)));
+    Some(cell.get().clone()).filter(|bytes| !bytes.is_empty())
+}
+
+// Stores values as candid, so they decode across releases the same way the
+// snapshot does: fields added later must be `opt`
+macro_rules! candid_storable {
+    ($($t:ty),*) => {$(
+        impl Storable for $t {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(candid::encode_one(self)
+                    .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to encode {}: {}", stringify!($t), e))))
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                candid::decode_one(&bytes)
+                    .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode {}: {}", stringify!($t), e)))
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    )*};
+}
+
+candid_storable!(LedgerTransaction, CashEntry, Notification, PortfolioSnapshot, RoleChange);
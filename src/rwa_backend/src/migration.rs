@@ -0,0 +1,1046 @@
+// Layouts of earlier stable state schemas and their conversion to the current
+// one. Only the records that changed are redeclared here.
+
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::{HashMap, HashSet};
+use crate::amendment::{self, Amendment, AmendmentState, AmendmentStatus, FieldChange};
+use crate::asset::{self, Asset, AssetState, AssetStatus, AssetTransition, EconomicChanges, KeyMetrics};
+use crate::canister::StableState;
+use crate::cash::CashState;
+use crate::access::AccessState;
+use crate::distribution::{Distribution, DistributionPayment, DistributionState, DistributionStatus, PayoutMode};
+use crate::funding::{FundingRound, FundingRoundStatus, FundingState, Subscription};
+use crate::fx::FxState;
+use crate::icrc::{self, Account, LedgerState};
+use crate::kyc::KycState;
+use crate::kyc_review::KycReviewState;
+use crate::notification::{Notification, NotificationState, NotificationType};
+use crate::orderbook::{Order, OrderSide, OrderState, OrderStatus, OrderType};
+use crate::payment::PaymentState;
+use crate::portfolio::{self, CostBasis, CurrencyTotals, PortfolioFlows, PortfolioRecord, PortfolioSnapshot, PortfolioState};
+use crate::money::{BasisPoints, Currency, Money};
+use crate::trade::{Trade, TradeEscrow, TradeState, TradeStatus};
+use crate::user::UserState;
+use crate::valuation::ValuationState;
+
+// Schema v1 kept timestamps and schedule dates as free-form strings: canister
+// time rendered with `to_string`, client-supplied values, or YYYY-MM-DD dates.
+// A bare number is read by magnitude as seconds, milliseconds, microseconds or
+// nanoseconds; dates may carry a time of day and a UTC offset.
+pub fn parse_legacy_time(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(number) = value.parse::<u64>() {
+        let nanos = match number {
+            n if n < 100_000_000_000 => n.checked_mul(1_000_000_000)?,
+            n if n < 100_000_000_000_000 => n.checked_mul(1_000_000)?,
+            n if n < 100_000_000_000_000_000 => n.checked_mul(1_000)?,
+            n => n,
+        };
+        return Some(nanos);
+    }
+    let days = days_from_civil(value.get(..10)?)?;
+    let mut seconds = days.checked_mul(86_400)?;
+    let rest = value.get(10..)?;
+    if let Some(time) = rest.strip_prefix(['T', ' ']) {
+        let hours: i64 = time.get(..2)?.parse().ok()?;
+        let minutes: i64 = time.get(3..5)?.parse().ok()?;
+        let secs: i64 = match time.get(5..6) {
+            Some(":") => time.get(6..8)?.parse().ok()?,
+            _ => 0,
+        };
+        if hours > 23 || minutes > 59 || secs > 60 {
+            return None;
+        }
+        seconds += hours * 3600 + minutes * 60 + secs;
+        // Offsets such as +05:30 are local time ahead of UTC
+        if let Some(at) = time.find(['+', '-']) {
+            let sign = if time[at..].starts_with('+') { 1 } else { -1 };
+            let offset_hours: i64 = time.get(at + 1..at + 3)?.parse().ok()?;
+            let offset_minutes: i64 = time.get(at + 4..at + 6).map_or(Some(0), |m| m.parse().ok())?;
+            seconds -= sign * (offset_hours * 3600 + offset_minutes * 60);
+        }
+    } else if !rest.is_empty() {
+        return None;
+    }
+    u64::try_from(seconds).ok()?.checked_mul(1_000_000_000)
+}
+
+// Days since 1970-01-01 of a YYYY-MM-DD date in the proleptic Gregorian calendar
+pub fn days_from_civil(date: &str) -> Option<i64> {
+    let mut parts = date.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+// Creation times that cannot be read are recorded as 0
+fn created_at(value: &str, record: &str, id: u64) -> u64 {
+    parse_legacy_time(value).unwrap_or_else(|| {
+        ic_cdk::println!("Migration: unreadable created_at {:?} on {} #{}; set to 0", value, record, id);
+        0
+    })
+}
+
+// Schedule dates that cannot be read are dropped; the issuer sets them again
+fn schedule_date(value: Option<String>, field: &str, asset_id: u64) -> Option<u64> {
+    let value = value?;
+    let parsed = parse_legacy_time(&value);
+    if parsed.is_none() {
+        ic_cdk::println!("Migration: unreadable {} {:?} on asset #{}; cleared", field, value, asset_id);
+    }
+    parsed
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AssetV1 {
+    pub id: u64,
+    pub owner_id: Principal,
+    pub name: String,
+    pub description: String,
+    pub category: String,
+    pub location: String,
+    pub images: Vec<String>,
+    pub documents: Vec<String>,
+    pub total_value: u64,
+    pub token_price: u64,
+    pub total_tokens: u64,
+    pub available_tokens: u64,
+    pub apy: f64,
+    pub status: AssetStatus,
+    pub launch_date: Option<String>,
+    pub funding_deadline: Option<String>,
+    pub monthly_income: Option<u64>,
+    pub risk_rating: Option<String>,
+    pub key_metrics: Option<KeyMetricsV2>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AssetStateV1 {
+    pub assets: HashMap<u64, AssetV1>,
+    pub next_id: u64,
+    pub history: Option<HashMap<u64, Vec<AssetTransition>>>,
+}
+
+impl From<AssetStateV1> for AssetStateV2 {
+    fn from(state: AssetStateV1) -> Self {
+        let assets = state.assets.into_iter().map(|(id, a)| {
+            let asset = AssetV2 {
+                id: a.id,
+                owner_id: a.owner_id,
+                name: a.name,
+                description: a.description,
+                category: a.category,
+                location: a.location,
+                images: a.images,
+                documents: a.documents,
+                total_value: a.total_value,
+                token_price: a.token_price,
+                total_tokens: a.total_tokens,
+                available_tokens: a.available_tokens,
+                apy: a.apy,
+                status: a.status,
+                launch_date: schedule_date(a.launch_date, "launch_date", a.id),
+                funding_deadline: schedule_date(a.funding_deadline, "funding_deadline", a.id),
+                monthly_income: a.monthly_income,
+                risk_rating: a.risk_rating,
+                key_metrics: a.key_metrics,
+            };
+            (id, asset)
+        });
+        AssetStateV2 { assets: assets.collect(), next_id: state.next_id, history: state.history }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TradeV1 {
+    pub id: u64,
+    pub buyer_id: Principal,
+    pub seller_id: Principal,
+    pub token_id: u64,
+    pub asset_id: u64,
+    pub quantity: u64,
+    pub price: u64,
+    pub currency: Currency,
+    pub status: TradeStatus,
+    pub created_at: String,
+    pub filled: u64,
+    pub buy_order_id: Option<u64>,
+    pub sell_order_id: Option<u64>,
+    pub escrow: Option<TradeEscrow>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TradeStateV1 {
+    pub trades: HashMap<u64, TradeV1>,
+    pub next_id: u64,
+}
+
+impl From<TradeStateV1> for TradeStateV4 {
+    fn from(state: TradeStateV1) -> Self {
+        let trades = state.trades.into_iter().map(|(id, t)| {
+            let trade = TradeV4 {
+                id: t.id,
+                buyer_id: t.buyer_id,
+                seller_id: t.seller_id,
+                token_id: t.token_id,
+                asset_id: t.asset_id,
+                quantity: t.quantity,
+                price: t.price,
+                currency: t.currency,
+                status: t.status,
+                created_at: created_at(&t.created_at, "trade", t.id),
+                filled: t.filled,
+                buy_order_id: t.buy_order_id,
+                sell_order_id: t.sell_order_id,
+                escrow: t.escrow,
+                expires_at: t.expires_at,
+            };
+            (id, trade)
+        });
+        TradeStateV4 { trades: trades.collect(), next_id: state.next_id }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct NotificationV1 {
+    pub id: u64,
+    pub user_id: Principal,
+    pub notification_type: NotificationType,
+    pub message: String,
+    pub read: bool,
+    pub created_at: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct NotificationStateV1 {
+    pub notifications: HashMap<u64, NotificationV1>,
+    pub next_id: u64,
+}
+
+impl From<NotificationStateV1> for NotificationState {
+    fn from(state: NotificationStateV1) -> Self {
+        let notifications = state.notifications.into_iter().map(|(id, n)| {
+            let notification = Notification {
+                id: n.id,
+                user_id: n.user_id,
+                notification_type: n.notification_type,
+                message: n.message,
+                read: n.read,
+                created_at: created_at(&n.created_at, "notification", n.id),
+            };
+            (id, notification)
+        });
+        NotificationState { notifications: notifications.collect(), next_id: state.next_id }
+    }
+}
+
+// Schema v1: timestamps on assets, trades and notifications were strings
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV1 {
+    pub users: UserState,
+    pub assets: AssetStateV1,
+    pub tokens: TokenStateV5,
+    pub trades: TradeStateV1,
+    pub portfolios: PortfolioStateV3,
+    pub notifications: NotificationStateV1,
+    pub ledgers: Option<LedgerState>,
+    pub orders: Option<OrderStateV4>,
+    pub cash: Option<CashState>,
+    pub payments: Option<PaymentState>,
+    pub funding: Option<FundingStateV4>,
+    pub amendments: Option<AmendmentStateV2>,
+    pub distributions: Option<DistributionStateV4>,
+}
+
+impl From<StableStateV1> for StableStateV2 {
+    fn from(state: StableStateV1) -> Self {
+        StableStateV2 {
+            users: state.users,
+            assets: state.assets.into(),
+            tokens: state.tokens,
+            trades: state.trades.into(),
+            portfolios: state.portfolios,
+            notifications: state.notifications.into(),
+            ledgers: state.ledgers,
+            orders: state.orders,
+            cash: state.cash,
+            payments: state.payments,
+            funding: state.funding,
+            amendments: state.amendments,
+            distributions: state.distributions,
+        }
+    }
+}
+
+// Schema v2 priced assets in bare u64 amounts and held rates as f64 percentages
+#[derive(Clone, CandidType, Deserialize)]
+pub struct KeyMetricsV2 {
+    pub cap_rate: Option<f64>,
+    pub occupancy_rate: Option<f64>,
+    pub location_score: Option<f64>,
+    pub liquidity_rating: Option<String>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct AssetV2 {
+    pub id: u64,
+    pub owner_id: Principal,
+    pub name: String,
+    pub description: String,
+    pub category: String,
+    pub location: String,
+    pub images: Vec<String>,
+    pub documents: Vec<String>,
+    pub total_value: u64,
+    pub token_price: u64,
+    pub total_tokens: u64,
+    pub available_tokens: u64,
+    pub apy: f64,
+    pub status: AssetStatus,
+    pub launch_date: Option<u64>,
+    pub funding_deadline: Option<u64>,
+    pub monthly_income: Option<u64>,
+    pub risk_rating: Option<String>,
+    pub key_metrics: Option<KeyMetricsV2>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AssetStateV2 {
+    pub assets: HashMap<u64, AssetV2>,
+    pub next_id: u64,
+    pub history: Option<HashMap<u64, Vec<AssetTransition>>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EconomicChangesV2 {
+    pub total_value: Option<u64>,
+    pub token_price: Option<u64>,
+    pub total_tokens: Option<u64>,
+    pub apy: Option<f64>,
+    pub monthly_income: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AmendmentV2 {
+    pub id: u64,
+    pub asset_id: u64,
+    pub requested_by: Principal,
+    pub changes: EconomicChangesV2,
+    pub diff: Vec<FieldChange>,
+    pub reason: String,
+    pub status: AmendmentStatus,
+    pub reviewer_id: Option<Principal>,
+    pub review_note: Option<String>,
+    pub created_at: u64,
+    pub reviewed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AmendmentStateV2 {
+    pub amendments: HashMap<u64, AmendmentV2>,
+    pub next_id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV2 {
+    pub users: UserState,
+    pub assets: AssetStateV2,
+    pub tokens: TokenStateV5,
+    pub trades: TradeStateV4,
+    pub portfolios: PortfolioStateV3,
+    pub notifications: NotificationState,
+    pub ledgers: Option<LedgerState>,
+    pub orders: Option<OrderStateV4>,
+    pub cash: Option<CashState>,
+    pub payments: Option<PaymentState>,
+    pub funding: Option<FundingStateV4>,
+    pub amendments: Option<AmendmentStateV2>,
+    pub distributions: Option<DistributionStateV4>,
+}
+
+// v2 rates were percentages: 7.25 becomes 725 basis points
+fn basis_points(percent: f64) -> BasisPoints {
+    BasisPoints((percent * 100.0).round().clamp(0.0, u32::MAX as f64) as u32)
+}
+
+// Amounts were already counted in minor units and keep their value; an asset
+// takes the currency of its funding round, USD if it never had one
+fn asset_v3(a: AssetV2, currency: Currency) -> Asset {
+    Asset {
+        id: a.id,
+        owner_id: a.owner_id,
+        name: a.name,
+        description: a.description,
+        category: a.category,
+        location: a.location,
+        images: a.images,
+        documents: a.documents,
+        total_value: Money::new(a.total_value, currency),
+        token_price: Money::new(a.token_price, currency),
+        total_tokens: a.total_tokens,
+        available_tokens: a.available_tokens,
+        apy: basis_points(a.apy),
+        status: a.status,
+        launch_date: a.launch_date,
+        funding_deadline: a.funding_deadline,
+        monthly_income: a.monthly_income.map(|v| Money::new(v, currency)),
+        risk_rating: a.risk_rating,
+        key_metrics: a.key_metrics.map(|m| KeyMetrics {
+            cap_rate: m.cap_rate.map(basis_points),
+            occupancy_rate: m.occupancy_rate.map(basis_points),
+            location_score: m.location_score,
+            liquidity_rating: m.liquidity_rating,
+        }),
+        valued_at: None,
+        transfer_rules: None,
+    }
+}
+
+fn changes_v3(c: &EconomicChangesV2, currency: Currency) -> EconomicChanges {
+    EconomicChanges {
+        total_value: c.total_value.map(|v| Money::new(v, currency)),
+        token_price: c.token_price.map(|v| Money::new(v, currency)),
+        total_tokens: c.total_tokens,
+        apy: c.apy.map(basis_points),
+        monthly_income: c.monthly_income.map(|v| Money::new(v, currency)),
+    }
+}
+
+// The v2 rendering of an amendment diff, to tell which pending amendments
+// were still current when the snapshot was taken
+fn diff_v2(asset: &AssetV2, changes: &EconomicChangesV2) -> Vec<FieldChange> {
+    let fields = [
+        ("total_value", asset.total_value.to_string(), changes.total_value.map(|v| v.to_string())),
+        ("token_price", asset.token_price.to_string(), changes.token_price.map(|v| v.to_string())),
+        ("total_tokens", asset.total_tokens.to_string(), changes.total_tokens.map(|v| v.to_string())),
+        ("apy", asset.apy.to_string(), changes.apy.map(|v| v.to_string())),
+        ("monthly_income", format!("{:?}", asset.monthly_income), changes.monthly_income.map(|v| format!("{:?}", Some(v)))),
+    ];
+    fields.into_iter()
+        .filter_map(|(field, old_value, new_value)| {
+            new_value.filter(|new_value| *new_value != old_value).map(|new_value| FieldChange {
+                field: field.to_string(),
+                old_value,
+                new_value,
+            })
+        })
+        .collect()
+}
+
+impl From<StableStateV2> for StableStateV3 {
+    fn from(state: StableStateV2) -> Self {
+        let mut currencies: HashMap<u64, Currency> = HashMap::new();
+        for round in state.funding.iter().flat_map(|f| f.rounds.values()) {
+            currencies.insert(round.asset_id, round.currency);
+        }
+        let currency_of = |asset_id: u64| currencies.get(&asset_id).copied().unwrap_or(Currency::USD);
+        let amendments = state.amendments.map(|amendments| {
+            let converted = amendments.amendments.into_iter().map(|(id, a)| {
+                let currency = currency_of(a.asset_id);
+                let changes = changes_v3(&a.changes, currency);
+                // Re-render the diff of a pending amendment in the new format so
+                // approval does not mistake it for stale; stale ones stay stale
+                let diff = match state.assets.assets.get(&a.asset_id) {
+                    Some(asset) if a.status == AmendmentStatus::Pending && diff_v2(asset, &a.changes) == a.diff => {
+                        amendment::diff(&asset_v3(asset.clone(), currency), &changes)
+                    }
+                    _ => a.diff,
+                };
+                let amendment = Amendment {
+                    id: a.id,
+                    asset_id: a.asset_id,
+                    requested_by: a.requested_by,
+                    changes,
+                    diff,
+                    reason: a.reason,
+                    status: a.status,
+                    reviewer_id: a.reviewer_id,
+                    review_note: a.review_note,
+                    created_at: a.created_at,
+                    reviewed_at: a.reviewed_at,
+                };
+                (id, amendment)
+            });
+            AmendmentState { amendments: converted.collect(), next_id: amendments.next_id }
+        });
+        let assets = state.assets.assets.into_iter()
+            .map(|(id, a)| (id, asset_v3(a, currency_of(id))))
+            .collect();
+        StableStateV3 {
+            users: state.users,
+            assets: AssetState { assets, next_id: state.assets.next_id, history: state.assets.history },
+            tokens: state.tokens,
+            trades: state.trades,
+            portfolios: state.portfolios,
+            notifications: state.notifications,
+            ledgers: state.ledgers,
+            orders: state.orders,
+            cash: state.cash,
+            payments: state.payments,
+            funding: state.funding,
+            amendments,
+            distributions: state.distributions,
+        }
+    }
+}
+
+// Schema v3 summed portfolio values and flows across currencies. Assets were
+// overwhelmingly USD-priced then, so the sums are carried over as USD.
+#[derive(CandidType, Deserialize)]
+pub struct PortfolioSnapshotV3 {
+    pub timestamp: u64,
+    pub market_value: u64,
+    pub cost_basis: u64,
+    pub flows: PortfolioFlows,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PortfolioStateV3 {
+    pub portfolios: HashMap<Principal, PortfolioRecord>,
+    pub cost_basis: Option<HashMap<(Principal, u64), CostBasis>>,
+    pub flows: Option<HashMap<Principal, PortfolioFlows>>,
+    pub snapshots: Option<HashMap<Principal, Vec<PortfolioSnapshotV3>>>,
+}
+
+impl From<PortfolioStateV3> for PortfolioState {
+    fn from(state: PortfolioStateV3) -> Self {
+        let snapshot = |s: PortfolioSnapshotV3| PortfolioSnapshot {
+            timestamp: s.timestamp,
+            totals: vec![CurrencyTotals {
+                currency: Currency::USD,
+                market_value: s.market_value,
+                cost_basis: s.cost_basis,
+                flows: s.flows,
+            }],
+        };
+        PortfolioState {
+            portfolios: state.portfolios,
+            cost_basis: state.cost_basis,
+            flows: state.flows.map(|flows| flows.into_iter().map(|(user, f)| ((user, Currency::USD), f)).collect()),
+            snapshots: state.snapshots.map(|snapshots| {
+                snapshots.into_iter().map(|(user, history)| (user, history.into_iter().map(snapshot).collect())).collect()
+            }),
+            snapshot_run: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV3 {
+    pub users: UserState,
+    pub assets: AssetState,
+    pub tokens: TokenStateV5,
+    pub trades: TradeStateV4,
+    pub portfolios: PortfolioStateV3,
+    pub notifications: NotificationState,
+    pub ledgers: Option<LedgerState>,
+    pub orders: Option<OrderStateV4>,
+    pub cash: Option<CashState>,
+    pub payments: Option<PaymentState>,
+    pub funding: Option<FundingStateV4>,
+    pub amendments: Option<AmendmentState>,
+    pub distributions: Option<DistributionStateV4>,
+}
+
+impl From<StableStateV3> for StableStateV6 {
+    fn from(state: StableStateV3) -> Self {
+        StableStateV6 {
+            users: state.users,
+            assets: state.assets,
+            tokens: Some(state.tokens),
+            trades: state.trades.into(),
+            portfolios: state.portfolios.into(),
+            notifications: state.notifications,
+            ledgers: state.ledgers,
+            orders: state.orders.map(Into::into),
+            cash: state.cash,
+            payments: state.payments,
+            funding: state.funding.map(Into::into),
+            amendments: state.amendments,
+            distributions: state.distributions.map(Into::into),
+            fx: None,
+            valuations: None,
+            access: None,
+            kyc: None,
+            kyc_applications: None,
+        }
+    }
+}
+
+// Schema v4 kept trade, order, funding and distribution amounts as bare u64
+// beside a currency field; each becomes a Money in that currency
+#[derive(CandidType, Deserialize)]
+pub struct TradeV4 {
+    pub id: u64,
+    pub buyer_id: Principal,
+    pub seller_id: Principal,
+    pub token_id: u64,
+    pub asset_id: u64,
+    pub quantity: u64,
+    pub price: u64,
+    pub currency: Currency,
+    pub status: TradeStatus,
+    pub created_at: u64,
+    pub filled: u64,
+    pub buy_order_id: Option<u64>,
+    pub sell_order_id: Option<u64>,
+    pub escrow: Option<TradeEscrow>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TradeStateV4 {
+    pub trades: HashMap<u64, TradeV4>,
+    pub next_id: u64,
+}
+
+impl From<TradeStateV4> for TradeState {
+    fn from(state: TradeStateV4) -> Self {
+        let trades = state.trades.into_iter().map(|(id, t)| {
+            let trade = Trade {
+                id: t.id,
+                buyer_id: t.buyer_id,
+                seller_id: t.seller_id,
+                asset_id: t.asset_id,
+                quantity: t.quantity,
+                price: Money::new(t.price, t.currency),
+                status: t.status,
+                created_at: t.created_at,
+                filled: t.filled,
+                buy_order_id: t.buy_order_id,
+                sell_order_id: t.sell_order_id,
+                escrow: t.escrow,
+                expires_at: t.expires_at,
+            };
+            (id, trade)
+        });
+        TradeState { trades: trades.collect(), next_id: state.next_id }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct OrderV4 {
+    pub id: u64,
+    pub asset_id: u64,
+    pub owner_id: Principal,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub price: Option<u64>,
+    pub quantity: u64,
+    pub filled: u64,
+    pub currency: Currency,
+    pub status: OrderStatus,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct OrderStateV4 {
+    pub orders: HashMap<u64, OrderV4>,
+    pub next_id: u64,
+}
+
+impl From<OrderStateV4> for OrderState {
+    fn from(state: OrderStateV4) -> Self {
+        let orders = state.orders.into_iter().map(|(id, o)| {
+            let order = Order {
+                id: o.id,
+                asset_id: o.asset_id,
+                owner_id: o.owner_id,
+                side: o.side,
+                order_type: o.order_type,
+                price: o.price.map(|p| Money::new(p, o.currency)),
+                quantity: o.quantity,
+                filled: o.filled,
+                currency: o.currency,
+                status: o.status,
+                created_at: o.created_at,
+            };
+            (id, order)
+        });
+        OrderState { orders: orders.collect(), next_id: state.next_id }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SubscriptionV4 {
+    pub investor_id: Principal,
+    pub tokens: u64,
+    pub price: u64,
+    pub amount: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct FundingRoundV4 {
+    pub id: u64,
+    pub asset_id: u64,
+    pub issuer_id: Principal,
+    pub currency: Currency,
+    pub min_raise: u64,
+    pub raised: u64,
+    pub tokens_subscribed: u64,
+    pub deadline: u64,
+    pub status: FundingRoundStatus,
+    pub subscriptions: Vec<SubscriptionV4>,
+    pub created_at: u64,
+    pub closed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct FundingStateV4 {
+    pub rounds: HashMap<u64, FundingRoundV4>,
+    pub next_id: u64,
+}
+
+impl From<FundingStateV4> for FundingState {
+    fn from(state: FundingStateV4) -> Self {
+        let rounds = state.rounds.into_iter().map(|(id, r)| {
+            let currency = r.currency;
+            let subscriptions = r.subscriptions.into_iter().map(|s| Subscription {
+                investor_id: s.investor_id,
+                tokens: s.tokens,
+                price: Money::new(s.price, currency),
+                amount: Money::new(s.amount, currency),
+                created_at: s.created_at,
+            });
+            let round = FundingRound {
+                id: r.id,
+                asset_id: r.asset_id,
+                issuer_id: r.issuer_id,
+                min_raise: Money::new(r.min_raise, currency),
+                raised: Money::new(r.raised, currency),
+                tokens_subscribed: r.tokens_subscribed,
+                deadline: r.deadline,
+                status: r.status,
+                subscriptions: subscriptions.collect(),
+                created_at: r.created_at,
+                closed_at: r.closed_at,
+            };
+            (id, round)
+        });
+        FundingState { rounds: rounds.collect(), next_id: state.next_id }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DistributionPaymentV4 {
+    pub holder_id: Principal,
+    pub units: u64,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DistributionV4 {
+    pub id: u64,
+    pub asset_id: u64,
+    pub depositor_id: Principal,
+    pub currency: Currency,
+    pub amount: u64,
+    pub period: String,
+    pub record_date: u64,
+    pub payout_mode: PayoutMode,
+    pub status: DistributionStatus,
+    pub total_units: u64,
+    pub payments: Vec<DistributionPaymentV4>,
+    pub created_at: u64,
+    pub paid_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DistributionStateV4 {
+    pub distributions: HashMap<u64, DistributionV4>,
+    pub next_id: u64,
+}
+
+impl From<DistributionStateV4> for DistributionState {
+    fn from(state: DistributionStateV4) -> Self {
+        let distributions = state.distributions.into_iter().map(|(id, d)| {
+            let currency = d.currency;
+            let payments = d.payments.into_iter().map(|p| DistributionPayment {
+                holder_id: p.holder_id,
+                units: p.units,
+                amount: Money::new(p.amount, currency),
+            });
+            let distribution = Distribution {
+                id: d.id,
+                asset_id: d.asset_id,
+                depositor_id: d.depositor_id,
+                amount: Money::new(d.amount, currency),
+                period: d.period,
+                record_date: d.record_date,
+                payout_mode: d.payout_mode,
+                status: d.status,
+                total_units: d.total_units,
+                payments: payments.collect(),
+                created_at: d.created_at,
+                paid_at: d.paid_at,
+            };
+            (id, distribution)
+        });
+        DistributionState { distributions: distributions.collect(), next_id: state.next_id }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV4 {
+    pub users: UserState,
+    pub assets: AssetState,
+    pub tokens: TokenStateV5,
+    pub trades: TradeStateV4,
+    pub portfolios: PortfolioState,
+    pub notifications: NotificationState,
+    pub ledgers: Option<LedgerState>,
+    pub orders: Option<OrderStateV4>,
+    pub cash: Option<CashState>,
+    pub payments: Option<PaymentState>,
+    pub funding: Option<FundingStateV4>,
+    pub amendments: Option<AmendmentState>,
+    pub distributions: Option<DistributionStateV4>,
+    pub fx: Option<FxState>,
+    pub valuations: Option<ValuationState>,
+    pub access: Option<AccessState>,
+    pub kyc: Option<KycState>,
+    pub kyc_applications: Option<KycReviewState>,
+}
+
+impl From<StableStateV4> for StableStateV6 {
+    fn from(state: StableStateV4) -> Self {
+        StableStateV6 {
+            users: state.users,
+            assets: state.assets,
+            tokens: Some(state.tokens),
+            trades: state.trades.into(),
+            portfolios: state.portfolios,
+            notifications: state.notifications,
+            ledgers: state.ledgers,
+            orders: state.orders.map(Into::into),
+            cash: state.cash,
+            payments: state.payments,
+            funding: state.funding.map(Into::into),
+            amendments: state.amendments,
+            distributions: state.distributions.map(Into::into),
+            fx: state.fx,
+            valuations: state.valuations,
+            access: state.access,
+            kyc: state.kyc,
+            kyc_applications: state.kyc_applications,
+        }
+    }
+}
+
+// Up to schema v5 tokens were also kept as lots beside the asset ledgers. Lots
+// minted before an asset had a ledger are on no ledger at all, so restore mints
+// those to their owners; the rest were credited when issued and are skipped.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub enum TokenStatus {
+    Available,
+    Sold,
+    Locked,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TokenLot {
+    pub id: u64,
+    pub asset_id: u64,
+    pub owner_id: Principal,
+    pub amount: u64,
+    pub price: u64,
+    pub status: TokenStatus,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct TokenStateV5 {
+    pub tokens: HashMap<u64, TokenLot>,
+    pub next_id: u64,
+}
+
+// Memo of the mint that credited a lot on its asset ledger
+pub fn lot_memo(lot_id: u64) -> Vec<u8> {
+    lot_id.to_be_bytes().to_vec()
+}
+
+// Lots holding units that no mint on the ledger accounts for, oldest first
+pub fn unminted_lots(state: TokenStateV5, mut is_minted: impl FnMut(&TokenLot) -> bool) -> Vec<TokenLot> {
+    let mut lots: Vec<TokenLot> = state.tokens.into_values()
+        .filter(|lot| lot.amount > 0 && !is_minted(lot))
+        .collect();
+    lots.sort_by_key(|lot| lot.id);
+    lots
+}
+
+// Credit each unminted lot to its owner on the asset ledger, opening the
+// ledger of an asset that has none, and count it as bought at the lot price
+pub fn mint_lots(state: TokenStateV5) {
+    let mut minted: HashMap<u64, HashSet<Vec<u8>>> = HashMap::new();
+    let lots = unminted_lots(state, |lot| {
+        minted.entry(lot.asset_id)
+            .or_insert_with(|| icrc::mint_memos(lot.asset_id))
+            .contains(&lot_memo(lot.id))
+    });
+    for lot in lots {
+        let Ok(asset) = asset::get_asset(lot.asset_id) else {
+            ic_cdk::println!("Migration: lot #{} of {} units belongs to missing asset #{}; dropped", lot.id, lot.amount, lot.asset_id);
+            continue;
+        };
+        icrc::open_ledger(&asset);
+        icrc::mint(lot.asset_id, &Account::of(lot.owner_id), lot.amount, Some(lot_memo(lot.id)))
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to mint lot #{} of asset #{}: {}", lot.id, lot.asset_id, e)));
+        // The units are minted either way; an unbookable cost only leaves it unknown
+        if let Err(e) = portfolio::record_purchase(lot.owner_id, lot.asset_id, lot.amount, Money::new(lot.price, asset.currency())) {
+            ic_cdk::println!("Migration: cost of lot #{} not booked: {}", lot.id, e);
+        }
+    }
+}
+
+// Schemas v5 and v6: the current layout, with the token lots of a v5 snapshot
+// taken before the lot store was dropped
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV6 {
+    pub users: UserState,
+    pub assets: AssetState,
+    pub tokens: Option<TokenStateV5>,
+    pub trades: TradeState,
+    pub portfolios: PortfolioState,
+    pub notifications: NotificationState,
+    pub ledgers: Option<LedgerState>,
+    pub orders: Option<OrderState>,
+    pub cash: Option<CashState>,
+    pub payments: Option<PaymentState>,
+    pub funding: Option<FundingState>,
+    pub amendments: Option<AmendmentState>,
+    pub distributions: Option<DistributionState>,
+    pub fx: Option<FxState>,
+    pub valuations: Option<ValuationState>,
+    pub access: Option<AccessState>,
+    pub kyc: Option<KycState>,
+    pub kyc_applications: Option<KycReviewState>,
+}
+
+impl StableStateV6 {
+    // The current state and the lots still to mint into the ledgers
+    pub fn into_parts(self) -> (StableState, TokenStateV5) {
+        let state = StableState {
+            users: self.users,
+            assets: self.assets,
+            trades: self.trades,
+            portfolios: self.portfolios,
+            notifications: self.notifications,
+            ledgers: self.ledgers,
+            orders: self.orders,
+            cash: self.cash,
+            payments: self.payments,
+            funding: self.funding,
+            amendments: self.amendments,
+            distributions: self.distributions,
+            fx: self.fx,
+            valuations: self.valuations,
+            access: self.access,
+            kyc: self.kyc,
+            kyc_applications: self.kyc_applications,
+        };
+        (state, self.tokens.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS: u64 = 1_000_000_000;
+
+    fn lot(id: u64, asset_id: u64, amount: u64) -> TokenLot {
+        TokenLot { id, asset_id, owner_id: Principal::anonymous(), amount, price: 100, status: TokenStatus::Available }
+    }
+
+    #[test]
+    fn legacy_numbers_are_read_by_magnitude() {
+        assert_eq!(parse_legacy_time("1700000000"), Some(1_700_000_000 * NANOS));
+        assert_eq!(parse_legacy_time("1700000000123"), Some(1_700_000_000_123_000_000));
+        assert_eq!(parse_legacy_time("1700000000123456"), Some(1_700_000_000_123_456_000));
+        assert_eq!(parse_legacy_time(" 1700000000123456789 "), Some(1_700_000_000_123_456_789));
+    }
+
+    #[test]
+    fn legacy_dates_take_time_of_day_and_offset() {
+        assert_eq!(parse_legacy_time("1970-01-01"), Some(0));
+        assert_eq!(parse_legacy_time("2024-03-01"), Some(1_709_251_200 * NANOS));
+        assert_eq!(parse_legacy_time("2024-03-01T10:30"), Some((1_709_251_200 + 37_800) * NANOS));
+        assert_eq!(parse_legacy_time("2024-03-01 10:30:15"), Some((1_709_251_200 + 37_815) * NANOS));
+        // 10:30 at +05:30 is 05:00 UTC
+        assert_eq!(parse_legacy_time("2024-03-01T10:30:00+05:30"), Some((1_709_251_200 + 18_000) * NANOS));
+        assert_eq!(parse_legacy_time("2024-03-01T10:30:00-02"), Some((1_709_251_200 + 45_000) * NANOS));
+    }
+
+    #[test]
+    fn unreadable_legacy_times_are_refused() {
+        for value in ["", "soon", "2024-13-01", "2024-02-32", "2024-03-01T25:00", "2024-03-01junk", "1969-12-31"] {
+            assert_eq!(parse_legacy_time(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn civil_days_cross_leap_years() {
+        assert_eq!(days_from_civil("1970-01-01"), Some(0));
+        assert_eq!(days_from_civil("2000-02-29"), Some(11_016));
+        assert_eq!(days_from_civil("2000-03-01"), Some(11_017));
+        assert_eq!(days_from_civil("1969-12-31"), Some(-1));
+    }
+
+    #[test]
+    fn percentages_become_basis_points() {
+        assert_eq!(basis_points(7.25), BasisPoints(725));
+        assert_eq!(basis_points(0.004), BasisPoints(0));
+        assert_eq!(basis_points(-3.0), BasisPoints(0));
+    }
+
+    #[test]
+    fn only_lots_no_mint_accounts_for_are_minted() {
+        let state = TokenStateV5 {
+            tokens: [lot(3, 1, 5), lot(1, 1, 10), lot(2, 2, 0), lot(4, 2, 7)].into_iter().map(|l| (l.id, l)).collect(),
+            next_id: 5,
+        };
+        let minted = HashSet::from([(1, lot_memo(3))]);
+        let lots = unminted_lots(state, |l| minted.contains(&(l.asset_id, lot_memo(l.id))));
+        // Lot 3 is already on the ledger and lot 2 is empty
+        assert_eq!(lots.iter().map(|l| l.id).collect::<Vec<_>>(), vec![1, 4]);
+    }
+
+    #[test]
+    fn assets_take_the_currency_of_their_funding_round() {
+        let asset = AssetV2 {
+            id: 1,
+            owner_id: Principal::anonymous(),
+            name: "Depot".to_string(),
+            description: String::new(),
+            category: String::new(),
+            location: String::new(),
+            images: vec![],
+            documents: vec![],
+            total_value: 1_000_000,
+            token_price: 1_000,
+            total_tokens: 1_000,
+            available_tokens: 600,
+            apy: 6.5,
+            status: AssetStatus::Approved,
+            launch_date: None,
+            funding_deadline: None,
+            monthly_income: Some(5_000),
+            risk_rating: None,
+            key_metrics: None,
+        };
+        let asset = asset_v3(asset, Currency::INR);
+        assert_eq!(asset.token_price, Money::new(1_000, Currency::INR));
+        assert_eq!(asset.monthly_income, Some(Money::new(5_000, Currency::INR)));
+        assert_eq!(asset.apy, BasisPoints(650));
+    }
+}
@@ -0,0 +1,157 @@
+// Fixed-point amounts and rates. A Money amount counts the currency's minor
+// unit (cents, paise, e8s), so its scale is fixed by the currency; rates are
+// whole basis points. Arithmetic is checked: it fails instead of wrapping,
+// saturating or mixing currencies.
+
+use std::fmt;
+use crate::error::{RwaError, RwaResult};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Currency {
+    ICP,
+    USD,
+    INR,
+}
+
+impl Currency {
+    // Digits after the decimal point; matches the ICP ledger's e8s
+    pub fn decimals(self) -> u32 {
+        match self {
+            Currency::ICP => 8,
+            Currency::USD | Currency::INR => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq, Eq)]
+pub struct Money {
+    // In minor units of `currency`
+    pub amount: u64,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: u64, currency: Currency) -> Self {
+        Money { amount, currency }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.amount == 0
+    }
+
+    pub fn expect_currency(&self, currency: Currency) -> RwaResult<()> {
+        if self.currency != currency {
+            return Err(RwaError::CurrencyMismatch { expected: currency, found: self.currency });
+        }
+        Ok(())
+    }
+
+    pub fn checked_add(self, other: Money) -> RwaResult<Money> {
+        other.expect_currency(self.currency)?;
+        self.amount.checked_add(other.amount)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or_else(|| RwaError::Overflow(format!("{} + {}", self, other)))
+    }
+
+    pub fn checked_sub(self, other: Money) -> RwaResult<Money> {
+        other.expect_currency(self.currency)?;
+        self.amount.checked_sub(other.amount)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or_else(|| RwaError::Overflow(format!("{} - {}", self, other)))
+    }
+
+    // Price times a number of units
+    pub fn checked_mul(self, quantity: u64) -> RwaResult<Money> {
+        self.amount.checked_mul(quantity)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or_else(|| RwaError::Overflow(format!("{} x {}", self, quantity)))
+    }
+
+    // `part` of `whole` shares of this amount, rounded down
+    pub fn checked_pro_rata(self, part: u64, whole: u64) -> RwaResult<Money> {
+        (self.amount as u128 * part as u128).checked_div(whole as u128)
+            .and_then(|amount| u64::try_from(amount).ok())
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or_else(|| RwaError::Overflow(format!("{} x {} / {}", self, part, whole)))
+    }
+
+    // Signed difference in minor units, as for profit and loss
+    pub fn checked_delta(self, other: Money) -> RwaResult<i64> {
+        other.expect_currency(self.currency)?;
+        i64::try_from(self.amount as i128 - other.amount as i128)
+            .map_err(|_| RwaError::Overflow(format!("{} - {}", self, other)))
+    }
+}
+
+// 1234567 INR paise renders as "12,345.67 INR"
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = 10u64.pow(self.currency.decimals());
+        let whole = (self.amount / unit).to_string();
+        let mut grouped = String::with_capacity(whole.len() + whole.len() / 3);
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i).is_multiple_of(3) {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        let width = self.currency.decimals() as usize;
+        write!(f, "{}.{:0width$} {:?}", grouped, self.amount % unit, self.currency, width = width)
+    }
+}
+
+// A rate in hundredths of a percent: 725 is 7.25%
+#[derive(Clone, Copy, Debug, Default, candid::CandidType, candid::Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BasisPoints(pub u32);
+
+impl BasisPoints {
+    pub const HUNDRED_PERCENT: BasisPoints = BasisPoints(10_000);
+}
+
+impl fmt::Display for BasisPoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}%", self.0 / 100, self.0 % 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_refuses_overflow_and_mixed_currencies() {
+        let max = Money::new(u64::MAX, Currency::USD);
+        assert!(matches!(max.checked_add(Money::new(1, Currency::USD)), Err(RwaError::Overflow(_))));
+        assert!(matches!(Money::new(1, Currency::USD).checked_sub(Money::new(2, Currency::USD)), Err(RwaError::Overflow(_))));
+        assert!(matches!(max.checked_mul(2), Err(RwaError::Overflow(_))));
+        assert!(matches!(
+            Money::new(1, Currency::USD).checked_add(Money::new(1, Currency::INR)),
+            Err(RwaError::CurrencyMismatch { expected: Currency::USD, found: Currency::INR })
+        ));
+    }
+
+    #[test]
+    fn pro_rata_rounds_down_and_refuses_an_empty_whole() {
+        let cost = Money::new(1_000, Currency::INR);
+        assert_eq!(cost.checked_pro_rata(1, 3).unwrap(), Money::new(333, Currency::INR));
+        assert_eq!(Money::new(u64::MAX, Currency::INR).checked_pro_rata(3, 3).unwrap().amount, u64::MAX);
+        assert!(matches!(Money::new(u64::MAX, Currency::INR).checked_pro_rata(4, 3), Err(RwaError::Overflow(_))));
+        assert!(matches!(cost.checked_pro_rata(1, 0), Err(RwaError::Overflow(_))));
+    }
+
+    #[test]
+    fn deltas_are_signed_and_bounded() {
+        let (low, high) = (Money::new(250, Currency::USD), Money::new(1_000, Currency::USD));
+        assert_eq!(high.checked_delta(low).unwrap(), 750);
+        assert_eq!(low.checked_delta(high).unwrap(), -750);
+        assert!(matches!(Money::new(u64::MAX, Currency::USD).checked_delta(Money::new(0, Currency::USD)), Err(RwaError::Overflow(_))));
+    }
+
+    #[test]
+    fn amounts_display_in_major_units() {
+        assert_eq!(Money::new(123_456, Currency::INR).to_string(), "1,234.56 INR");
+        assert_eq!(Money::new(5, Currency::ICP).to_string(), "0.00000005 ICP");
+        assert_eq!(BasisPoints(725).to_string(), "7.25%");
+    }
+}
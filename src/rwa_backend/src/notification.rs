@@ -1,7 +1,10 @@
 use candid::Principal;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use crate::user::is_admin;
+use ic_stable_structures::StableBTreeMap;
+use crate::access::{Permission, has_permission, require_permission};
+use crate::error::{RwaError, RwaResult};
+use crate::memory::{self, Memory};
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
 pub struct Notification {
@@ -10,7 +13,7 @@ pub struct Notification {
     pub notification_type: NotificationType,
     pub message: String,
     pub read: bool,
-    pub created_at: String,
+    pub created_at: u64,
 }
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
@@ -23,12 +26,38 @@ pub enum NotificationType {
 }
 
 thread_local! {
-    static NOTIFICATIONS: RefCell<HashMap<u64, Notification>> = RefCell::new(HashMap::new());
-    static NOTIFICATION_ID_COUNTER: RefCell<u64> = RefCell::new(1);
+    // In stable memory
+    static NOTIFICATIONS: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(memory::init_map(memory::NOTIFICATIONS));
+    static NOTIFICATION_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
 }
 
-#[ic_cdk::update]
-pub fn create_notification(user_id: Principal, notification_type: NotificationType, message: String, created_at: String) -> Notification {
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct NotificationState {
+    // Only filled in snapshots before schema v6, which kept notifications on the heap
+    pub notifications: HashMap<u64, Notification>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> NotificationState {
+    NotificationState {
+        notifications: HashMap::new(),
+        next_id: NOTIFICATION_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: NotificationState) {
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        for (id, notification) in state.notifications {
+            notifications.insert(id, notification);
+        }
+    });
+    NOTIFICATION_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id);
+}
+
+// Internal helper used by other modules to notify a user
+pub fn create_notification(user_id: Principal, notification_type: NotificationType, message: String) -> Notification {
     let id = NOTIFICATION_ID_COUNTER.with(|counter| {
         let mut c = counter.borrow_mut();
         let id = *c;
@@ -41,42 +70,56 @@ pub fn create_notification(user_id: Principal, notification_type: NotificationTy
         notification_type,
         message,
         read: false,
-        created_at,
+        created_at: ic_cdk::api::time(),
     };
     NOTIFICATIONS.with(|notifications| notifications.borrow_mut().insert(id, notification.clone()));
     notification
 }
 
-#[ic_cdk::query]
-pub fn get_notification(id: u64) -> Option<Notification> {
-    NOTIFICATIONS.with(|notifications| notifications.borrow().get(&id).cloned())
+// Admin: Send a notification to a user
+#[ic_cdk::update(name = "create_notification")]
+pub fn send_notification(user_id: Principal, notification_type: NotificationType, message: String) -> RwaResult<Notification> {
+    require_permission(&ic_cdk::caller(), Permission::SendNotifications)?;
+    Ok(create_notification(user_id, notification_type, message))
 }
 
 #[ic_cdk::query]
-pub fn list_notifications_by_user(user_id: Principal) -> Vec<Notification> {
-    NOTIFICATIONS.with(|notifications| notifications.borrow().values().filter(|n| n.user_id == user_id).cloned().collect())
+pub fn get_notification(id: u64) -> RwaResult<Notification> {
+    let caller = ic_cdk::caller();
+    let notification = NOTIFICATIONS.with(|notifications| notifications.borrow().get(&id))
+        .ok_or_else(|| RwaError::not_found("Notification", id))?;
+    if notification.user_id != caller && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(notification)
 }
 
 #[ic_cdk::query]
-pub fn list_all_notifications() -> Vec<Notification> {
+pub fn list_notifications_by_user(user_id: Principal) -> RwaResult<Vec<Notification>> {
     let caller = ic_cdk::caller();
-    if !is_admin(&caller) {
-        return vec![];
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
     }
-    NOTIFICATIONS.with(|notifications| notifications.borrow().values().cloned().collect())
+    Ok(NOTIFICATIONS.with(|notifications| notifications.borrow().iter().map(|(_, n)| n).filter(|n| n.user_id == user_id).collect()))
+}
+
+#[ic_cdk::query]
+pub fn list_all_notifications() -> RwaResult<Vec<Notification>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAccounts)?;
+    Ok(NOTIFICATIONS.with(|notifications| notifications.borrow().iter().map(|(_, n)| n).collect()))
 }
 
 #[ic_cdk::update]
-pub fn mark_notification_read(id: u64) -> Option<Notification> {
+pub fn mark_notification_read(id: u64) -> RwaResult<Notification> {
     let caller = ic_cdk::caller();
     NOTIFICATIONS.with(|notifications| {
         let mut notifications = notifications.borrow_mut();
-        if let Some(notification) = notifications.get_mut(&id) {
-            if notification.user_id == caller || is_admin(&caller) {
-                notification.read = true;
-                return Some(notification.clone());
-            }
+        let mut notification = notifications.get(&id).ok_or_else(|| RwaError::not_found("Notification", id))?;
+        if notification.user_id != caller && !has_permission(&caller, Permission::ManageAccounts) {
+            return Err(RwaError::Unauthorized);
         }
-        None
+        notification.read = true;
+        notifications.insert(id, notification.clone());
+        Ok(notification)
     })
-} 
\ No newline at end of file
+}
@@ -0,0 +1,535 @@
+// Secondary market: limit and market orders per (asset, currency), matched with
+// price-time priority and settled against the asset ledger and cash balances.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use crate::access::{Permission, has_permission};
+use crate::asset::{get_asset, Asset, AssetStatus};
+use crate::cash;
+use crate::compliance;
+use crate::error::{RwaError, RwaResult};
+use crate::fx;
+use crate::icrc::{self, Account, ESCROW_ORDER};
+use crate::money::{Currency, Money};
+use crate::portfolio;
+use crate::trade::{record_settled_trade, Trade};
+use crate::user::require_kyc;
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum OrderType {
+    Limit,
+    Market,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum OrderStatus {
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct Order {
+    pub id: u64,
+    pub asset_id: u64,
+    pub owner_id: Principal,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    // Limit price per token; None for market orders
+    pub price: Option<Money>,
+    pub quantity: u64,
+    pub filled: u64,
+    pub currency: Currency,
+    pub status: OrderStatus,
+    pub created_at: u64,
+}
+
+impl Order {
+    pub fn remaining(&self) -> u64 {
+        self.quantity - self.filled
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.status, OrderStatus::Open | OrderStatus::PartiallyFilled)
+    }
+
+    // Limit price in minor units of the order's currency; 0 for market orders
+    fn limit(&self) -> u64 {
+        self.price.map_or(0, |p| p.amount)
+    }
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PlaceOrderResponse {
+    pub order: Order,
+    pub trades: Vec<Trade>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PriceLevel {
+    pub price: Money,
+    pub quantity: u64,
+    pub order_count: u64,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct OrderBookView {
+    pub asset_id: u64,
+    pub currency: Currency,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+// Resting limit orders of one market. Iterating either set yields orders in
+// matching priority: best price first, then earliest order id.
+#[derive(Default)]
+struct OrderBook {
+    // (price, order_id)
+    asks: BTreeSet<(u64, u64)>,
+    // (u64::MAX - price, order_id)
+    bids: BTreeSet<(u64, u64)>,
+}
+
+impl OrderBook {
+    fn insert(&mut self, order: &Order) {
+        let price = order.limit();
+        match order.side {
+            OrderSide::Sell => self.asks.insert((price, order.id)),
+            OrderSide::Buy => self.bids.insert((u64::MAX - price, order.id)),
+        };
+    }
+
+    fn remove(&mut self, order: &Order) {
+        let price = order.limit();
+        match order.side {
+            OrderSide::Sell => self.asks.remove(&(price, order.id)),
+            OrderSide::Buy => self.bids.remove(&(u64::MAX - price, order.id)),
+        };
+    }
+}
+
+type MarketKey = (u64, Currency);
+
+thread_local! {
+    static ORDERS: RefCell<HashMap<u64, Order>> = RefCell::new(HashMap::new());
+    static ORDER_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+    // Index over ORDERS, rebuilt on restore rather than persisted
+    static BOOKS: RefCell<HashMap<MarketKey, OrderBook>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct OrderState {
+    pub orders: HashMap<u64, Order>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> OrderState {
+    BOOKS.with(|books| books.borrow_mut().clear());
+    OrderState {
+        orders: ORDERS.with(|orders| std::mem::take(&mut *orders.borrow_mut())),
+        next_id: ORDER_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: OrderState) {
+    BOOKS.with(|books| {
+        let mut books = books.borrow_mut();
+        books.clear();
+        for order in state.orders.values().filter(|o| o.is_open() && o.order_type == OrderType::Limit) {
+            books.entry((order.asset_id, order.currency)).or_default().insert(order);
+        }
+    });
+    ORDERS.with(|orders| *orders.borrow_mut() = state.orders);
+    ORDER_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id.max(1));
+}
+
+// Internal: An order by id, for callers that check access themselves
+pub fn get_order_internal(id: u64) -> RwaResult<Order> {
+    ORDERS.with(|orders| orders.borrow().get(&id).cloned()).ok_or_else(|| RwaError::not_found("Order", id))
+}
+
+fn save_order(order: &Order) {
+    ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+}
+
+// Best resting order on the opposite side that `taker` may trade against,
+// other than those in `passed_over`
+fn best_counter_order(taker: &Order, passed_over: &[u64]) -> Option<Order> {
+    BOOKS.with(|books| {
+        let books = books.borrow();
+        let book = books.get(&(taker.asset_id, taker.currency))?;
+        let side = match taker.side {
+            OrderSide::Buy => &book.asks,
+            OrderSide::Sell => &book.bids,
+        };
+        ORDERS.with(|orders| {
+            let orders = orders.borrow();
+            side.iter()
+                .filter_map(|(_, id)| orders.get(id))
+                // No self-trading: skip the taker's own resting orders
+                .find(|o| o.owner_id != taker.owner_id && !passed_over.contains(&o.id))
+                .cloned()
+        })
+    })
+}
+
+fn crosses(taker: &Order, maker: &Order) -> bool {
+    let (Some(limit), Some(maker_price)) = (taker.price, maker.price) else {
+        return taker.order_type == OrderType::Market;
+    };
+    match taker.side {
+        OrderSide::Buy => maker_price.amount <= limit.amount,
+        OrderSide::Sell => maker_price.amount >= limit.amount,
+    }
+}
+
+fn update_fill(order: &mut Order, quantity: u64) {
+    order.filled += quantity;
+    order.status = if order.remaining() == 0 { OrderStatus::Filled } else { OrderStatus::PartiallyFilled };
+}
+
+// Moves both legs of a fill between `buy` and `sell` at `price`. All checks
+// happen before placement, so a failure here is a broken invariant.
+fn settle_fill(buy: &mut Order, sell: &mut Order, quantity: u64, price: Money) -> RwaResult<Trade> {
+    let paid = price.checked_mul(quantity)?;
+    let amount = paid.amount;
+    // Asset leg: the sell order's units are always held in its escrow account
+    icrc::move_balance(sell.asset_id, &icrc::escrow_account(ESCROW_ORDER, sell.id), &Account::of(buy.owner_id), quantity, None)?;
+    // Payment leg: limit buys pay from their reservation and get back any price improvement
+    match buy.price {
+        Some(limit) => {
+            cash::debit_reserved(buy.owner_id, buy.currency, amount, format!("Order #{} fill", buy.id))?;
+            let improvement = limit.checked_mul(quantity)?.checked_sub(paid)?;
+            if !improvement.is_zero() {
+                cash::release(buy.owner_id, buy.currency, improvement.amount, format!("Order #{} price improvement", buy.id))?;
+            }
+        }
+        None => cash::debit(buy.owner_id, buy.currency, amount, format!("Order #{} fill", buy.id))?,
+    }
+    cash::credit(sell.owner_id, sell.currency, amount, format!("Order #{} fill", sell.id))?;
+    portfolio::record_sale(sell.owner_id, sell.asset_id, quantity, price)?;
+    portfolio::record_purchase(buy.owner_id, buy.asset_id, quantity, price)?;
+    update_fill(buy, quantity);
+    update_fill(sell, quantity);
+    let trade = record_settled_trade(buy.owner_id, sell.owner_id, buy.asset_id, quantity, price, buy.id, sell.id);
+    let message = format!("Trade #{}: {} tokens of asset #{} at {}", trade.id, quantity, trade.asset_id, price);
+    create_notification(buy.owner_id, NotificationType::Trade, format!("Bought {}", message));
+    create_notification(sell.owner_id, NotificationType::Trade, format!("Sold {}", message));
+    Ok(trade)
+}
+
+// Matches `taker` against the book until it is filled or no longer crosses
+fn match_order(taker: &mut Order, asset: &Asset) -> RwaResult<Vec<Trade>> {
+    let mut trades = vec![];
+    let mut passed_over = vec![];
+    while taker.remaining() > 0 {
+        let Some(mut maker) = best_counter_order(taker, &passed_over) else { break };
+        if !crosses(taker, &maker) {
+            break;
+        }
+        // Resting orders are always limit orders
+        let Some(price) = maker.price else { break };
+        let mut quantity = taker.remaining().min(maker.remaining());
+        // Market buys carry no reservation, so fill only what available cash covers
+        if taker.side == OrderSide::Buy && taker.order_type == OrderType::Market {
+            let available = cash::balance_of(taker.owner_id, taker.currency).available;
+            quantity = quantity.min(available / price.amount.max(1));
+            if quantity == 0 {
+                break;
+            }
+        }
+        // Makers the asset's transfer rules keep from this fill are skipped
+        let (buyer, seller) = match taker.side {
+            OrderSide::Buy => (taker.owner_id, maker.owner_id),
+            OrderSide::Sell => (maker.owner_id, taker.owner_id),
+        };
+        if compliance::check_transfer(asset, Some(seller), buyer, quantity).is_err() {
+            passed_over.push(maker.id);
+            continue;
+        }
+        let trade = match taker.side {
+            OrderSide::Buy => settle_fill(taker, &mut maker, quantity, price)?,
+            OrderSide::Sell => settle_fill(&mut maker, taker, quantity, price)?,
+        };
+        if maker.remaining() == 0 {
+            BOOKS.with(|books| {
+                if let Some(book) = books.borrow_mut().get_mut(&(maker.asset_id, maker.currency)) {
+                    book.remove(&maker);
+                }
+            });
+        }
+        save_order(&maker);
+        trades.push(trade);
+    }
+    Ok(trades)
+}
+
+// Hands back whatever an order still holds: escrowed units for sells,
+// reserved cash for limit buys
+fn release_remaining(order: &Order) -> RwaResult<()> {
+    let remaining = order.remaining();
+    if remaining == 0 {
+        return Ok(());
+    }
+    match (order.side, order.price) {
+        (OrderSide::Sell, _) => {
+            icrc::move_balance(order.asset_id, &icrc::escrow_account(ESCROW_ORDER, order.id), &Account::of(order.owner_id), remaining, None)?;
+        }
+        (OrderSide::Buy, Some(price)) => cash::release(order.owner_id, order.currency, price.checked_mul(remaining)?.amount, format!("Order #{} released", order.id))?,
+        (OrderSide::Buy, None) => {}
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+pub fn place_order(
+    asset_id: u64,
+    side: OrderSide,
+    order_type: OrderType,
+    price: Option<Money>,
+    quantity: u64,
+    currency: Currency,
+) -> RwaResult<PlaceOrderResponse> {
+    let caller = ic_cdk::caller();
+    require_kyc(&caller)?;
+    if quantity == 0 {
+        return Err(RwaError::validation("quantity", "must be greater than zero"));
+    }
+    match (order_type, price) {
+        (OrderType::Limit, None) => {
+            return Err(RwaError::validation("price", "limit orders need a price greater than zero"));
+        }
+        (OrderType::Limit, Some(price)) => {
+            if price.is_zero() {
+                return Err(RwaError::validation("price", "limit orders need a price greater than zero"));
+            }
+            price.expect_currency(currency)?;
+        }
+        (OrderType::Market, Some(_)) => {
+            return Err(RwaError::validation("price", "market orders take no price"));
+        }
+        _ => {}
+    }
+    let asset = get_asset(asset_id)?;
+    asset.check_tradable()?;
+    // The full transfer rules are checked against each counterparty when matching
+    match side {
+        OrderSide::Buy => compliance::check_receiver(&asset, caller, quantity)?,
+        OrderSide::Sell => compliance::check_sender(&asset, caller, quantity)?,
+    }
+    // A freshness gate only: fills settle in the order's currency, and just the
+    // portfolio restates them in the asset's, at the last rate. Every order in
+    // this book shares the pair, so a rate is there for each fill.
+    fx::require_fresh_rate(currency, asset.currency())?;
+    let id = ORDER_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    // Lock what the order may need before it can touch the book
+    match (side, price) {
+        (OrderSide::Sell, _) => {
+            icrc::move_balance(asset_id, &Account::of(caller), &icrc::escrow_account(ESCROW_ORDER, id), quantity, None)?;
+        }
+        (OrderSide::Buy, Some(price)) => cash::reserve(caller, currency, price.checked_mul(quantity)?.amount, format!("Order #{}", id))?,
+        (OrderSide::Buy, None) => {}
+    }
+    let mut order = Order {
+        id,
+        asset_id,
+        owner_id: caller,
+        side,
+        order_type,
+        price,
+        quantity,
+        filled: 0,
+        currency,
+        status: OrderStatus::Open,
+        created_at: ic_cdk::api::time(),
+    };
+    // Everything was validated and locked above; trap to roll back on failure
+    let trades = match_order(&mut order, &asset)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to settle order #{}: {}", id, e)));
+    if order.remaining() > 0 {
+        match order.order_type {
+            OrderType::Limit => BOOKS.with(|books| books.borrow_mut().entry((asset_id, currency)).or_default().insert(&order)),
+            // Market orders never rest: the unfilled remainder is cancelled
+            OrderType::Market => {
+                release_remaining(&order)
+                    .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to release order #{}: {}", id, e)));
+                order.status = OrderStatus::Cancelled;
+            }
+        }
+    }
+    save_order(&order);
+    Ok(PlaceOrderResponse { order, trades })
+}
+
+#[ic_cdk::update]
+pub fn cancel_order(id: u64) -> RwaResult<Order> {
+    let caller = ic_cdk::caller();
+    let mut order = get_order_internal(id)?;
+    if order.owner_id != caller && !has_permission(&caller, Permission::ManageAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    if !order.is_open() {
+        return Err(RwaError::InvalidState(format!("Order #{} is already {:?}", id, order.status)));
+    }
+    release_remaining(&order)?;
+    BOOKS.with(|books| {
+        if let Some(book) = books.borrow_mut().get_mut(&(order.asset_id, order.currency)) {
+            book.remove(&order);
+        }
+    });
+    order.status = OrderStatus::Cancelled;
+    save_order(&order);
+    if order.owner_id != caller {
+        create_notification(order.owner_id, NotificationType::Trade, format!("Your order #{} was cancelled by support", id));
+    }
+    Ok(order)
+}
+
+// Internal: Cancel every open order in an asset that is leaving the market,
+// handing back escrowed units and reserved cash
+pub fn cancel_asset_orders(asset_id: u64, status: &AssetStatus) {
+    let open: Vec<Order> = ORDERS.with(|orders| {
+        orders.borrow().values().filter(|o| o.asset_id == asset_id && o.is_open()).cloned().collect()
+    });
+    for mut order in open {
+        release_remaining(&order).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to release order #{}: {}", order.id, e)));
+        BOOKS.with(|books| {
+            if let Some(book) = books.borrow_mut().get_mut(&(order.asset_id, order.currency)) {
+                book.remove(&order);
+            }
+        });
+        order.status = OrderStatus::Cancelled;
+        save_order(&order);
+        create_notification(order.owner_id, NotificationType::Trade, format!("Your order #{} was cancelled: asset #{} is now {:?}", order.id, asset_id, status));
+    }
+}
+
+// Internal: A user's orders that can still fill
+pub fn open_orders_of(user_id: Principal) -> Vec<Order> {
+    ORDERS.with(|orders| orders.borrow().values().filter(|o| o.owner_id == user_id && o.is_open()).cloned().collect())
+}
+
+#[ic_cdk::query]
+pub fn get_order(id: u64) -> RwaResult<Order> {
+    let caller = ic_cdk::caller();
+    let order = get_order_internal(id)?;
+    if order.owner_id != caller && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(order)
+}
+
+#[ic_cdk::query]
+pub fn list_orders_by_user(user_id: Principal) -> RwaResult<Vec<Order>> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(ORDERS.with(|orders| orders.borrow().values().filter(|o| o.owner_id == user_id).cloned().collect()))
+}
+
+// Aggregated depth of a market, best prices first
+#[ic_cdk::query]
+pub fn get_order_book(asset_id: u64, currency: Currency) -> OrderBookView {
+    fn levels<'a>(entries: impl Iterator<Item = &'a (u64, u64)>, orders: &HashMap<u64, Order>) -> Vec<PriceLevel> {
+        let mut levels: Vec<PriceLevel> = vec![];
+        for order in entries.filter_map(|(_, id)| orders.get(id)) {
+            let Some(price) = order.price else { continue };
+            match levels.last_mut() {
+                Some(level) if level.price == price => {
+                    level.quantity += order.remaining();
+                    level.order_count += 1;
+                }
+                _ => levels.push(PriceLevel { price, quantity: order.remaining(), order_count: 1 }),
+            }
+        }
+        levels
+    }
+    BOOKS.with(|books| {
+        let books = books.borrow();
+        ORDERS.with(|orders| {
+            let orders = orders.borrow();
+            let (bids, asks) = match books.get(&(asset_id, currency)) {
+                Some(book) => (levels(book.bids.iter(), &orders), levels(book.asks.iter(), &orders)),
+                None => (vec![], vec![]),
+            };
+            OrderBookView { asset_id, currency, bids, asks }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: u64, owner: u8, side: OrderSide, price: Option<u64>) -> Order {
+        Order {
+            id,
+            asset_id: 1,
+            owner_id: Principal::from_slice(&[owner]),
+            side,
+            order_type: if price.is_some() { OrderType::Limit } else { OrderType::Market },
+            price: price.map(|p| Money::new(p, Currency::USD)),
+            quantity: 10,
+            filled: 0,
+            currency: Currency::USD,
+            status: OrderStatus::Open,
+            created_at: id,
+        }
+    }
+
+    fn restore(orders: Vec<Order>) {
+        restore_state(OrderState { orders: orders.into_iter().map(|o| (o.id, o)).collect(), next_id: 100 });
+    }
+
+    #[test]
+    fn best_price_then_earliest_order_matches_first() {
+        let mut filled = order(5, 3, OrderSide::Sell, Some(95));
+        filled.status = OrderStatus::Filled;
+        restore(vec![
+            order(1, 2, OrderSide::Sell, Some(105)),
+            order(2, 3, OrderSide::Sell, Some(100)),
+            order(3, 2, OrderSide::Sell, Some(100)),
+            order(4, 2, OrderSide::Buy, Some(98)),
+            filled,
+            order(6, 3, OrderSide::Buy, Some(99)),
+        ]);
+        let buy = order(10, 1, OrderSide::Buy, Some(110));
+        assert_eq!(best_counter_order(&buy, &[]).map(|o| o.id), Some(2));
+        assert_eq!(best_counter_order(&buy, &[2]).map(|o| o.id), Some(3));
+        assert_eq!(best_counter_order(&buy, &[2, 3, 1]).map(|o| o.id), None);
+        let sell = order(11, 1, OrderSide::Sell, None);
+        assert_eq!(best_counter_order(&sell, &[]).map(|o| o.id), Some(6));
+    }
+
+    #[test]
+    fn takers_never_match_their_own_orders() {
+        restore(vec![order(1, 1, OrderSide::Sell, Some(90)), order(2, 2, OrderSide::Sell, Some(100))]);
+        assert_eq!(best_counter_order(&order(10, 1, OrderSide::Buy, Some(110)), &[]).map(|o| o.id), Some(2));
+    }
+
+    #[test]
+    fn limits_cross_at_or_through_the_resting_price() {
+        let ask = order(1, 2, OrderSide::Sell, Some(100));
+        let bid = order(2, 2, OrderSide::Buy, Some(100));
+        assert!(crosses(&order(10, 1, OrderSide::Buy, Some(100)), &ask));
+        assert!(!crosses(&order(10, 1, OrderSide::Buy, Some(99)), &ask));
+        assert!(crosses(&order(10, 1, OrderSide::Sell, Some(100)), &bid));
+        assert!(!crosses(&order(10, 1, OrderSide::Sell, Some(101)), &bid));
+        assert!(crosses(&order(10, 1, OrderSide::Buy, None), &ask));
+    }
+}
@@ -0,0 +1,482 @@
+// Payment leg on external ICRC-1/ICRC-2 ledgers (ICP, ckUSDC-style stablecoins).
+// Funds are pulled with icrc2_transfer_from and paid out with icrc1_transfer.
+
+use candid::{Nat, Principal};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::access::{Permission, require_permission};
+use crate::cash;
+use crate::error::{RwaError, RwaResult};
+use crate::icrc::{Account, Subaccount, TransferArg, TransferError, TransferFromArgs, TransferFromError};
+use crate::money::Currency;
+use crate::scheduler::{self, Job};
+use crate::notification::{create_notification, NotificationType};
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub struct PaymentLedger {
+    pub currency: Currency,
+    pub ledger_id: Principal,
+    // Transfer fee charged by the ledger, in its smallest unit
+    pub fee: u64,
+}
+
+// A ledger payout that has not gone through. Escrow payouts the ledger refused
+// are retried from their subaccount. Payouts whose outcome is unknown are sent
+// again unchanged, so the ledger deduplicates them if the first one landed.
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PendingPayout {
+    pub id: u64,
+    pub ledger: PaymentLedger,
+    // None for the default subaccount, which backs cash balances
+    pub from_subaccount: Option<Subaccount>,
+    pub user_id: Principal,
+    pub amount: u64,
+    pub reason: String,
+    pub attempts: u32,
+    pub last_error: String,
+    pub next_attempt_at: u64,
+    // Destination, when not the user's own account
+    pub to: Option<Account>,
+    // Creation time of a transfer whose outcome is unknown, reused when resending it
+    pub created_at_time: Option<u64>,
+}
+
+impl PendingPayout {
+    fn recipient(&self) -> Account {
+        self.to.unwrap_or(Account::of(self.user_id))
+    }
+
+    // Payouts from the default subaccount bear the ledger fee
+    fn sent(&self) -> u64 {
+        if self.from_subaccount.is_none() { self.amount.saturating_sub(self.ledger.fee) } else { self.amount }
+    }
+}
+
+// Why a payout transfer did not go through
+#[derive(Clone, Debug, PartialEq)]
+pub enum PayoutError {
+    // The ledger refused the transfer, so nothing moved
+    Rejected(TransferError),
+    // The call failed without a reply; the transfer may or may not have executed
+    Unknown(String),
+}
+
+impl fmt::Display for PayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayoutError::Rejected(e) => write!(f, "ledger refused the transfer: {:?}", e),
+            PayoutError::Unknown(e) => write!(f, "ledger did not reply: {}", e),
+        }
+    }
+}
+
+// What becomes of a payout whose transfer did not go through
+#[derive(Debug, PartialEq)]
+enum PayoutStep {
+    // Credit the amount to the user's cash balance
+    Refund,
+    // Try again later with a new transfer
+    Retry,
+    // Send the same transfer again to learn whether the first one landed
+    Reconcile,
+    // Nothing more can be learned from the ledger; kept for support
+    Lost,
+}
+
+// Internal: Next step for a payout that failed with `err`. Only a refusal of a
+// first transfer proves nothing moved; once an outcome is unknown, only the
+// ledger's deduplication of the same transfer can settle it.
+fn next_step(from_escrow: bool, outcome_unknown: bool, err: &PayoutError) -> PayoutStep {
+    match err {
+        PayoutError::Unknown(_) => PayoutStep::Reconcile,
+        PayoutError::Rejected(TransferError::TemporarilyUnavailable) if outcome_unknown => PayoutStep::Reconcile,
+        PayoutError::Rejected(_) if outcome_unknown => PayoutStep::Lost,
+        PayoutError::Rejected(_) if from_escrow => PayoutStep::Retry,
+        PayoutError::Rejected(_) => PayoutStep::Refund,
+    }
+}
+
+// Internal: When to retry after `attempts` failed attempts
+fn retry_delay(attempts: u32) -> u64 {
+    PAYOUT_RETRY_NANOS.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(MAX_PAYOUT_RETRY_NANOS)
+}
+
+// First retry delay, doubled after every failed attempt up to a day
+const PAYOUT_RETRY_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_PAYOUT_RETRY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Ledgers deduplicate transfers for a day; a resend has to reach them well within it
+const DEDUP_WINDOW_NANOS: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    static PAYMENT_LEDGERS: RefCell<HashMap<Currency, PaymentLedger>> = RefCell::new(HashMap::new());
+    static PENDING_PAYOUTS: RefCell<HashMap<u64, PendingPayout>> = RefCell::new(HashMap::new());
+    static PAYOUT_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+    // Payouts whose outcome could not be settled with the ledger
+    static LOST_PAYOUTS: RefCell<HashMap<u64, PendingPayout>> = RefCell::new(HashMap::new());
+    // Users with a withdrawal in flight
+    static WITHDRAWALS_IN_FLIGHT: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+}
+
+// Allows one withdrawal per user at a time, across the ledger call
+struct WithdrawalGuard(Principal);
+
+impl WithdrawalGuard {
+    fn acquire(user: Principal) -> RwaResult<Self> {
+        WITHDRAWALS_IN_FLIGHT.with(|in_flight| {
+            if in_flight.borrow_mut().insert(user) {
+                Ok(WithdrawalGuard(user))
+            } else {
+                Err(RwaError::InvalidState("A withdrawal is already in progress".to_string()))
+            }
+        })
+    }
+}
+
+impl Drop for WithdrawalGuard {
+    fn drop(&mut self) {
+        WITHDRAWALS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct PaymentState {
+    pub ledgers: HashMap<Currency, PaymentLedger>,
+    pub pending_payouts: Option<HashMap<u64, PendingPayout>>,
+    pub next_payout_id: Option<u64>,
+    pub lost_payouts: Option<HashMap<u64, PendingPayout>>,
+}
+
+pub fn take_state() -> PaymentState {
+    PaymentState {
+        ledgers: PAYMENT_LEDGERS.with(|ledgers| std::mem::take(&mut *ledgers.borrow_mut())),
+        pending_payouts: Some(PENDING_PAYOUTS.with(|pending| std::mem::take(&mut *pending.borrow_mut()))),
+        next_payout_id: Some(PAYOUT_ID_COUNTER.with(|counter| *counter.borrow())),
+        lost_payouts: Some(LOST_PAYOUTS.with(|lost| std::mem::take(&mut *lost.borrow_mut()))),
+    }
+}
+
+pub fn restore_state(state: PaymentState) {
+    PAYMENT_LEDGERS.with(|ledgers| *ledgers.borrow_mut() = state.ledgers);
+    PENDING_PAYOUTS.with(|pending| *pending.borrow_mut() = state.pending_payouts.unwrap_or_default());
+    PAYOUT_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_payout_id.unwrap_or(1).max(1));
+    LOST_PAYOUTS.with(|lost| *lost.borrow_mut() = state.lost_payouts.unwrap_or_default());
+}
+
+// Ledger that settles `currency`, if one is configured
+pub fn ledger_for(currency: Currency) -> Option<PaymentLedger> {
+    PAYMENT_LEDGERS.with(|ledgers| ledgers.borrow().get(&currency).cloned())
+}
+
+fn call_failed(method: &str, err: impl std::fmt::Debug) -> RwaError {
+    RwaError::InvalidState(format!("Ledger call {} failed: {:?}", method, err))
+}
+
+// Internal: Pull `amount` from `from` into one of the canister's subaccounts.
+// The payer must have approved the canister on the ledger beforehand.
+pub async fn collect(ledger: &PaymentLedger, from: Principal, to_subaccount: Option<Subaccount>, amount: u64) -> RwaResult<u64> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::of(from),
+        to: Account { owner: ic_cdk::id(), subaccount: to_subaccount },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger.ledger_id, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|e| call_failed("icrc2_transfer_from", e))?;
+    match result {
+        Ok(block) => Ok(u64::try_from(block.0).unwrap_or(u64::MAX)),
+        Err(TransferFromError::InsufficientFunds { balance }) => Err(RwaError::InsufficientBalance {
+            required: amount,
+            available: u64::try_from(balance.0).unwrap_or(u64::MAX),
+        }),
+        Err(TransferFromError::InsufficientAllowance { allowance }) => Err(RwaError::validation(
+            "allowance",
+            format!("approve the canister for at least {} on the ledger (current allowance {})", amount, allowance),
+        )),
+        Err(e) => Err(call_failed("icrc2_transfer_from", e)),
+    }
+}
+
+// Internal: Send `amount` from one of the canister's subaccounts to `to`. The
+// ledger fee is taken out of the subaccount on top of `amount`. The payout id
+// goes in the memo so a resend with the same `created_at_time` is deduplicated.
+pub async fn pay(ledger: &PaymentLedger, from_subaccount: Option<Subaccount>, to: Account, amount: u64, payout_id: u64, created_at_time: u64) -> Result<u64, PayoutError> {
+    let arg = TransferArg {
+        from_subaccount,
+        to,
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(ledger.fee)),
+        memo: Some(payout_id.to_be_bytes().to_vec()),
+        created_at_time: Some(created_at_time),
+    };
+    let result: Result<(Result<Nat, TransferError>,), _> = ic_cdk::call(ledger.ledger_id, "icrc1_transfer", (arg,)).await;
+    match result {
+        Ok((Ok(block),)) => Ok(u64::try_from(block.0).unwrap_or(u64::MAX)),
+        // An earlier send of this transfer landed
+        Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Ok(u64::try_from(duplicate_of.0).unwrap_or(u64::MAX)),
+        Ok((Err(e),)) => Err(PayoutError::Rejected(e)),
+        Err((code, message)) => Err(PayoutError::Unknown(format!("{:?}: {}", code, message))),
+    }
+}
+
+// Internal: Pay `amount` owed to a user out of a canister subaccount without
+// blocking the caller. From the default subaccount, which backs cash
+// balances, the user bears the ledger fee and `amount` less the fee is sent;
+// if the ledger refuses it the full amount is credited to their cash instead.
+// Escrow subaccounts were funded with the fee on top, so `amount` is sent in
+// full and a refused transfer is queued and retried from there. A transfer
+// with no reply is resent until the ledger confirms or refuses it.
+pub fn spawn_payout(ledger: PaymentLedger, from_subaccount: Option<Subaccount>, user: Principal, amount: u64, reason: String) {
+    send_payout(new_payout(ledger, from_subaccount, None, user, amount, reason));
+}
+
+fn new_payout(ledger: PaymentLedger, from_subaccount: Option<Subaccount>, to: Option<Account>, user_id: Principal, amount: u64, reason: String) -> PendingPayout {
+    let id = PAYOUT_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    PendingPayout {
+        id,
+        ledger,
+        from_subaccount,
+        user_id,
+        amount,
+        reason,
+        attempts: 0,
+        last_error: String::new(),
+        next_attempt_at: 0,
+        to,
+        created_at_time: None,
+    }
+}
+
+fn send_payout(mut payout: PendingPayout) {
+    ic_cdk::spawn(async move {
+        let created_at = payout.created_at_time.unwrap_or_else(ic_cdk::api::time);
+        let result = pay(&payout.ledger, payout.from_subaccount, payout.recipient(), payout.sent(), payout.id, created_at).await;
+        payout.attempts += 1;
+        match result {
+            Ok(block) => {
+                create_notification(payout.user_id, NotificationType::Trade, format!("{}: {} {:?} paid out in ledger block {}", payout.reason, payout.sent(), payout.ledger.currency, block));
+            }
+            Err(e) => payout_failed(payout, created_at, e),
+        }
+    });
+}
+
+// Internal: Refund, requeue or record a payout whose transfer created at
+// `created_at` did not go through
+fn payout_failed(mut payout: PendingPayout, created_at: u64, err: PayoutError) {
+    let now = ic_cdk::api::time();
+    let user = payout.user_id;
+    let first = payout.attempts == 1;
+    let mut step = next_step(payout.from_subaccount.is_some(), payout.created_at_time.is_some(), &err);
+    payout.last_error = err.to_string();
+    if step == PayoutStep::Reconcile && now >= created_at.saturating_add(DEDUP_WINDOW_NANOS) {
+        step = PayoutStep::Lost;
+    }
+    match step {
+        PayoutStep::Refund => {
+            let credited = cash::credit(user, payout.ledger.currency, payout.amount, format!("{} (ledger payout failed)", payout.reason));
+            match credited {
+                Ok(_) => {
+                    create_notification(user, NotificationType::Trade, format!("{}: ledger payout failed ({}); {} {:?} was credited to your cash balance", payout.reason, err, payout.amount, payout.ledger.currency));
+                }
+                Err(credit_err) => {
+                    payout.last_error = format!("{}; crediting cash failed: {}", err, credit_err);
+                    record_lost_payout(payout);
+                }
+            }
+        }
+        PayoutStep::Retry => {
+            payout.created_at_time = None;
+            queue_payout(payout.clone(), now + retry_delay(payout.attempts));
+            if first {
+                create_notification(user, NotificationType::Trade, format!("{}: ledger payout failed ({}); it will be retried", payout.reason, err));
+            }
+        }
+        PayoutStep::Reconcile => {
+            payout.created_at_time = Some(created_at);
+            let due = (now + retry_delay(payout.attempts)).min(created_at.saturating_add(DEDUP_WINDOW_NANOS));
+            queue_payout(payout.clone(), due);
+            if first {
+                create_notification(user, NotificationType::Trade, format!("{}: ledger payout unconfirmed ({}); it is being checked with the ledger", payout.reason, err));
+            }
+        }
+        PayoutStep::Lost => record_lost_payout(payout),
+    }
+}
+
+fn queue_payout(mut payout: PendingPayout, next_attempt_at: u64) {
+    payout.next_attempt_at = next_attempt_at;
+    scheduler::schedule(Job::RetryPayout(payout.id), next_attempt_at);
+    PENDING_PAYOUTS.with(|pending| pending.borrow_mut().insert(payout.id, payout));
+}
+
+fn record_lost_payout(payout: PendingPayout) {
+    create_notification(payout.user_id, NotificationType::Trade, format!("{}: ledger payout of {} {:?} could not be confirmed; support will reconcile it", payout.reason, payout.sent(), payout.ledger.currency));
+    LOST_PAYOUTS.with(|lost| lost.borrow_mut().insert(payout.id, payout));
+}
+
+// Internal: (id, next attempt) of every queued payout, for the scheduler
+pub fn pending_payout_times() -> Vec<(u64, u64)> {
+    PENDING_PAYOUTS.with(|pending| pending.borrow().values().map(|p| (p.id, p.next_attempt_at)).collect())
+}
+
+// Internal: Retry a queued payout that is due. It leaves the queue while in
+// flight and is queued again if it still does not go through.
+pub fn retry_payout(id: u64) -> RwaResult<()> {
+    let now = ic_cdk::api::time();
+    let payout = PENDING_PAYOUTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        match pending.get(&id) {
+            Some(p) if p.next_attempt_at <= now => Ok(pending.remove(&id)),
+            Some(_) => Err(RwaError::InvalidState(format!("Payout #{} is not due yet", id))),
+            None => Err(RwaError::not_found("Pending payout", id)),
+        }
+    })?;
+    if let Some(p) = payout {
+        send_payout(p);
+    }
+    Ok(())
+}
+
+// Admin: Route a currency's payments through an ICRC-1/ICRC-2 ledger canister
+#[ic_cdk::update]
+pub fn set_payment_ledger(currency: Currency, ledger_id: Principal, fee: u64) -> RwaResult<PaymentLedger> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    let ledger = PaymentLedger { currency, ledger_id, fee };
+    PAYMENT_LEDGERS.with(|ledgers| ledgers.borrow_mut().insert(currency, ledger.clone()));
+    Ok(ledger)
+}
+
+// Admin: Stop settling a currency on a ledger
+#[ic_cdk::update]
+pub fn remove_payment_ledger(currency: Currency) -> RwaResult<PaymentLedger> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    PAYMENT_LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&currency))
+        .ok_or_else(|| RwaError::not_found("Payment ledger for", format!("{:?}", currency)))
+}
+
+#[ic_cdk::query]
+pub fn list_payment_ledgers() -> Vec<PaymentLedger> {
+    PAYMENT_LEDGERS.with(|ledgers| ledgers.borrow().values().cloned().collect())
+}
+
+// Support: Payouts waiting for a retry or a check with the ledger, oldest first
+#[ic_cdk::query]
+pub fn list_pending_payouts() -> RwaResult<Vec<PendingPayout>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAccounts)?;
+    let mut pending: Vec<PendingPayout> = PENDING_PAYOUTS.with(|pending| pending.borrow().values().cloned().collect());
+    pending.sort_by_key(|p| p.id);
+    Ok(pending)
+}
+
+// Support: Payouts whose outcome the ledger could not settle, oldest first.
+// Their funds may or may not have moved and need reconciling by hand.
+#[ic_cdk::query]
+pub fn list_lost_payouts() -> RwaResult<Vec<PendingPayout>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAccounts)?;
+    let mut lost: Vec<PendingPayout> = LOST_PAYOUTS.with(|lost| lost.borrow().values().cloned().collect());
+    lost.sort_by_key(|p| p.id);
+    Ok(lost)
+}
+
+// Move funds from the caller's ledger account into their internal cash balance,
+// where the order book can use them. Requires an icrc2_approve for the canister.
+#[ic_cdk::update]
+pub async fn deposit_funds(currency: Currency, amount: u64) -> RwaResult<cash::CashBalance> {
+    let caller = ic_cdk::caller();
+    if amount == 0 {
+        return Err(RwaError::validation("amount", "must be greater than zero"));
+    }
+    let ledger = ledger_for(currency)
+        .ok_or_else(|| RwaError::InvalidState(format!("{:?} is not settled on a ledger", currency)))?;
+    collect(&ledger, caller, None, amount).await?;
+    cash::credit(caller, currency, amount, "Ledger deposit")?;
+    Ok(cash::balance_of(caller, currency))
+}
+
+// Send `amount` of the caller's available cash to a ledger account (default:
+// the caller's own). The ledger fee comes out of `amount`. The balance is
+// debited before the ledger call and restored if the ledger refuses the
+// transfer. If the ledger does not reply, the transfer is resent until its
+// outcome is known.
+#[ic_cdk::update]
+pub async fn withdraw(currency: Currency, amount: u64, to: Option<Account>) -> RwaResult<u64> {
+    let caller = ic_cdk::caller();
+    let _guard = WithdrawalGuard::acquire(caller)?;
+    let ledger = ledger_for(currency)
+        .ok_or_else(|| RwaError::InvalidState(format!("{:?} is not settled on a ledger", currency)))?;
+    if amount <= ledger.fee {
+        return Err(RwaError::validation("amount", format!("must exceed the ledger fee of {}", ledger.fee)));
+    }
+    let to = to.unwrap_or(Account::of(caller));
+    cash::debit(caller, currency, amount, "Withdrawal")?;
+    let mut payout = new_payout(ledger, None, Some(to), caller, amount, "Withdrawal".to_string());
+    let created_at = ic_cdk::api::time();
+    match pay(&payout.ledger, None, to, payout.sent(), payout.id, created_at).await {
+        Ok(block) => Ok(block),
+        Err(PayoutError::Rejected(e)) => {
+            cash::credit(caller, currency, amount, "Withdrawal reversed")
+                .unwrap_or_else(|credit_err| ic_cdk::trap(&format!("Failed to restore withdrawal of {}: {}", caller, credit_err)));
+            Err(call_failed("icrc1_transfer", e))
+        }
+        Err(e) => {
+            let message = format!("Ledger call icrc1_transfer failed: {}; payout #{} is being checked with the ledger", e, payout.id);
+            payout.attempts = 1;
+            payout_failed(payout, created_at, e);
+            Err(RwaError::InvalidState(message))
+        }
+    }
+}
+
+// Withdraw the caller's whole available balance in `currency`
+#[ic_cdk::update]
+pub async fn claim(currency: Currency, to: Option<Account>) -> RwaResult<u64> {
+    let available = cash::balance_of(ic_cdk::caller(), currency).available;
+    withdraw(currency, available, to).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unknown() -> PayoutError {
+        PayoutError::Unknown("SysTransient: timed out".to_string())
+    }
+
+    #[test]
+    fn refused_first_transfers_are_refunded_or_retried() {
+        let refused = PayoutError::Rejected(TransferError::InsufficientFunds { balance: Nat::from(0u64) });
+        assert_eq!(next_step(false, false, &refused), PayoutStep::Refund);
+        assert_eq!(next_step(true, false, &refused), PayoutStep::Retry);
+        assert_eq!(next_step(false, false, &PayoutError::Rejected(TransferError::TemporarilyUnavailable)), PayoutStep::Refund);
+    }
+
+    #[test]
+    fn transfers_without_a_reply_are_never_refunded() {
+        assert_eq!(next_step(false, false, &unknown()), PayoutStep::Reconcile);
+        assert_eq!(next_step(true, false, &unknown()), PayoutStep::Reconcile);
+        assert_eq!(next_step(false, true, &unknown()), PayoutStep::Reconcile);
+        assert_eq!(next_step(false, true, &PayoutError::Rejected(TransferError::TemporarilyUnavailable)), PayoutStep::Reconcile);
+        // The first send may have landed under a fee that has since changed
+        let refused = PayoutError::Rejected(TransferError::BadFee { expected_fee: Nat::from(20u64) });
+        assert_eq!(next_step(false, true, &refused), PayoutStep::Lost);
+        assert_eq!(next_step(true, true, &PayoutError::Rejected(TransferError::TooOld)), PayoutStep::Lost);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_day() {
+        assert_eq!(retry_delay(1), PAYOUT_RETRY_NANOS);
+        assert_eq!(retry_delay(2), 2 * PAYOUT_RETRY_NANOS);
+        assert_eq!(retry_delay(4), 8 * PAYOUT_RETRY_NANOS);
+        assert_eq!(retry_delay(40), MAX_PAYOUT_RETRY_NANOS);
+    }
+}
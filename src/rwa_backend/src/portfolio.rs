@@ -1,63 +1,878 @@
+// Portfolios are derived from what a user actually holds: ledger balances
+// (escrow included), open buy orders, and a running average cost per asset.
+// Positions are valued in their asset's currency and totalled in the user's
+// reporting currency. A daily timer snapshots every portfolio's value per
+// currency so performance can be charted and measured afterwards.
+
 use candid::Principal;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use crate::user::{is_admin, is_kyc_approved};
-use crate::notification::{create_notification, NotificationType};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::time::Duration;
+use ic_stable_structures::StableBTreeMap;
+use crate::access::{Permission, has_permission, require_permission};
+use crate::asset::{self, NANOS_PER_DAY};
+use crate::error::{RwaError, RwaResult};
+use crate::fx;
+use crate::icrc;
+use crate::memory::{self, Memory};
+use crate::money::{Currency, Money};
+use crate::orderbook::{self, OrderSide};
+use crate::token;
+use crate::trade;
+use crate::user::{reporting_currency, require_kyc};
+use crate::valuation;
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum PriceSource {
+    Appraisal,
+    LastTrade,
+    TokenPrice,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct Position {
+    pub asset_id: u64,
+    pub asset_name: String,
+    // The asset's currency; every amount below is in it
+    pub currency: Currency,
+    // Units owned, including `escrowed`
+    pub quantity: u64,
+    // Units parked in open sell orders or pending trades
+    pub escrowed: u64,
+    // Units still wanted by open buy orders
+    pub open_buy_quantity: u64,
+    // None when the units were acquired without a known price
+    pub cost_basis: Option<Money>,
+    pub price: Money,
+    pub price_source: PriceSource,
+    pub market_value: Money,
+    // Profit and loss in minor units of `currency`
+    pub unrealized_pnl: Option<i64>,
+    pub realized_pnl: i64,
+}
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
 pub struct Portfolio {
     pub user_id: Principal,
-    pub tokens: Vec<u64>,
+    // Reporting currency of the totals below
+    pub currency: Currency,
+    // Assets with a position
     pub assets: Vec<u64>,
+    pub positions: Vec<Position>,
+    pub market_value: Money,
+    pub cost_basis: Money,
+    // In minor units of `currency`
+    pub unrealized_pnl: i64,
+    // Distribution income received to date
+    pub income: Money,
+    // Currencies left out of the totals for want of a fresh rate
+    pub missing_rates: Vec<Currency>,
+    pub note: Option<String>,
+    pub valued_at: u64,
+}
+
+// What is stored per user; holdings are never stored, only derived
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PortfolioRecord {
+    pub user_id: Principal,
+    // Admin annotation
+    pub note: Option<String>,
+}
+
+// Running average cost of one user's units of one asset. Amounts are in the
+// asset's currency.
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct CostBasis {
+    pub quantity: u64,
+    pub cost: u64,
+    pub realized_pnl: i64,
+}
+
+// Running totals of value moved into and out of a user's holdings. Purchases
+// and incoming transfers are contributions, sales and outgoing transfers are
+// withdrawals; distributions are income and count towards performance.
+// Amounts are in the currency the flows are keyed by.
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct PortfolioFlows {
+    pub contributed: u64,
+    pub withdrawn: u64,
+    pub income: u64,
+}
+
+// A user's holdings and flows in one currency at one time. Kept in stable
+// memory, so the amounts stay bare; read them through the accessors.
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct CurrencyTotals {
+    pub currency: Currency,
+    pub market_value: u64,
+    pub cost_basis: u64,
+    // Flow totals as they stood at the snapshot
+    pub flows: PortfolioFlows,
+}
+
+impl CurrencyTotals {
+    fn market_value(&self) -> Money {
+        Money::new(self.market_value, self.currency)
+    }
+
+    fn cost_basis(&self) -> Money {
+        Money::new(self.cost_basis, self.currency)
+    }
+}
+
+// Snapshots keep each currency apart so they can be converted at the rate of
+// their own time, in whatever currency the user reports in later
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PortfolioSnapshot {
+    pub timestamp: u64,
+    pub totals: Vec<CurrencyTotals>,
+}
+
+#[derive(Clone, Copy, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum Granularity {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PortfolioHistoryPoint {
+    pub timestamp: u64,
+    pub market_value: Money,
+    pub cost_basis: Money,
+    // Contributions less withdrawals since the previous point, in minor units
+    pub net_flow: i64,
+    // Distribution income since the previous point
+    pub income: Money,
+    // Time-weighted return from the first point to this one
+    pub cumulative_return: f64,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct PortfolioHistory {
+    pub user_id: Principal,
+    // Reporting currency; each point is converted at the rates of its time
+    pub currency: Currency,
+    pub from: u64,
+    pub to: u64,
+    pub granularity: Granularity,
+    pub points: Vec<PortfolioHistoryPoint>,
+    pub net_flow: i64,
+    pub income: Money,
+    // None with fewer than two points
+    pub time_weighted_return: Option<f64>,
+    // Annualized internal rate of return; None when it has no solution
+    pub money_weighted_return: Option<f64>,
+    // Snapshots left out because no rate was fresh at their time
+    pub unconverted_points: u64,
+}
+
+// How often every portfolio is valued for the history
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Most users valued in one snapshot timer tick
+const SNAPSHOT_BATCH: usize = 200;
+
+// Instructions a snapshot batch may use before yielding, well under the
+// per-message limit
+const SNAPSHOT_BATCH_INSTRUCTIONS: u64 = 5_000_000_000;
+
+// Progress of a snapshot run across timer ticks
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct SnapshotRun {
+    pub started_at: u64,
+    // Last user handled; users are visited in principal order
+    pub after: Option<Principal>,
+    pub recorded: u64,
 }
 
+const NANOS_PER_YEAR: f64 = 365.25 * NANOS_PER_DAY as f64;
+
 thread_local! {
-    static PORTFOLIOS: RefCell<HashMap<Principal, Portfolio>> = RefCell::new(HashMap::new());
+    static PORTFOLIOS: RefCell<HashMap<Principal, PortfolioRecord>> = RefCell::new(HashMap::new());
+    static COST_BASIS: RefCell<HashMap<(Principal, u64), CostBasis>> = RefCell::new(HashMap::new());
+    static FLOWS: RefCell<HashMap<(Principal, Currency), PortfolioFlows>> = RefCell::new(HashMap::new());
+    // Keyed by (user, timestamp), in stable memory
+    static SNAPSHOTS: RefCell<StableBTreeMap<(Principal, u64), PortfolioSnapshot, Memory>> =
+        RefCell::new(memory::init_map(memory::PORTFOLIO_SNAPSHOTS));
+    static SNAPSHOT_RUN: RefCell<Option<SnapshotRun>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct PortfolioState {
+    pub portfolios: HashMap<Principal, PortfolioRecord>,
+    pub cost_basis: Option<HashMap<(Principal, u64), CostBasis>>,
+    pub flows: Option<HashMap<(Principal, Currency), PortfolioFlows>>,
+    // Only set in snapshots before schema v6, which kept the history on the heap
+    pub snapshots: Option<HashMap<Principal, Vec<PortfolioSnapshot>>>,
+    pub snapshot_run: Option<SnapshotRun>,
+}
+
+pub fn take_state() -> PortfolioState {
+    PortfolioState {
+        portfolios: PORTFOLIOS.with(|portfolios| std::mem::take(&mut *portfolios.borrow_mut())),
+        cost_basis: Some(COST_BASIS.with(|basis| std::mem::take(&mut *basis.borrow_mut()))),
+        flows: Some(FLOWS.with(|flows| std::mem::take(&mut *flows.borrow_mut()))),
+        snapshots: None,
+        snapshot_run: SNAPSHOT_RUN.with(|run| run.borrow_mut().take()),
+    }
+}
+
+pub fn restore_state(state: PortfolioState) {
+    PORTFOLIOS.with(|portfolios| *portfolios.borrow_mut() = state.portfolios);
+    COST_BASIS.with(|basis| *basis.borrow_mut() = state.cost_basis.unwrap_or_default());
+    FLOWS.with(|flows| *flows.borrow_mut() = state.flows.unwrap_or_default());
+    SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        for (user, history) in state.snapshots.unwrap_or_default() {
+            for snapshot in history {
+                snapshots.insert((user, snapshot.timestamp), snapshot);
+            }
+        }
+    });
+    SNAPSHOT_RUN.with(|run| *run.borrow_mut() = state.snapshot_run);
+}
+
+// Internal: Add `amount` to a bare total kept in the same currency
+fn add_to(total: &mut u64, amount: Money) -> RwaResult<()> {
+    *total = Money::new(*total, amount.currency).checked_add(amount)?.amount;
+    Ok(())
+}
+
+fn add_pnl(total: i64, pnl: i64) -> RwaResult<i64> {
+    total.checked_add(pnl).ok_or_else(|| RwaError::Overflow(format!("{} + {}", total, pnl)))
+}
+
+// Applies `update` to a copy of the user's flows in `currency`, and keeps it
+// only if the update succeeds
+fn record_flow(user: Principal, currency: Currency, update: impl FnOnce(&mut PortfolioFlows) -> RwaResult<()>) -> RwaResult<()> {
+    FLOWS.with(|flows| {
+        let mut flows = flows.borrow_mut();
+        let mut updated = flows.get(&(user, currency)).cloned().unwrap_or_default();
+        update(&mut updated)?;
+        flows.insert((user, currency), updated);
+        Ok(())
+    })
+}
+
+// Same as record_flow, for the user's cost basis in an asset
+fn record_cost(user: Principal, asset_id: u64, update: impl FnOnce(&mut CostBasis) -> RwaResult<()>) -> RwaResult<()> {
+    COST_BASIS.with(|basis| {
+        let mut basis = basis.borrow_mut();
+        let mut updated = basis.get(&(user, asset_id)).cloned().unwrap_or_default();
+        update(&mut updated)?;
+        basis.insert((user, asset_id), updated);
+        Ok(())
+    })
+}
+
+fn flows_of(user_id: Principal) -> Vec<(Currency, PortfolioFlows)> {
+    FLOWS.with(|flows| {
+        flows.borrow().iter()
+            .filter(|((user, _), _)| *user == user_id)
+            .map(|((_, currency), f)| (*currency, f.clone()))
+            .collect()
+    })
+}
+
+// Price an asset's units are valued at in its own currency: the latest
+// accepted appraisal, else the last trade, else the issue price
+fn price_of(asset: &asset::Asset) -> (Money, PriceSource) {
+    if let Some(nav) = valuation::appraised_nav(asset) {
+        return (nav, PriceSource::Appraisal);
+    }
+    match trade::last_trade_price(asset.id).map(|price| fx::convert_at_last_rate(price, asset.currency())) {
+        Some(Ok(price)) => (price, PriceSource::LastTrade),
+        _ => (asset.token_price, PriceSource::TokenPrice),
+    }
+}
+
+// A fill's price restated in the asset's currency. Order entry and trade
+// settlement refuse a pair without a rate, so a failure here fails the fill
+// rather than leaving it unbooked.
+fn in_asset_currency(asset_id: u64, price: Money) -> RwaResult<Money> {
+    fx::convert_at_last_rate(price, asset::get_asset(asset_id)?.currency())
+}
+
+// Internal: `user` acquired `quantity` units at `price`
+pub fn record_purchase(user: Principal, asset_id: u64, quantity: u64, price: Money) -> RwaResult<()> {
+    let price = in_asset_currency(asset_id, price)?;
+    let value = price.checked_mul(quantity)?;
+    record_cost(user, asset_id, |entry| {
+        entry.quantity = entry.quantity.checked_add(quantity)
+            .ok_or_else(|| RwaError::Overflow(format!("{} + {} units", entry.quantity, quantity)))?;
+        add_to(&mut entry.cost, value)
+    })?;
+    record_flow(user, value.currency, |f| add_to(&mut f.contributed, value))
+}
+
+// Removes `quantity` units at average cost and returns the cost taken out
+fn remove_units(user: Principal, asset_id: u64, quantity: u64, currency: Currency) -> RwaResult<Money> {
+    let mut removed = Money::new(0, currency);
+    record_cost(user, asset_id, |entry| {
+        let quantity = quantity.min(entry.quantity);
+        if quantity == 0 {
+            return Ok(());
+        }
+        let cost = Money::new(entry.cost, currency);
+        removed = cost.checked_pro_rata(quantity, entry.quantity)?;
+        entry.quantity -= quantity;
+        entry.cost = cost.checked_sub(removed)?.amount;
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+// Internal: `user` sold `quantity` units at `price`; books the realized P&L
+pub fn record_sale(user: Principal, asset_id: u64, quantity: u64, price: Money) -> RwaResult<()> {
+    let price = in_asset_currency(asset_id, price)?;
+    let value = price.checked_mul(quantity)?;
+    let cost = remove_units(user, asset_id, quantity, value.currency)?;
+    let pnl = value.checked_delta(cost)?;
+    record_cost(user, asset_id, |entry| {
+        entry.realized_pnl = add_pnl(entry.realized_pnl, pnl)?;
+        Ok(())
+    })?;
+    record_flow(user, value.currency, |f| add_to(&mut f.withdrawn, value))
+}
+
+// Internal: Units moved between users without a price carry their cost with
+// them; for performance they leave and enter at the current price
+pub fn record_transfer(from: Principal, to: Principal, asset_id: u64, quantity: u64) -> RwaResult<()> {
+    let asset = asset::get_asset(asset_id)?;
+    let value = price_of(&asset).0.checked_mul(quantity)?;
+    record_flow(from, value.currency, |f| add_to(&mut f.withdrawn, value))?;
+    record_flow(to, value.currency, |f| add_to(&mut f.contributed, value))?;
+    let cost = remove_units(from, asset_id, quantity, asset.currency())?;
+    record_cost(to, asset_id, |entry| {
+        entry.quantity = entry.quantity.checked_add(quantity)
+            .ok_or_else(|| RwaError::Overflow(format!("{} + {} units", entry.quantity, quantity)))?;
+        add_to(&mut entry.cost, cost)
+    })
+}
+
+// Internal: `user` was paid `amount` of distribution income
+pub fn record_income(user: Principal, amount: Money) -> RwaResult<()> {
+    record_flow(user, amount.currency, |f| add_to(&mut f.income, amount))
+}
+
+// (free, escrowed) units per holder of every asset with a ledger
+type LedgerHoldings = Vec<(u64, BTreeMap<Principal, (u64, u64)>)>;
+
+fn ledger_holdings() -> RwaResult<LedgerHoldings> {
+    icrc::ledger_ids().into_iter().map(|asset_id| Ok((asset_id, token::holdings_detail(asset_id)?))).collect()
+}
+
+// Internal: Value `user`'s holdings as of now
+pub fn compute_portfolio(user_id: Principal) -> RwaResult<Portfolio> {
+    value_portfolio(user_id, &ledger_holdings()?)
+}
+
+// Values `user`'s holdings against balances already read from the ledgers
+fn value_portfolio(user_id: Principal, ledgers: &LedgerHoldings) -> RwaResult<Portfolio> {
+    let mut open_buys: BTreeMap<u64, u64> = BTreeMap::new();
+    for order in orderbook::open_orders_of(user_id).into_iter().filter(|o| o.side == OrderSide::Buy) {
+        *open_buys.entry(order.asset_id).or_insert(0) += order.remaining();
+    }
+    let mut asset_ids: BTreeSet<u64> = open_buys.keys().copied().collect();
+    let mut held: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    for (asset_id, holders) in ledgers {
+        if let Some(units) = holders.get(&user_id) {
+            held.insert(*asset_id, *units);
+            asset_ids.insert(*asset_id);
+        }
+    }
+    let mut positions = Vec::new();
+    for asset_id in asset_ids {
+        let asset = asset::get_asset(asset_id)?;
+        let (free, escrowed) = held.get(&asset_id).copied().unwrap_or_default();
+        let quantity = free + escrowed;
+        let (price, price_source) = price_of(&asset);
+        let basis = COST_BASIS.with(|basis| basis.borrow().get(&(user_id, asset_id)).cloned()).unwrap_or_default();
+        // Average cost applied to the units actually held; unknown if none were priced
+        let cost_basis = match basis.quantity {
+            0 => None,
+            held => Some(Money::new(basis.cost, asset.currency()).checked_pro_rata(quantity, held)?),
+        };
+        let market_value = price.checked_mul(quantity)?;
+        positions.push(Position {
+            asset_id,
+            asset_name: asset.name.clone(),
+            currency: asset.currency(),
+            quantity,
+            escrowed,
+            open_buy_quantity: open_buys.get(&asset_id).copied().unwrap_or(0),
+            cost_basis,
+            price,
+            price_source,
+            market_value,
+            unrealized_pnl: cost_basis.map(|cost| market_value.checked_delta(cost)).transpose()?,
+            realized_pnl: basis.realized_pnl,
+        });
+    }
+    let currency = reporting_currency(&user_id);
+    let now = ic_cdk::api::time();
+    let mut missing_rates = BTreeSet::new();
+    let mut convert = |amount: Money| -> Option<Money> {
+        match fx::convert(amount, currency, now) {
+            Ok(converted) => Some(converted),
+            Err(_) => {
+                missing_rates.insert(amount.currency);
+                None
+            }
+        }
+    };
+    let (mut market_value, mut cost_basis, mut unrealized_pnl) = (Money::new(0, currency), Money::new(0, currency), 0i64);
+    for p in &positions {
+        let Some(value) = convert(p.market_value) else { continue };
+        market_value = market_value.checked_add(value)?;
+        if let Some(cost) = p.cost_basis.and_then(&mut convert) {
+            cost_basis = cost_basis.checked_add(cost)?;
+            unrealized_pnl = add_pnl(unrealized_pnl, value.checked_delta(cost)?)?;
+        }
+    }
+    let mut income = Money::new(0, currency);
+    for (from, f) in flows_of(user_id) {
+        if let Some(converted) = convert(Money::new(f.income, from)) {
+            income = income.checked_add(converted)?;
+        }
+    }
+    Ok(Portfolio {
+        user_id,
+        currency,
+        assets: positions.iter().filter(|p| p.quantity > 0).map(|p| p.asset_id).collect(),
+        positions,
+        market_value,
+        cost_basis,
+        unrealized_pnl,
+        income,
+        missing_rates: missing_rates.into_iter().collect(),
+        note: PORTFOLIOS.with(|portfolios| portfolios.borrow().get(&user_id).and_then(|r| r.note.clone())),
+        valued_at: now,
+    })
 }
 
 #[ic_cdk::update]
-pub fn create_portfolio(user_id: Principal) -> Portfolio {
+pub fn create_portfolio(user_id: Principal) -> RwaResult<Portfolio> {
     let caller = ic_cdk::caller();
-    if !is_kyc_approved(&user_id) {
-        ic_cdk::trap("KYC not approved");
+    if caller != user_id && !has_permission(&caller, Permission::ManageAccounts) {
+        return Err(RwaError::Unauthorized);
     }
-    let portfolio = Portfolio {
-        user_id,
-        tokens: vec![],
-        assets: vec![],
-    };
-    PORTFOLIOS.with(|portfolios| portfolios.borrow_mut().insert(user_id, portfolio.clone()));
-    portfolio
+    require_kyc(&user_id)?;
+    if PORTFOLIOS.with(|portfolios| portfolios.borrow().contains_key(&user_id)) {
+        return Err(RwaError::AlreadyExists(format!("Portfolio for {}", user_id)));
+    }
+    PORTFOLIOS.with(|portfolios| portfolios.borrow_mut().insert(user_id, PortfolioRecord { user_id, note: None }));
+    compute_portfolio(user_id)
 }
 
 #[ic_cdk::query]
-pub fn get_portfolio(user_id: Principal) -> Option<Portfolio> {
-    PORTFOLIOS.with(|portfolios| portfolios.borrow().get(&user_id).cloned())
+pub fn get_portfolio(user_id: Principal) -> RwaResult<Portfolio> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    compute_portfolio(user_id)
 }
 
+// Admin: Attach a note to a user's portfolio. Holdings themselves are derived
+// and cannot be edited.
 #[ic_cdk::update]
-pub fn update_portfolio(user_id: Principal, tokens: Vec<u64>, assets: Vec<u64>) -> Option<Portfolio> {
-    let caller = ic_cdk::caller();
-    if caller != user_id && !is_admin(&caller) {
-        return None;
+pub fn update_portfolio(user_id: Principal, note: Option<String>) -> RwaResult<Portfolio> {
+    require_permission(&ic_cdk::caller(), Permission::ManageAccounts)?;
+    PORTFOLIOS.with(|portfolios| {
+        portfolios.borrow_mut().entry(user_id).or_insert_with(|| PortfolioRecord { user_id, note: None }).note = note;
+    });
+    compute_portfolio(user_id)
+}
+
+// Users with a portfolio record, a snapshot history or units on any ledger
+fn tracked_users(ledgers: &LedgerHoldings) -> BTreeSet<Principal> {
+    let mut users: BTreeSet<Principal> = PORTFOLIOS.with(|portfolios| portfolios.borrow().keys().copied().collect());
+    users.extend(snapshot_users());
+    for (_, holders) in ledgers {
+        users.extend(holders.keys().copied());
+    }
+    users
+}
+
+#[ic_cdk::query]
+pub fn list_portfolios() -> RwaResult<Vec<Portfolio>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAccounts)?;
+    let ledgers = ledger_holdings()?;
+    tracked_users(&ledgers).into_iter().map(|user_id| value_portfolio(user_id, &ledgers)).collect()
+}
+
+fn snapshot_of(portfolio: &Portfolio) -> RwaResult<PortfolioSnapshot> {
+    let mut totals: BTreeMap<Currency, CurrencyTotals> = BTreeMap::new();
+    fn entry(totals: &mut BTreeMap<Currency, CurrencyTotals>, currency: Currency) -> &mut CurrencyTotals {
+        totals.entry(currency).or_insert_with(|| CurrencyTotals {
+            currency,
+            market_value: 0,
+            cost_basis: 0,
+            flows: PortfolioFlows::default(),
+        })
     }
-    if !is_kyc_approved(&user_id) {
-        ic_cdk::trap("KYC not approved");
+    for p in &portfolio.positions {
+        let t = entry(&mut totals, p.currency);
+        add_to(&mut t.market_value, p.market_value)?;
+        if let Some(cost) = p.cost_basis {
+            add_to(&mut t.cost_basis, cost)?;
+        }
     }
-    PORTFOLIOS.with(|portfolios| {
-        let mut portfolios = portfolios.borrow_mut();
-        if let Some(portfolio) = portfolios.get_mut(&user_id) {
-            portfolio.tokens = tokens;
-            portfolio.assets = assets;
-            // Notify user
-            create_notification(user_id, NotificationType::Investment, "Your portfolio was updated".to_string(), ic_cdk::api::time().to_string());
-            return Some(portfolio.clone());
+    for (currency, flows) in flows_of(portfolio.user_id) {
+        entry(&mut totals, currency).flows = flows;
+    }
+    Ok(PortfolioSnapshot { timestamp: portfolio.valued_at, totals: totals.into_values().collect() })
+}
+
+// A snapshot restated in the reporting currency at the rates of its own time
+struct ValuedSnapshot<'a> {
+    snapshot: &'a PortfolioSnapshot,
+    market_value: Money,
+    cost_basis: Money,
+}
+
+// None when a currency in the snapshot had no fresh rate at its time
+fn value_snapshot(snapshot: &PortfolioSnapshot, currency: Currency) -> RwaResult<Option<ValuedSnapshot<'_>>> {
+    let (mut market_value, mut cost_basis) = (Money::new(0, currency), Money::new(0, currency));
+    for t in &snapshot.totals {
+        let convert = |amount| fx::convert(amount, currency, snapshot.timestamp).ok();
+        let (Some(value), Some(cost)) = (convert(t.market_value()), convert(t.cost_basis())) else { return Ok(None) };
+        market_value = market_value.checked_add(value)?;
+        cost_basis = cost_basis.checked_add(cost)?;
+    }
+    Ok(Some(ValuedSnapshot { snapshot, market_value, cost_basis }))
+}
+
+// Net contributions and income between two snapshots. Each currency's change
+// is converted at the later snapshot's rates so exchange moves on flows
+// already counted do not show up as new flows.
+fn flows_between(after: &PortfolioSnapshot, before: Option<&PortfolioSnapshot>, currency: Currency) -> RwaResult<(i64, Money)> {
+    let (mut net_flow, mut income) = (0i64, Money::new(0, currency));
+    for t in &after.totals {
+        let previous = before
+            .and_then(|b| b.totals.iter().find(|p| p.currency == t.currency))
+            .map(|p| p.flows.clone())
+            .unwrap_or_default();
+        // Flow totals only grow; value_snapshot already proved a rate exists at this time
+        let change = |total: u64, before: u64| -> RwaResult<Money> {
+            let change = Money::new(total, t.currency).checked_sub(Money::new(before, t.currency))?;
+            fx::convert(change, currency, after.timestamp)
+        };
+        let contributed = change(t.flows.contributed, previous.contributed)?;
+        let withdrawn = change(t.flows.withdrawn, previous.withdrawn)?;
+        net_flow = add_pnl(net_flow, contributed.checked_delta(withdrawn)?)?;
+        income = income.checked_add(change(t.flows.income, previous.income)?)?;
+    }
+    Ok((net_flow, income))
+}
+
+// Internal: Start a snapshot run over every tracked portfolio. Run by the
+// scheduler every SNAPSHOT_INTERVAL; the users are worked through in batches,
+// one per timer tick, so no single message grows with the user base.
+pub fn record_snapshots() {
+    if let Some(run) = SNAPSHOT_RUN.with(|run| run.borrow().clone()) {
+        return ic_cdk::println!("Portfolio snapshots: run started at {} is still going; not starting another", run.started_at);
+    }
+    SNAPSHOT_RUN.with(|run| *run.borrow_mut() = Some(SnapshotRun { started_at: ic_cdk::api::time(), after: None, recorded: 0 }));
+    snapshot_batch();
+}
+
+// Internal: Pick up a run an upgrade interrupted
+pub fn resume_snapshots() {
+    if SNAPSHOT_RUN.with(|run| run.borrow().is_some()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, snapshot_batch);
+    }
+}
+
+// Snapshots up to SNAPSHOT_BATCH users after the run's cursor, then hands the
+// rest to a fresh timer. A batch that reaches SNAPSHOT_BATCH_INSTRUCTIONS
+// stops early at the user it got to.
+fn snapshot_batch() {
+    let Some(mut run) = SNAPSHOT_RUN.with(|run| run.borrow().clone()) else { return };
+    let ledgers = match ledger_holdings() {
+        Ok(ledgers) => ledgers,
+        Err(e) => {
+            SNAPSHOT_RUN.with(|run| *run.borrow_mut() = None);
+            return ic_cdk::println!("Portfolio snapshots abandoned: {}", e);
+        }
+    };
+    let users = tracked_users(&ledgers);
+    let pending: Vec<Principal> = match run.after {
+        Some(after) => users.range((Bound::Excluded(after), Bound::Unbounded)).copied().collect(),
+        None => users.into_iter().collect(),
+    };
+    let mut done = 0;
+    for user_id in pending.iter().take(SNAPSHOT_BATCH) {
+        // At least one user per batch, so a run always moves forward
+        if done > 0 && ic_cdk::api::instruction_counter() > SNAPSHOT_BATCH_INSTRUCTIONS {
+            ic_cdk::println!("Portfolio snapshots: batch cut short after {} users at the instruction budget", done);
+            break;
+        }
+        match value_portfolio(*user_id, &ledgers) {
+            Ok(portfolio) => {
+                if record_snapshot(&portfolio) {
+                    run.recorded += 1;
+                }
+            }
+            Err(e) => ic_cdk::println!("Portfolio snapshot of {} skipped: {}", user_id, e),
+        }
+        run.after = Some(*user_id);
+        done += 1;
+    }
+    if done < pending.len() {
+        SNAPSHOT_RUN.with(|r| *r.borrow_mut() = Some(run));
+        ic_cdk_timers::set_timer(Duration::ZERO, snapshot_batch);
+    } else {
+        SNAPSHOT_RUN.with(|r| *r.borrow_mut() = None);
+        ic_cdk::println!("Portfolio snapshots: run started at {} recorded {} snapshots", run.started_at, run.recorded);
+    }
+}
+
+// Snapshots of `user` taken between `from` and `to` inclusive, oldest first
+fn snapshot_range(snapshots: &StableBTreeMap<(Principal, u64), PortfolioSnapshot, Memory>, user: Principal, from: u64, to: u64) -> impl Iterator<Item = PortfolioSnapshot> + '_ {
+    snapshots.range((user, from)..=(user, to)).map(|(_, snapshot)| snapshot)
+}
+
+// Users with at least one snapshot, found by skipping from one user's keys to the next
+fn snapshot_users() -> Vec<Principal> {
+    SNAPSHOTS.with(|snapshots| {
+        let snapshots = snapshots.borrow();
+        let mut users = Vec::new();
+        let mut next = snapshots.iter().next();
+        while let Some(((user, _), _)) = next {
+            users.push(user);
+            next = snapshots.range((Bound::Excluded((user, u64::MAX)), Bound::Unbounded)).next();
+        }
+        users
+    })
+}
+
+// Appends `portfolio` to its user's history; false if there is nothing to chart
+fn record_snapshot(portfolio: &Portfolio) -> bool {
+    SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        let user = portfolio.user_id;
+        // Nothing worth charting until the user first holds something
+        if portfolio.positions.is_empty() && snapshot_range(&snapshots, user, 0, u64::MAX).next().is_none() {
+            return false;
+        }
+        match snapshot_of(portfolio) {
+            Ok(snapshot) => {
+                snapshots.insert((user, snapshot.timestamp), snapshot);
+                true
+            }
+            Err(e) => {
+                ic_cdk::println!("Portfolio snapshot of {} skipped: {}", user, e);
+                false
+            }
         }
-        None
     })
 }
 
+// Bucket a timestamp falls in: days since the epoch, ISO weeks (Monday
+// first), or calendar months
+fn bucket(timestamp: u64, granularity: Granularity) -> i64 {
+    let days = (timestamp / NANOS_PER_DAY) as i64;
+    match granularity {
+        Granularity::Daily => days,
+        // 1970-01-01 was a Thursday
+        Granularity::Weekly => (days + 3).div_euclid(7),
+        Granularity::Monthly => {
+            // Inverse of migration::days_from_civil
+            let z = days + 719_468;
+            let era = z.div_euclid(146_097);
+            let doe = z - era * 146_097;
+            let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+            let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+            let mp = (5 * doy + 2) / 153;
+            let month = if mp < 10 { mp + 3 } else { mp - 9 };
+            let year = yoe + era * 400 + i64::from(month <= 2);
+            year * 12 + month - 1
+        }
+    }
+}
+
+// Growth factor of one period for the time-weighted return. Flows are taken
+// as arriving at the start of the period, so a period that starts from
+// nothing still has a base to grow from; one with no base does not count.
+fn period_growth(opening: Money, net_flow: i64, closing: Money, income: Money) -> f64 {
+    let base = opening.amount as f64 + net_flow as f64;
+    if base > 0.0 { (closing.amount as f64 + income.amount as f64) / base } else { 1.0 }
+}
+
+// Annualized rate at which `cash_flows` (time, amount) discount to zero,
+// found by bisection. None if the flows never change sign over the range.
+fn internal_rate_of_return(cash_flows: &[(u64, f64)]) -> Option<f64> {
+    let start = cash_flows.first()?.0;
+    let npv = |rate: f64| -> f64 {
+        cash_flows.iter()
+            .map(|(at, amount)| amount / (1.0 + rate).powf((at - start) as f64 / NANOS_PER_YEAR))
+            .sum()
+    };
+    let (mut low, mut high) = (-0.9999, 1.0);
+    while npv(low).signum() == npv(high).signum() {
+        if high > 1e6 {
+            return None;
+        }
+        high *= 10.0;
+    }
+    for _ in 0..200 {
+        let mid = (low + high) / 2.0;
+        if npv(mid).signum() == npv(low).signum() {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    let rate = (low + high) / 2.0;
+    rate.is_finite().then_some(rate)
+}
+
+// Performance of a user's portfolio between `from` and `to` (nanoseconds),
+// one point per bucket taken from the last snapshot in it, in the user's
+// reporting currency. The current valuation is included when `to` has not
+// passed yet.
 #[ic_cdk::query]
-pub fn list_portfolios() -> Vec<Portfolio> {
-    PORTFOLIOS.with(|portfolios| portfolios.borrow().values().cloned().collect())
-} 
\ No newline at end of file
+pub fn get_portfolio_history(user_id: Principal, from: u64, to: u64, granularity: Granularity) -> RwaResult<PortfolioHistory> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    if from >= to {
+        return Err(RwaError::validation("to", "must be after from"));
+    }
+    let mut snapshots: Vec<PortfolioSnapshot> = SNAPSHOTS.with(|snapshots| snapshot_range(&snapshots.borrow(), user_id, from, to).collect());
+    let now = ic_cdk::api::time();
+    if (from..=to).contains(&now) {
+        snapshots.push(snapshot_of(&compute_portfolio(user_id)?)?);
+    }
+    let currency = reporting_currency(&user_id);
+    let valued: Vec<ValuedSnapshot> = snapshots.iter()
+        .map(|s| value_snapshot(s, currency))
+        .collect::<RwaResult<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    let unconverted_points = (snapshots.len() - valued.len()) as u64;
+    let mut sampled: Vec<ValuedSnapshot> = Vec::new();
+    for v in valued {
+        match sampled.last_mut() {
+            Some(last) if bucket(last.snapshot.timestamp, granularity) == bucket(v.snapshot.timestamp, granularity) => *last = v,
+            _ => sampled.push(v),
+        }
+    }
+    let mut points = Vec::with_capacity(sampled.len());
+    let mut cash_flows = Vec::with_capacity(sampled.len() + 1);
+    let mut growth = 1.0;
+    for (index, v) in sampled.iter().enumerate() {
+        let timestamp = v.snapshot.timestamp;
+        let (net_flow, income) = match index.checked_sub(1).map(|i| &sampled[i]) {
+            Some(previous) => {
+                let (net_flow, income) = flows_between(v.snapshot, Some(previous.snapshot), currency)?;
+                growth *= period_growth(previous.market_value, net_flow, v.market_value, income);
+                cash_flows.push((timestamp, income.amount as f64 - net_flow as f64));
+                (net_flow, income)
+            }
+            None => {
+                // The opening value is treated as invested at the first point
+                cash_flows.push((timestamp, -(v.market_value.amount as f64)));
+                (0, Money::new(0, currency))
+            }
+        };
+        points.push(PortfolioHistoryPoint {
+            timestamp,
+            market_value: v.market_value,
+            cost_basis: v.cost_basis,
+            net_flow,
+            income,
+            cumulative_return: growth - 1.0,
+        });
+    }
+    let has_periods = points.len() > 1;
+    if let Some(last) = sampled.last().filter(|_| has_periods) {
+        // ...and the closing value as realized at the last one
+        cash_flows.push((last.snapshot.timestamp, last.market_value.amount as f64));
+    }
+    let mut net_flow = 0i64;
+    let mut income = Money::new(0, currency);
+    for p in &points {
+        net_flow = add_pnl(net_flow, p.net_flow)?;
+        income = income.checked_add(p.income)?;
+    }
+    Ok(PortfolioHistory {
+        user_id,
+        currency,
+        from,
+        to,
+        granularity,
+        net_flow,
+        income,
+        time_weighted_return: has_periods.then_some(growth - 1.0),
+        money_weighted_return: if has_periods { internal_rate_of_return(&cash_flows) } else { None },
+        points,
+        unconverted_points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YEAR: u64 = NANOS_PER_YEAR as u64;
+
+    fn usd(amount: u64) -> Money {
+        Money::new(amount, Currency::USD)
+    }
+
+    fn snapshot(timestamp: u64, contributed: u64, withdrawn: u64, income: u64) -> PortfolioSnapshot {
+        PortfolioSnapshot {
+            timestamp,
+            totals: vec![CurrencyTotals { currency: Currency::USD, market_value: 0, cost_basis: 0, flows: PortfolioFlows { contributed, withdrawn, income } }],
+        }
+    }
+
+    #[test]
+    fn irr_finds_the_annual_rate_that_discounts_flows_to_zero() {
+        let rate = internal_rate_of_return(&[(0, -100.0), (YEAR, 110.0)]).unwrap();
+        assert!((rate - 0.10).abs() < 1e-9, "{}", rate);
+        // 100 in, 50 more after a year, 165 out after two: 10% a year on both
+        let rate = internal_rate_of_return(&[(0, -100.0), (YEAR, -50.0), (2 * YEAR, 176.0)]).unwrap();
+        assert!((rate - 0.10).abs() < 1e-6, "{}", rate);
+        assert_eq!(internal_rate_of_return(&[(0, 100.0), (YEAR, 110.0)]), None);
+        assert_eq!(internal_rate_of_return(&[]), None);
+    }
+
+    #[test]
+    fn twr_periods_treat_flows_as_arriving_at_the_start() {
+        // 100 grows with 50 added to 165: 10% on the 150 invested
+        assert!((period_growth(usd(100), 50, usd(165), usd(0)) - 1.1).abs() < 1e-12);
+        // Income counts as return, withdrawals shrink the base
+        assert!((period_growth(usd(100), -20, usd(80), usd(8)) - 1.1).abs() < 1e-12);
+        assert_eq!(period_growth(usd(0), 0, usd(50), usd(0)), 1.0);
+    }
+
+    #[test]
+    fn flows_between_snapshots_are_the_change_in_running_totals() {
+        let before = snapshot(1, 100, 0, 0);
+        let after = snapshot(2, 150, 20, 5);
+        assert_eq!(flows_between(&after, Some(&before), Currency::USD).unwrap(), (30, usd(5)));
+        assert_eq!(flows_between(&after, None, Currency::USD).unwrap(), (130, usd(5)));
+        // Running totals never shrink; a snapshot where one did is refused
+        assert!(matches!(flows_between(&before, Some(&after), Currency::USD), Err(RwaError::Overflow(_))));
+    }
+
+    #[test]
+    fn buckets_follow_iso_weeks_and_calendar_months() {
+        let day = |d: u64| d * NANOS_PER_DAY;
+        // 1970-01-04 was a Sunday, 1970-01-05 a Monday
+        assert_eq!(bucket(day(3), Granularity::Weekly) + 1, bucket(day(4), Granularity::Weekly));
+        assert_eq!(bucket(day(4), Granularity::Weekly), bucket(day(10), Granularity::Weekly));
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(bucket(day(19_782), Granularity::Monthly), 2024 * 12 + 1);
+        assert_eq!(bucket(day(19_783), Granularity::Monthly), 2024 * 12 + 2);
+        assert_eq!(bucket(day(19_783) + 1, Granularity::Daily), 19_783);
+    }
+}
@@ -0,0 +1,109 @@
+// Background jobs on ic-cdk-timers: funding rounds close at their deadline,
+// pending trades expire at expires_at, income distributions are paid at their
+// record date, KYC approvals lapse at their expiry, failed escrow payouts are
+// retried and portfolios are snapshotted daily. Timers do not survive
+// upgrades, so `arm` re-creates them from canister state in init and
+// post_upgrade.
+
+use candid::Principal;
+use std::time::Duration;
+use crate::distribution;
+use crate::funding;
+use crate::kyc;
+use crate::payment;
+use crate::portfolio;
+use crate::trade;
+
+// Safety net for jobs whose timer was lost or whose run failed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Job {
+    CloseFundingRound(u64),
+    ExpireTrade(u64),
+    PayDistribution(u64),
+    ExpireKyc(Principal),
+    RetryPayout(u64),
+}
+
+// Internal: Arm one timer per scheduled job plus the periodic sweep and snapshots
+pub fn arm() {
+    for (job, due_at) in due_jobs(u64::MAX) {
+        schedule(job, due_at);
+    }
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || {
+        for (job, _) in due_jobs(ic_cdk::api::time()) {
+            schedule(job, 0);
+        }
+    });
+    ic_cdk_timers::set_timer_interval(portfolio::SNAPSHOT_INTERVAL, portfolio::record_snapshots);
+    portfolio::resume_snapshots();
+}
+
+// Internal: Run `job` at `due_at` (nanoseconds since the epoch). Each job runs
+// in its own timer callback so a failure only rolls back that job.
+pub fn schedule(job: Job, due_at: u64) {
+    let delay = due_at.saturating_sub(ic_cdk::api::time());
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || run(job));
+}
+
+// Scheduled work that falls due at or before `until`
+fn due_jobs(until: u64) -> Vec<(Job, u64)> {
+    let rounds = funding::open_round_deadlines().into_iter()
+        .map(|(id, deadline)| (Job::CloseFundingRound(id), deadline));
+    let trades = trade::pending_trade_expiries().into_iter()
+        .map(|(id, expires_at)| (Job::ExpireTrade(id), expires_at));
+    let distributions = distribution::scheduled_record_dates().into_iter()
+        .map(|(id, record_date)| (Job::PayDistribution(id), record_date));
+    let kyc = kyc::kyc_expiries().into_iter()
+        .map(|(user_id, expires_at)| (Job::ExpireKyc(user_id), expires_at));
+    let payouts = payment::pending_payout_times().into_iter()
+        .map(|(id, next_attempt_at)| (Job::RetryPayout(id), next_attempt_at));
+    rounds.chain(trades).chain(distributions).chain(kyc).chain(payouts).filter(|(_, due_at)| *due_at <= until).collect()
+}
+
+// Jobs are idempotent: anything already closed, settled or cancelled since
+// scheduling is skipped, and anything not yet due is left for a later run.
+fn run(job: Job) {
+    let now = ic_cdk::api::time();
+    let result = match job {
+        Job::CloseFundingRound(id) => match funding::get_funding_round(id) {
+            Ok(round) if round.status == funding::FundingRoundStatus::Open && round.deadline <= now => {
+                // Trap so a half-closed round is rolled back and retried by the sweep
+                let round = funding::close_round(id)
+                    .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to close funding round #{}: {}", id, e)));
+                Ok(format!("funding round #{} closed as {:?}", id, round.status))
+            }
+            _ => return,
+        },
+        Job::ExpireTrade(id) => match trade::get_trade_internal(id) {
+            Ok(t) if t.status == trade::TradeStatus::Pending && t.expires_at.is_some_and(|at| at <= now) => {
+                trade::expire_trade(id).map(|_| format!("trade #{} expired", id))
+            }
+            _ => return,
+        },
+        Job::PayDistribution(id) => match distribution::get_distribution(id) {
+            Ok(d) if d.status == distribution::DistributionStatus::Scheduled && d.record_date <= now => {
+                // Trap so a partly paid distribution is rolled back and retried by the sweep
+                distribution::record_and_pay(id)
+                    .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to pay distribution #{}: {}", id, e)));
+                Ok(format!("distribution #{} paid", id))
+            }
+            _ => return,
+        },
+        Job::ExpireKyc(user_id) => match kyc::expire(user_id) {
+            Ok(()) => Ok(format!("KYC approval of {} expired", user_id)),
+            // Renewed, revoked or already lapsed since scheduling
+            Err(_) => return,
+        },
+        Job::RetryPayout(id) => match payment::retry_payout(id) {
+            Ok(()) => Ok(format!("payout #{} retried", id)),
+            // Already retried or not due yet
+            Err(_) => return,
+        },
+    };
+    match result {
+        Ok(message) => ic_cdk::println!("Scheduler: {}", message),
+        Err(e) => ic_cdk::println!("Scheduler: {:?} deferred: {}", job, e),
+    }
+}
@@ -1,92 +1,126 @@
 use candid::Principal;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use crate::user::{is_admin, is_kyc_approved};
+use std::collections::BTreeMap;
+use crate::access::{Permission, has_permission};
+use crate::asset;
+use crate::compliance;
+use crate::error::{RwaError, RwaResult};
+use crate::icrc::{self, Account, ESCROW_ORDER, ESCROW_TRADE};
+use crate::money::Money;
+use crate::orderbook;
+use crate::portfolio;
+use crate::trade;
+use crate::user::require_kyc;
 use crate::notification::{create_notification, NotificationType};
 
+// A user's units of one asset. Ownership lives on the asset ledger alone;
+// this is a view over its balances, never stored.
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
-pub struct Token {
-    pub id: u64,
+pub struct Holding {
     pub asset_id: u64,
     pub owner_id: Principal,
-    pub amount: u64,
-    pub price: u64,
-    pub status: TokenStatus,
+    // Units in the holder's own accounts
+    pub available: u64,
+    // Units parked in order or trade escrow
+    pub escrowed: u64,
 }
 
-#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
-pub enum TokenStatus {
-    Available,
-    Sold,
-    Locked,
+// Internal: Units of an asset held by each user as (free, escrowed). Units
+// parked in order or trade escrow count towards the user who escrowed them.
+pub fn holdings_detail(asset_id: u64) -> RwaResult<BTreeMap<Principal, (u64, u64)>> {
+    let mut holdings: BTreeMap<Principal, (u64, u64)> = BTreeMap::new();
+    for (account, amount) in icrc::balances(asset_id)? {
+        let (holder, escrowed) = match icrc::parse_escrow(&account) {
+            Some((ESCROW_ORDER, id)) => (orderbook::get_order_internal(id)?.owner_id, true),
+            Some((ESCROW_TRADE, id)) => (trade::get_trade_internal(id)?.seller_id, true),
+            _ if account.owner == ic_cdk::id() => continue,
+            _ => (account.owner, false),
+        };
+        let entry = holdings.entry(holder).or_default();
+        if escrowed {
+            entry.1 += amount;
+        } else {
+            entry.0 += amount;
+        }
+    }
+    Ok(holdings)
 }
 
-thread_local! {
-    static TOKENS: RefCell<HashMap<u64, Token>> = RefCell::new(HashMap::new());
-    static TOKEN_ID_COUNTER: RefCell<u64> = RefCell::new(1);
+// Internal: Total units of an asset held by each user, escrow included
+pub fn holdings(asset_id: u64) -> RwaResult<BTreeMap<Principal, u64>> {
+    Ok(holdings_detail(asset_id)?.into_iter().map(|(holder, (free, escrowed))| (holder, free + escrowed)).collect())
 }
 
+// Issuer or admin: Issue `amount` units of new supply to `owner_id`, who must
+// be KYC approved; returns the ledger transaction index
 #[ic_cdk::update]
-pub fn mint_token(asset_id: u64, owner_id: Principal, amount: u64, price: u64) -> Token {
+pub fn mint_token(asset_id: u64, owner_id: Principal, amount: u64, price: u64) -> RwaResult<u64> {
     let caller = ic_cdk::caller();
-    if !is_kyc_approved(&caller) {
-        ic_cdk::trap("KYC not approved");
+    require_kyc(&owner_id)?;
+    if amount == 0 {
+        return Err(RwaError::validation("amount", "must be greater than zero"));
+    }
+    if !icrc::has_ledger(asset_id) {
+        return Err(RwaError::InvalidState(format!("Asset #{} has no token ledger; it must be approved first", asset_id)));
     }
-    let id = TOKEN_ID_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        let id = *c;
-        *c += 1;
-        id
-    });
-    let token = Token {
-        id,
-        asset_id,
-        owner_id,
-        amount,
-        price,
-        status: TokenStatus::Available,
-    };
-    TOKENS.with(|tokens| tokens.borrow_mut().insert(id, token.clone()));
-    token
+    let asset = asset::get_asset(asset_id)?;
+    compliance::check_transfer(&asset, None, owner_id, amount)?;
+    asset::issue_supply(asset_id, &caller, amount)?;
+    // Supply was reserved above, so a ledger failure here must roll it back
+    Ok(deliver(asset_id, owner_id, amount, Money::new(price, asset.currency()))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to mint tokens of asset #{}: {}", asset_id, e))))
 }
 
-#[ic_cdk::query]
-pub fn get_token(id: u64) -> Option<Token> {
-    TOKENS.with(|tokens| tokens.borrow().get(&id).cloned())
+// Internal: Mint units of supply already taken out of the asset's
+// available_tokens to `owner_id` on the asset ledger, bought at `price` each
+pub fn deliver(asset_id: u64, owner_id: Principal, amount: u64, price: Money) -> RwaResult<u64> {
+    let index = icrc::mint(asset_id, &Account::of(owner_id), amount, None)?;
+    portfolio::record_purchase(owner_id, asset_id, amount, price)?;
+    Ok(index)
 }
 
+// Move `amount` units of an asset from a holder's ledger balance to another
+// user, fee-free. Holders move their own units; support may move anyone's.
 #[ic_cdk::update]
-pub fn transfer_token(token_id: u64, new_owner: Principal) -> Option<Token> {
+pub fn transfer_token(asset_id: u64, from: Principal, to: Principal, amount: u64) -> RwaResult<u64> {
     let caller = ic_cdk::caller();
-    if !is_kyc_approved(&new_owner) {
-        ic_cdk::trap("Recipient KYC not approved");
+    if from != caller && !has_permission(&caller, Permission::MoveHoldings) {
+        return Err(RwaError::Unauthorized);
     }
-    TOKENS.with(|tokens| {
-        let mut tokens = tokens.borrow_mut();
-        if let Some(token) = tokens.get_mut(&token_id) {
-            if token.owner_id == caller || is_admin(&caller) {
-                token.owner_id = new_owner;
-                token.status = TokenStatus::Sold;
-                // Notify new owner
-                create_notification(new_owner, NotificationType::Investment, format!("You received token #{} for asset #{}", token.id, token.asset_id), ic_cdk::api::time().to_string());
-                return Some(token.clone());
-            }
-        }
-        None
-    })
+    if amount == 0 {
+        return Err(RwaError::validation("amount", "must be greater than zero"));
+    }
+    if from == to {
+        return Err(RwaError::validation("to", "sender and receiver must differ"));
+    }
+    compliance::check_transfer(&asset::get_asset(asset_id)?, Some(from), to, amount)?;
+    let index = icrc::move_balance(asset_id, &Account::of(from), &Account::of(to), amount, None)?;
+    // The units have moved, so a failure here must roll the transfer back
+    portfolio::record_transfer(from, to, asset_id, amount)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to book transfer of asset #{}: {}", asset_id, e)));
+    create_notification(to, NotificationType::Investment, format!("You received {} tokens of asset #{}", amount, asset_id));
+    Ok(index)
+}
+
+fn holdings_of(asset_id: u64) -> RwaResult<Vec<Holding>> {
+    let holdings = holdings_detail(asset_id)?.into_iter().map(|(owner_id, (available, escrowed))| Holding { asset_id, owner_id, available, escrowed });
+    Ok(holdings.filter(|h| h.available + h.escrowed > 0).collect())
 }
 
 #[ic_cdk::query]
-pub fn list_tokens() -> Vec<Token> {
-    TOKENS.with(|tokens| tokens.borrow().values().cloned().collect())
+pub fn list_tokens() -> RwaResult<Vec<Holding>> {
+    let mut holdings = Vec::new();
+    for asset_id in icrc::ledger_ids() {
+        holdings.extend(holdings_of(asset_id)?);
+    }
+    Ok(holdings)
 }
 
 #[ic_cdk::query]
-pub fn list_tokens_by_user(user_id: Principal) -> Vec<Token> {
-    TOKENS.with(|tokens| tokens.borrow().values().filter(|t| t.owner_id == user_id).cloned().collect())
+pub fn list_tokens_by_user(user_id: Principal) -> RwaResult<Vec<Holding>> {
+    Ok(list_tokens()?.into_iter().filter(|h| h.owner_id == user_id).collect())
 }
 
 #[ic_cdk::query]
-pub fn list_tokens_by_asset(asset_id: u64) -> Vec<Token> {
-    TOKENS.with(|tokens| tokens.borrow().values().filter(|t| t.asset_id == asset_id).cloned().collect())
-} 
\ No newline at end of file
+pub fn list_tokens_by_asset(asset_id: u64) -> RwaResult<Vec<Holding>> {
+    holdings_of(asset_id)
+}
@@ -1,7 +1,18 @@
 use candid::Principal;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use crate::user::{is_admin, is_kyc_approved};
+use std::collections::{HashMap, HashSet};
+use crate::access::{Permission, has_permission, require_permission};
+use crate::asset::{self, AssetStatus};
+use crate::cash;
+use crate::compliance;
+use crate::error::{RwaError, RwaResult};
+use crate::fx;
+use crate::icrc::{self, Account, ESCROW_TRADE};
+use crate::money::{Currency, Money};
+use crate::payment::{self, PaymentLedger};
+use crate::portfolio;
+use crate::scheduler::{self, Job};
+use crate::user::require_kyc;
 use crate::notification::{create_notification, NotificationType};
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
@@ -9,14 +20,35 @@ pub struct Trade {
     pub id: u64,
     pub buyer_id: Principal,
     pub seller_id: Principal,
-    pub token_id: u64,
     pub asset_id: u64,
     pub quantity: u64,
-    pub price: u64,
-    pub currency: Currency,
+    // Per token, in the currency the trade settles in
+    pub price: Money,
     pub status: TradeStatus,
-    pub created_at: String,
+    pub created_at: u64,
     pub filled: u64,
+    // Set for trades produced by the order book
+    pub buy_order_id: Option<u64>,
+    pub sell_order_id: Option<u64>,
+    // Delivery-versus-payment escrow of a bilateral trade
+    pub escrow: Option<TradeEscrow>,
+    pub expires_at: Option<u64>,
+}
+
+impl Trade {
+    pub fn currency(&self) -> Currency {
+        self.price.currency
+    }
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, PartialEq)]
+pub struct TradeEscrow {
+    // Seller's units moved into the trade's escrow account
+    pub asset_locked: bool,
+    // Buyer's payment reserved in their cash balance, or held on
+    // `payment_ledger` in the trade's escrow subaccount
+    pub payment_locked: bool,
+    pub payment_ledger: Option<PaymentLedger>,
 }
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
@@ -24,97 +56,435 @@ pub enum TradeStatus {
     Pending,
     Completed,
     Cancelled,
+    Expired,
 }
 
-#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
-pub enum Currency {
-    ICP,
-    USD,
-    INR,
-}
+// How long a bilateral trade waits for both legs before it expires
+const TRADE_TTL_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
 
 thread_local! {
     static TRADES: RefCell<HashMap<u64, Trade>> = RefCell::new(HashMap::new());
-    static TRADE_ID_COUNTER: RefCell<u64> = RefCell::new(1);
+    static TRADE_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+    // Trades with a ledger call in flight; other changes wait until it returns
+    static TRADES_IN_FLIGHT: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+}
+
+// Marks a trade busy for the duration of an inter-canister call
+struct TradeGuard(u64);
+
+impl TradeGuard {
+    fn acquire(id: u64) -> RwaResult<Self> {
+        TRADES_IN_FLIGHT.with(|in_flight| {
+            if in_flight.borrow_mut().insert(id) {
+                Ok(TradeGuard(id))
+            } else {
+                Err(RwaError::InvalidState(format!("Trade #{} has a payment in progress", id)))
+            }
+        })
+    }
+}
+
+impl Drop for TradeGuard {
+    fn drop(&mut self) {
+        TRADES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
+}
+
+fn ensure_idle(id: u64) -> RwaResult<()> {
+    if TRADES_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&id)) {
+        return Err(RwaError::InvalidState(format!("Trade #{} has a payment in progress", id)));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct TradeState {
+    pub trades: HashMap<u64, Trade>,
+    pub next_id: u64,
+}
+
+pub fn take_state() -> TradeState {
+    TradeState {
+        trades: TRADES.with(|trades| std::mem::take(&mut *trades.borrow_mut())),
+        next_id: TRADE_ID_COUNTER.with(|counter| *counter.borrow()),
+    }
+}
+
+pub fn restore_state(state: TradeState) {
+    TRADES.with(|trades| *trades.borrow_mut() = state.trades);
+    TRADE_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id);
+}
+
+fn next_trade_id() -> u64 {
+    TRADE_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    })
+}
+
+// Internal: Record a trade that settled immediately, such as an order book fill
+pub fn record_settled_trade(
+    buyer_id: Principal,
+    seller_id: Principal,
+    asset_id: u64,
+    quantity: u64,
+    price: Money,
+    buy_order_id: u64,
+    sell_order_id: u64,
+) -> Trade {
+    let trade = Trade {
+        id: next_trade_id(),
+        buyer_id,
+        seller_id,
+        asset_id,
+        quantity,
+        price,
+        status: TradeStatus::Completed,
+        created_at: ic_cdk::api::time(),
+        filled: quantity,
+        buy_order_id: Some(buy_order_id),
+        sell_order_id: Some(sell_order_id),
+        escrow: None,
+        expires_at: None,
+    };
+    TRADES.with(|trades| trades.borrow_mut().insert(trade.id, trade.clone()));
+    trade
 }
 
 #[ic_cdk::update]
 pub fn create_trade(
     buyer_id: Principal,
     seller_id: Principal,
-    token_id: u64,
     asset_id: u64,
     quantity: u64,
-    price: u64,
-    currency: Currency,
-    created_at: String,
-) -> Trade {
+    price: Money,
+) -> RwaResult<Trade> {
     let caller = ic_cdk::caller();
-    if !is_kyc_approved(&buyer_id) || !is_kyc_approved(&seller_id) {
-        ic_cdk::trap("KYC not approved for buyer or seller");
+    if caller != buyer_id && caller != seller_id && !has_permission(&caller, Permission::MoveHoldings) {
+        return Err(RwaError::Unauthorized);
     }
-    let id = TRADE_ID_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        let id = *c;
-        *c += 1;
-        id
-    });
-    let created_at_clone = created_at.clone();
-    let trade = Trade {
+    let asset = asset::get_asset(asset_id)?;
+    asset.check_tradable()?;
+    require_kyc(&seller_id)?;
+    if buyer_id == seller_id {
+        return Err(RwaError::validation("seller_id", "buyer and seller must differ"));
+    }
+    if quantity == 0 {
+        return Err(RwaError::validation("quantity", "must be greater than zero"));
+    }
+    compliance::check_transfer(&asset, Some(seller_id), buyer_id, quantity)?;
+    let currency = price.currency;
+    let notional = price.checked_mul(quantity)?.amount;
+    // A freshness gate only: the trade settles in its own currency, and just
+    // the portfolio restates it in the asset's, at the last rate
+    fx::require_fresh_rate(currency, asset.currency())?;
+    let id = next_trade_id();
+    let now = ic_cdk::api::time();
+    let mut trade = Trade {
         id,
         buyer_id,
         seller_id,
-        token_id,
         asset_id,
         quantity,
         price,
-        currency,
         status: TradeStatus::Pending,
-        created_at,
+        created_at: now,
         filled: 0,
+        buy_order_id: None,
+        sell_order_id: None,
+        escrow: Some(TradeEscrow::default()),
+        expires_at: Some(now + TRADE_TTL_NANOS),
     };
+    // The creating party delivers its own leg right away, except a payment
+    // settled on a ledger, which the buyer sends with fund_trade
+    if caller == seller_id {
+        lock_asset_leg(&mut trade)?;
+    } else if caller == buyer_id && payment::ledger_for(currency).is_none() {
+        cash::reserve(buyer_id, currency, notional, format!("Trade #{}", id))?;
+        set_escrow(&mut trade, |escrow| escrow.payment_locked = true);
+    }
     TRADES.with(|trades| trades.borrow_mut().insert(id, trade.clone()));
+    if let Some(expires_at) = trade.expires_at {
+        scheduler::schedule(Job::ExpireTrade(id), expires_at);
+    }
     // Notify buyer and seller
-    create_notification(buyer_id, NotificationType::Trade, format!("Trade #{} created for token #{}", id, token_id), created_at_clone.clone());
-    create_notification(seller_id, NotificationType::Trade, format!("Trade #{} created for token #{}", id, token_id), created_at_clone);
-    trade
+    let message = format!("Trade #{} created for {} tokens of asset #{}", id, quantity, asset_id);
+    create_notification(buyer_id, NotificationType::Trade, message.clone());
+    create_notification(seller_id, NotificationType::Trade, message);
+    Ok(trade)
 }
 
-#[ic_cdk::query]
-pub fn get_trade(id: u64) -> Option<Trade> {
+// Internal: A trade by id, for callers that check access themselves
+pub fn get_trade_internal(id: u64) -> RwaResult<Trade> {
     TRADES.with(|trades| trades.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("Trade", id))
+}
+
+#[ic_cdk::query]
+pub fn get_trade(id: u64) -> RwaResult<Trade> {
+    let caller = ic_cdk::caller();
+    let trade = get_trade_internal(id)?;
+    if trade.buyer_id != caller && trade.seller_id != caller && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(trade)
 }
 
 #[ic_cdk::query]
-pub fn list_trades() -> Vec<Trade> {
-    TRADES.with(|trades| trades.borrow().values().cloned().collect())
+pub fn list_trades() -> RwaResult<Vec<Trade>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAccounts)?;
+    Ok(TRADES.with(|trades| trades.borrow().values().cloned().collect()))
 }
 
 #[ic_cdk::query]
-pub fn list_trades_by_user(user_id: Principal) -> Vec<Trade> {
-    TRADES.with(|trades| trades.borrow().values().filter(|t| t.buyer_id == user_id || t.seller_id == user_id).cloned().collect())
+pub fn list_trades_by_user(user_id: Principal) -> RwaResult<Vec<Trade>> {
+    let caller = ic_cdk::caller();
+    if caller != user_id && !has_permission(&caller, Permission::ViewAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(TRADES.with(|trades| trades.borrow().values().filter(|t| t.buyer_id == user_id || t.seller_id == user_id).cloned().collect()))
 }
 
 #[ic_cdk::query]
-pub fn list_trades_by_asset(asset_id: u64) -> Vec<Trade> {
-    TRADES.with(|trades| trades.borrow().values().filter(|t| t.asset_id == asset_id).cloned().collect())
+pub fn list_trades_by_asset(asset_id: u64) -> RwaResult<Vec<Trade>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewAccounts)?;
+    Ok(TRADES.with(|trades| trades.borrow().values().filter(|t| t.asset_id == asset_id).cloned().collect()))
+}
+
+fn set_escrow(trade: &mut Trade, f: impl FnOnce(&mut TradeEscrow)) {
+    f(trade.escrow.get_or_insert_with(TradeEscrow::default));
+}
+
+fn trade_notional(trade: &Trade) -> RwaResult<Money> {
+    trade.price.checked_mul(trade.quantity)
+}
+
+// Moves the seller's units from their ledger balance into the trade's escrow account
+fn lock_asset_leg(trade: &mut Trade) -> RwaResult<()> {
+    icrc::move_balance(trade.asset_id, &Account::of(trade.seller_id), &icrc::escrow_account(ESCROW_TRADE, trade.id), trade.quantity, None)?;
+    set_escrow(trade, |escrow| escrow.asset_locked = true);
+    Ok(())
+}
+
+// Returns every delivered leg to its owner
+fn release_legs(trade: &mut Trade) -> RwaResult<()> {
+    let escrow = trade.escrow.clone().unwrap_or_default();
+    if escrow.asset_locked {
+        icrc::move_balance(trade.asset_id, &icrc::escrow_account(ESCROW_TRADE, trade.id), &Account::of(trade.seller_id), trade.quantity, None)?;
+    }
+    if escrow.payment_locked {
+        let amount = trade_notional(trade)?.amount;
+        match escrow.payment_ledger {
+            Some(ledger) => payment::spawn_payout(ledger, Some(icrc::escrow_subaccount(ESCROW_TRADE, trade.id)), trade.buyer_id, amount, format!("Trade #{} refund", trade.id)),
+            None => cash::release(trade.buyer_id, trade.currency(), amount, format!("Trade #{} released", trade.id))?,
+        }
+    }
+    trade.escrow = Some(TradeEscrow::default());
+    Ok(())
+}
+
+// The asset's status, currency and transfer rules are checked again at
+// settlement, as any may have changed since the trade was created
+fn check_settlement(trade: &Trade) -> RwaResult<()> {
+    let asset = asset::get_asset(trade.asset_id)?;
+    asset.check_tradable()?;
+    // The portfolio books the fill in the asset's currency
+    fx::convert_at_last_rate(trade.price, asset.currency())?;
+    compliance::check_transfer(&asset, Some(trade.seller_id), trade.buyer_id, trade.quantity)
+}
+
+fn legs_delivered(trade: &Trade) -> bool {
+    trade.escrow.as_ref().is_some_and(|escrow| escrow.asset_locked && escrow.payment_locked)
+}
+
+// Swaps both escrowed legs. Callers check `legs_delivered` first.
+fn settle_legs(trade: &mut Trade) -> RwaResult<()> {
+    let amount = trade_notional(trade)?.amount;
+    icrc::move_balance(trade.asset_id, &icrc::escrow_account(ESCROW_TRADE, trade.id), &Account::of(trade.buyer_id), trade.quantity, None)?;
+    portfolio::record_sale(trade.seller_id, trade.asset_id, trade.quantity, trade.price)?;
+    portfolio::record_purchase(trade.buyer_id, trade.asset_id, trade.quantity, trade.price)?;
+    match trade.escrow.as_ref().and_then(|escrow| escrow.payment_ledger.clone()) {
+        Some(ledger) => payment::spawn_payout(ledger, Some(icrc::escrow_subaccount(ESCROW_TRADE, trade.id)), trade.seller_id, amount, format!("Trade #{} proceeds", trade.id)),
+        None => {
+            cash::debit_reserved(trade.buyer_id, trade.currency(), amount, format!("Trade #{} payment", trade.id))?;
+            cash::credit(trade.seller_id, trade.currency(), amount, format!("Trade #{} proceeds", trade.id))?;
+        }
+    }
+    trade.escrow = Some(TradeEscrow::default());
+    trade.filled = trade.quantity;
+    trade.status = TradeStatus::Completed;
+    Ok(())
+}
+
+fn notify_parties(trade: &Trade, message: String) {
+    create_notification(trade.buyer_id, NotificationType::Trade, message.clone());
+    create_notification(trade.seller_id, NotificationType::Trade, message);
+}
+
+fn save_trade(trade: &Trade) {
+    TRADES.with(|trades| trades.borrow_mut().insert(trade.id, trade.clone()));
+}
+
+// Internal: Release a pending trade's escrow once it is past its expiry
+pub fn expire_trade(id: u64) -> RwaResult<Trade> {
+    ensure_idle(id)?;
+    let mut trade = get_trade_internal(id)?;
+    if trade.status != TradeStatus::Pending {
+        return Err(RwaError::InvalidState(format!("Trade #{} is already {:?}", id, trade.status)));
+    }
+    // Any failure here means escrow no longer matches the trade; roll everything back
+    release_legs(&mut trade).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to release trade #{}: {}", id, e)));
+    trade.status = TradeStatus::Expired;
+    save_trade(&trade);
+    notify_parties(&trade, format!("Trade #{} expired and its escrow was released", id));
+    Ok(trade)
+}
+
+// Internal: Cancel every pending trade in an asset that is leaving the
+// market and hand back their escrow. Refuses, before changing anything, while
+// any of them has a payment in flight.
+pub fn cancel_asset_trades(asset_id: u64, status: &AssetStatus) -> RwaResult<()> {
+    let pending: Vec<Trade> = TRADES.with(|trades| {
+        trades.borrow().values().filter(|t| t.asset_id == asset_id && t.status == TradeStatus::Pending).cloned().collect()
+    });
+    for trade in &pending {
+        ensure_idle(trade.id)?;
+    }
+    for mut trade in pending {
+        release_legs(&mut trade).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to release trade #{}: {}", trade.id, e)));
+        trade.status = TradeStatus::Cancelled;
+        save_trade(&trade);
+        notify_parties(&trade, format!("Trade #{} was cancelled and its escrow released: asset #{} is now {:?}", trade.id, asset_id, status));
+    }
+    Ok(())
+}
+
+// Internal: Price of the most recent completed trade in an asset
+pub fn last_trade_price(asset_id: u64) -> Option<Money> {
+    TRADES.with(|trades| {
+        trades.borrow().values()
+            .filter(|t| t.asset_id == asset_id && t.status == TradeStatus::Completed)
+            .max_by_key(|t| t.id)
+            .map(|t| t.price)
+    })
+}
+
+// Internal: (id, expires_at) of every pending trade with an expiry, for the scheduler
+pub fn pending_trade_expiries() -> Vec<(u64, u64)> {
+    TRADES.with(|trades| {
+        trades.borrow().values()
+            .filter(|t| t.status == TradeStatus::Pending)
+            .filter_map(|t| t.expires_at.map(|expires_at| (t.id, expires_at)))
+            .collect()
+    })
+}
+
+fn is_expired(trade: &Trade) -> bool {
+    trade.expires_at.is_some_and(|expires_at| expires_at <= ic_cdk::api::time())
 }
 
+// Deliver the caller's leg of a pending trade: the seller's tokens or the
+// buyer's payment. Payments in a ledger-settled currency are pulled with
+// icrc2_transfer_from, so the buyer approves notional plus one ledger fee
+// first. The trade settles as soon as both legs are in escrow.
 #[ic_cdk::update]
-pub fn update_trade_status(id: u64, status: TradeStatus, filled: u64) -> Option<Trade> {
+pub async fn fund_trade(id: u64) -> RwaResult<Trade> {
     let caller = ic_cdk::caller();
-    TRADES.with(|trades| {
-        let mut trades = trades.borrow_mut();
-        if let Some(trade) = trades.get_mut(&id) {
-            if trade.buyer_id == caller || trade.seller_id == caller || is_admin(&caller) {
-                trade.status = status.clone();
-                trade.filled = filled;
-                // Notify both parties
-                create_notification(trade.buyer_id, NotificationType::Trade, format!("Trade #{} status updated to {:?}", id, status), ic_cdk::api::time().to_string());
-                create_notification(trade.seller_id, NotificationType::Trade, format!("Trade #{} status updated to {:?}", id, status), ic_cdk::api::time().to_string());
-                return Some(trade.clone());
+    let mut trade = get_trade_internal(id)?;
+    if trade.buyer_id != caller && trade.seller_id != caller {
+        return Err(RwaError::Unauthorized);
+    }
+    if trade.status != TradeStatus::Pending || trade.escrow.is_none() {
+        return Err(RwaError::InvalidState(format!("Trade #{} is {:?} and not escrowed", id, trade.status)));
+    }
+    if is_expired(&trade) {
+        expire_trade(id)?;
+        return Err(RwaError::InvalidState(format!("Trade #{} has expired", id)));
+    }
+    check_settlement(&trade)?;
+    let _guard = TradeGuard::acquire(id)?;
+    let escrow = trade.escrow.clone().unwrap_or_default();
+    if caller == trade.seller_id && !escrow.asset_locked {
+        lock_asset_leg(&mut trade)?;
+    } else if caller == trade.buyer_id && !escrow.payment_locked {
+        let amount = trade_notional(&trade)?;
+        match payment::ledger_for(trade.currency()) {
+            Some(ledger) => {
+                // The extra fee covers the payout from escrow to the seller or back to the buyer
+                let total = amount.checked_add(Money::new(ledger.fee, ledger.currency))?;
+                payment::collect(&ledger, caller, Some(icrc::escrow_subaccount(ESCROW_TRADE, id)), total.amount).await?;
+                // The guard kept this trade unchanged across the call
+                trade = get_trade_internal(id)?;
+                set_escrow(&mut trade, |escrow| {
+                    escrow.payment_locked = true;
+                    escrow.payment_ledger = Some(ledger);
+                });
+            }
+            None => {
+                cash::reserve(trade.buyer_id, trade.currency(), amount.amount, format!("Trade #{}", id))?;
+                set_escrow(&mut trade, |escrow| escrow.payment_locked = true);
             }
         }
-        None
-    })
-} 
\ No newline at end of file
+    } else {
+        return Err(RwaError::InvalidState(format!("Your leg of trade #{} is already delivered", id)));
+    }
+    if legs_delivered(&trade) {
+        match check_settlement(&trade) {
+            Ok(()) => {
+                settle_legs(&mut trade).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to settle trade #{}: {}", id, e)));
+                notify_parties(&trade, format!("Trade #{} completed: {} tokens of asset #{} delivered against payment", id, trade.quantity, trade.asset_id));
+            }
+            // Holdings moved while the payment was collected; both legs stay
+            // in escrow until the trade is completed or cancelled
+            Err(e) => notify_parties(&trade, format!("Trade #{} cannot settle yet: {}", id, e)),
+        }
+    } else {
+        notify_parties(&trade, format!("Trade #{}: {} delivered its leg", id, caller));
+    }
+    save_trade(&trade);
+    Ok(trade)
+}
+
+// Complete or cancel a pending trade. Completion swaps the escrowed legs and
+// is refused until both were delivered; cancellation releases them.
+#[ic_cdk::update]
+pub fn update_trade_status(id: u64, status: TradeStatus, filled: u64) -> RwaResult<Trade> {
+    let caller = ic_cdk::caller();
+    ensure_idle(id)?;
+    let mut trade = get_trade_internal(id)?;
+    if trade.buyer_id != caller && trade.seller_id != caller && !has_permission(&caller, Permission::ManageAccounts) {
+        return Err(RwaError::Unauthorized);
+    }
+    if trade.status != TradeStatus::Pending {
+        return Err(RwaError::InvalidState(format!("Trade #{} is already {:?}", id, trade.status)));
+    }
+    if is_expired(&trade) {
+        expire_trade(id)?;
+        return Err(RwaError::InvalidState(format!("Trade #{} has expired", id)));
+    }
+    match status {
+        TradeStatus::Completed => {
+            if filled != trade.quantity {
+                return Err(RwaError::validation("filled", "delivery-versus-payment trades fill in full"));
+            }
+            if !legs_delivered(&trade) {
+                return Err(RwaError::InvalidState(format!("Trade #{} cannot complete before both legs are delivered", id)));
+            }
+            check_settlement(&trade)?;
+            settle_legs(&mut trade).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to settle trade #{}: {}", id, e)));
+        }
+        TradeStatus::Cancelled => {
+            release_legs(&mut trade).unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to release trade #{}: {}", id, e)));
+            trade.status = TradeStatus::Cancelled;
+        }
+        TradeStatus::Pending | TradeStatus::Expired => {
+            return Err(RwaError::validation("status", "only Completed or Cancelled can be requested"));
+        }
+    }
+    save_trade(&trade);
+    // Notify both parties
+    notify_parties(&trade, format!("Trade #{} status updated to {:?}", id, trade.status));
+    Ok(trade)
+}
@@ -1,6 +1,7 @@
 use candid::Principal;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::Entry;
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
 pub struct User {
@@ -12,6 +13,8 @@ pub struct User {
     pub role: UserRole,
     pub profile: Option<UserProfile>,
     pub notifications: Vec<Notification>,
+    // Currency portfolio totals are reported in; USD when unset
+    pub reporting_currency: Option<Currency>,
 }
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
@@ -19,6 +22,8 @@ pub enum KycStatus {
     Pending,
     Approved,
     Rejected,
+    // Was approved; the approval ran out
+    Expired,
 }
 
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
@@ -33,119 +38,215 @@ pub struct UserProfile {
     pub avatar: Option<String>,
 }
 
+use crate::access::{self, Permission, RoleAction, Role, require_permission};
+use crate::error::{RwaError, RwaResult};
+use crate::kyc;
+use crate::money::Currency;
 use crate::notification::{Notification, create_notification, NotificationType};
 
 thread_local! {
     static USERS: RefCell<HashMap<Principal, User>> = RefCell::new(HashMap::new());
+    // Principals granted admin rights through init/upgrade arguments
+    static ADMINS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct UserState {
+    pub users: HashMap<Principal, User>,
+    pub admins: Option<BTreeSet<Principal>>,
+}
+
+pub fn take_state() -> UserState {
+    UserState {
+        users: USERS.with(|users| std::mem::take(&mut *users.borrow_mut())),
+        admins: Some(ADMINS.with(|admins| std::mem::take(&mut *admins.borrow_mut()))),
+    }
+}
+
+pub fn restore_state(state: UserState) {
+    USERS.with(|users| *users.borrow_mut() = state.users);
+    ADMINS.with(|admins| *admins.borrow_mut() = state.admins.unwrap_or_default());
+}
+
+// Grant admin rights to principals passed in init/upgrade arguments
+pub fn add_admins(principals: Vec<Principal>) {
+    ADMINS.with(|admins| admins.borrow_mut().extend(principals));
 }
 
 // Register a new user
 #[ic_cdk::update]
-pub fn register_user(username: String, email: String, wallet_address: String) -> Option<User> {
+pub fn register_user(username: String, email: String, wallet_address: String) -> RwaResult<User> {
     let caller = ic_cdk::caller();
+    if username.trim().is_empty() {
+        return Err(RwaError::validation("username", "must not be empty"));
+    }
     let user = User {
         id: caller,
-        username: username.clone(),
-        email: email.clone(),
+        username,
+        email,
         wallet_address,
         kyc_status: KycStatus::Pending,
         role: UserRole::User,
         profile: None,
         notifications: vec![],
+        reporting_currency: None,
     };
     USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        if users.contains_key(&caller) {
-            None
-        } else {
-            users.insert(caller, user.clone());
-            Some(user)
+        match users.borrow_mut().entry(caller) {
+            Entry::Occupied(_) => Err(RwaError::AlreadyExists(format!("User {}", caller))),
+            Entry::Vacant(entry) => {
+                entry.insert(user.clone());
+                Ok(user)
+            }
         }
     })
 }
 
 // Get user by principal
 #[ic_cdk::query]
-pub fn get_user(principal: Principal) -> Option<User> {
+pub fn get_user(principal: Principal) -> RwaResult<User> {
     USERS.with(|users| users.borrow().get(&principal).cloned())
+        .ok_or_else(|| RwaError::not_found("User", principal))
 }
 
 // Update user profile
 #[ic_cdk::update]
-pub fn update_profile(bio: Option<String>, avatar: Option<String>) -> Option<User> {
+pub fn update_profile(bio: Option<String>, avatar: Option<String>) -> RwaResult<User> {
     let caller = ic_cdk::caller();
     USERS.with(|users| {
         let mut users = users.borrow_mut();
-        if let Some(user) = users.get_mut(&caller) {
-            user.profile = Some(UserProfile { bio, avatar });
-            return Some(user.clone());
-        } else {
-            None
-        }
+        let user = users.get_mut(&caller).ok_or_else(|| RwaError::not_found("User", caller))?;
+        user.profile = Some(UserProfile { bio, avatar });
+        Ok(user.clone())
     })
 }
 
-// Set KYC status (admin only)
+// Choose the currency portfolio totals and history are reported in
 #[ic_cdk::update]
-pub fn set_kyc_status(user_id: Principal, status: KycStatus) -> Option<User> {
+pub fn set_reporting_currency(currency: Currency) -> RwaResult<User> {
     let caller = ic_cdk::caller();
-    if !is_admin(&caller) {
-        return None;
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let user = users.get_mut(&caller).ok_or_else(|| RwaError::not_found("User", caller))?;
+        user.reporting_currency = Some(currency);
+        Ok(user.clone())
+    })
+}
+
+// Internal: Currency a user's portfolio is reported in
+pub fn reporting_currency(user_id: &Principal) -> Currency {
+    USERS.with(|users| users.borrow().get(user_id).and_then(|u| u.reporting_currency))
+        .unwrap_or(Currency::USD)
+}
+
+// Compliance: Set KYC status. Approval needs the details taken by approve_kyc.
+#[ic_cdk::update]
+pub fn set_kyc_status(user_id: Principal, status: KycStatus) -> RwaResult<User> {
+    require_permission(&ic_cdk::caller(), Permission::ReviewKyc)?;
+    if status == KycStatus::Approved {
+        return Err(RwaError::validation("status", "approve with approve_kyc"));
     }
+    let user = set_kyc_status_internal(user_id, status.clone())?;
+    // Notify user
+    create_notification(user_id, NotificationType::Kyc, format!("Your KYC status changed to {:?}", status));
+    Ok(user)
+}
+
+// Internal: Set KYC status without notifying the user
+pub fn set_kyc_status_internal(user_id: Principal, status: KycStatus) -> RwaResult<User> {
     USERS.with(|users| {
         let mut users = users.borrow_mut();
-        if let Some(user) = users.get_mut(&user_id) {
-            user.kyc_status = status.clone();
-            // Notify user
-            create_notification(user_id, NotificationType::Kyc, format!("Your KYC status changed to {:?}", status), ic_cdk::api::time().to_string());
-            return Some(user.clone());
-        }
-        None
+        let user = users.get_mut(&user_id).ok_or_else(|| RwaError::not_found("User", user_id))?;
+        user.kyc_status = status;
+        Ok(user.clone())
     })
 }
 
-// List all users (admin only)
+// Internal: Users whose KYC is in `status`
+pub fn users_with_kyc_status(status: KycStatus) -> Vec<Principal> {
+    USERS.with(|users| users.borrow().values().filter(|u| u.kyc_status == status).map(|u| u.id).collect())
+}
+
 #[ic_cdk::query]
-pub fn list_users() -> Vec<User> {
-    let caller = ic_cdk::caller();
-    if !is_admin(&caller) {
-        return vec![];
+pub fn list_users() -> RwaResult<Vec<User>> {
+    require_permission(&ic_cdk::caller(), Permission::ViewUsers)?;
+    Ok(USERS.with(|users| users.borrow().values().cloned().collect()))
+}
+
+// Helper: Check if caller is an admin by the rules that predate roles. Canister
+// controllers and bootstrap admins always qualify so a fresh deployment can
+// grant its first roles. Access checks go through `access` instead.
+pub fn is_legacy_admin(caller: &Principal) -> bool {
+    if is_permanent_admin(caller) {
+        return true;
     }
     USERS.with(|users| {
-        let users = users.borrow();
-        users.values().cloned().collect()
+        users.borrow().get(caller).is_some_and(|u| u.role == UserRole::Admin)
     })
 }
 
-// Helper: Check if caller is admin
-pub fn is_admin(caller: &Principal) -> bool {
+// Helper: Check if `principal` is a controller or bootstrap admin, who hold
+// SuperAdmin regardless of role grants
+pub fn is_permanent_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal) || ADMINS.with(|admins| admins.borrow().contains(principal))
+}
+
+// Internal: Drop the legacy Admin role when SuperAdmin is revoked. Returns
+// false if the user did not have it.
+pub fn demote_legacy_admin(user_id: &Principal) -> bool {
     USERS.with(|users| {
-        users.borrow().get(caller).map_or(false, |u| u.role == UserRole::Admin)
+        let mut users = users.borrow_mut();
+        match users.get_mut(user_id) {
+            Some(user) if user.role == UserRole::Admin => {
+                user.role = UserRole::User;
+                true
+            }
+            _ => false,
+        }
     })
 }
 
-// Helper: Check if user is KYC approved
+// Helper: Check if user is KYC approved and the approval has not run out
 pub fn is_kyc_approved(principal: &Principal) -> bool {
-    USERS.with(|users| {
-        users.borrow().get(principal).map_or(false, |u| u.kyc_status == KycStatus::Approved)
-    })
+    let approved = USERS.with(|users| {
+        users.borrow().get(principal).is_some_and(|u| u.kyc_status == KycStatus::Approved)
+    });
+    approved && !kyc::has_lapsed(principal, ic_cdk::api::time())
+}
+
+// Helper: Fail with KycRequired unless user is KYC approved
+pub fn require_kyc(principal: &Principal) -> RwaResult<()> {
+    if is_kyc_approved(principal) { Ok(()) } else { Err(RwaError::KycRequired) }
 }
 
-// Admin: Change user role (moderation)
+// List principals holding bootstrap admin rights
+#[ic_cdk::query]
+pub fn list_admins() -> RwaResult<Vec<Principal>> {
+    require_permission(&ic_cdk::caller(), Permission::ManageRoles)?;
+    Ok(ADMINS.with(|admins| admins.borrow().iter().cloned().collect()))
+}
+
+// Admin: Change user role (moderation). Admin is SuperAdmin under RBAC, so
+// the change is logged as a grant or revocation of that role.
 #[ic_cdk::update]
-pub fn set_user_role(user_id: Principal, role: UserRole) -> Option<User> {
+pub fn set_user_role(user_id: Principal, role: UserRole) -> RwaResult<User> {
     let caller = ic_cdk::caller();
-    if !is_admin(&caller) {
-        return None;
+    require_permission(&caller, Permission::ManageRoles)?;
+    // Revoking would be logged but could not take effect
+    if role != UserRole::Admin && is_permanent_admin(&user_id) {
+        return Err(RwaError::InvalidState(format!("{} is a controller or bootstrap admin and always holds SuperAdmin", user_id)));
     }
     USERS.with(|users| {
         let mut users = users.borrow_mut();
-        if let Some(user) = users.get_mut(&user_id) {
-            user.role = role.clone();
-            // Notify user
-            create_notification(user_id, NotificationType::Admin, format!("Your role changed to {:?}", role), ic_cdk::api::time().to_string());
-            return Some(user.clone());
+        let user = users.get_mut(&user_id).ok_or_else(|| RwaError::not_found("User", user_id))?;
+        let action = if role == UserRole::Admin { RoleAction::Granted } else { RoleAction::Revoked };
+        let granted = access::apply_change(user_id, Role::SuperAdmin, &action);
+        if granted || user.role != role {
+            access::log_change(user_id, Role::SuperAdmin, action, caller, Some(format!("Set user role to {:?}", role)));
         }
-        None
+        user.role = role.clone();
+        // Notify user
+        create_notification(user_id, NotificationType::Admin, format!("Your role changed to {:?}", role));
+        Ok(user.clone())
     })
-} 
\ No newline at end of file
+}
@@ -0,0 +1,302 @@
+// Independent valuations of assets. Principals with the Appraiser role submit
+// appraisals backed by a report, a reviewer accepts or rejects them, and the latest
+// accepted one becomes the asset's value. Every appraisal is kept so past
+// values can be audited.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use crate::access::{self, Permission, Role, RoleAction, has_permission, require_permission};
+use crate::asset::{self, Asset, NANOS_PER_DAY};
+use crate::error::{RwaError, RwaResult};
+use crate::money::Money;
+use crate::token;
+use crate::notification::{create_notification, NotificationType};
+
+// Valuations older than this are flagged stale unless changed by an admin
+const DEFAULT_MAX_AGE_NANOS: u64 = 365 * NANOS_PER_DAY;
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum ValuationMethod {
+    SalesComparison,
+    Income,
+    Cost,
+    Other(String),
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, PartialEq)]
+pub enum AppraisalStatus {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct Appraisal {
+    pub id: u64,
+    pub asset_id: u64,
+    // The principal that signed the submission
+    pub appraiser: Principal,
+    // Value of the whole asset, in its currency
+    pub value: Money,
+    // Date the asset was valued at, which may precede submission
+    pub valuation_date: u64,
+    pub method: ValuationMethod,
+    // Hex SHA-256 of the appraisal report
+    pub report_hash: String,
+    pub status: AppraisalStatus,
+    pub reviewer_id: Option<Principal>,
+    pub review_note: Option<String>,
+    pub submitted_at: u64,
+    pub reviewed_at: Option<u64>,
+}
+
+// An asset's current value as the views see it
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize)]
+pub struct AssetValuation {
+    pub asset_id: u64,
+    // Latest accepted appraisal; None while only the issuer's figure exists
+    pub appraisal: Option<Appraisal>,
+    pub value: Money,
+    pub nav_per_token: Money,
+    // No appraisal yet, or the latest is older than the configured age
+    pub stale: bool,
+}
+
+thread_local! {
+    static APPRAISALS: RefCell<HashMap<u64, Appraisal>> = RefCell::new(HashMap::new());
+    static APPRAISAL_ID_COUNTER: RefCell<u64> = const { RefCell::new(1) };
+    static MAX_AGE_NANOS: RefCell<u64> = const { RefCell::new(DEFAULT_MAX_AGE_NANOS) };
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize)]
+pub struct ValuationState {
+    pub appraisals: HashMap<u64, Appraisal>,
+    pub next_id: u64,
+    // Appraisers whitelisted before roles existed; granted the Appraiser role
+    // on restore
+    pub appraisers: Option<BTreeSet<Principal>>,
+    pub max_age_nanos: Option<u64>,
+}
+
+pub fn take_state() -> ValuationState {
+    ValuationState {
+        appraisals: APPRAISALS.with(|appraisals| std::mem::take(&mut *appraisals.borrow_mut())),
+        next_id: APPRAISAL_ID_COUNTER.with(|counter| *counter.borrow()),
+        appraisers: None,
+        max_age_nanos: Some(MAX_AGE_NANOS.with(|max_age| *max_age.borrow())),
+    }
+}
+
+pub fn restore_state(state: ValuationState) {
+    APPRAISALS.with(|appraisals| *appraisals.borrow_mut() = state.appraisals);
+    APPRAISAL_ID_COUNTER.with(|counter| *counter.borrow_mut() = state.next_id.max(1));
+    // Runs after access::restore_state
+    for appraiser in state.appraisers.unwrap_or_default() {
+        access::record_change(appraiser, Role::Appraiser, RoleAction::Granted, ic_cdk::api::id(), Some("Migrated from the appraiser whitelist".to_string()));
+    }
+    MAX_AGE_NANOS.with(|max_age| *max_age.borrow_mut() = state.max_age_nanos.unwrap_or(DEFAULT_MAX_AGE_NANOS));
+}
+
+fn save_appraisal(appraisal: &Appraisal) {
+    APPRAISALS.with(|appraisals| appraisals.borrow_mut().insert(appraisal.id, appraisal.clone()));
+}
+
+// Helper: Check for a hex SHA-256 digest, as used for document hashes
+pub fn is_sha256_hex(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Internal: The accepted appraisal with the latest valuation date
+pub fn latest_accepted(asset_id: u64) -> Option<Appraisal> {
+    APPRAISALS.with(|appraisals| {
+        appraisals.borrow().values()
+            .filter(|a| a.asset_id == asset_id && a.status == AppraisalStatus::Accepted)
+            .max_by_key(|a| (a.valuation_date, a.id))
+            .cloned()
+    })
+}
+
+fn valuation_of(asset: &Asset) -> AssetValuation {
+    let appraisal = latest_accepted(asset.id);
+    let value = appraisal.as_ref().map_or(asset.total_value, |a| a.value);
+    let max_age = MAX_AGE_NANOS.with(|max_age| *max_age.borrow());
+    let stale = appraisal.as_ref()
+        .is_none_or(|a| ic_cdk::api::time().saturating_sub(a.valuation_date) > max_age);
+    AssetValuation {
+        asset_id: asset.id,
+        appraisal,
+        value,
+        // total_tokens is never zero; rounded down to the minor unit
+        nav_per_token: Money::new(value.amount / asset.total_tokens.max(1), value.currency),
+        stale,
+    }
+}
+
+// Internal: Value of one token by the latest accepted appraisal, if any
+pub fn appraised_nav(asset: &Asset) -> Option<Money> {
+    latest_accepted(asset.id).map(|_| valuation_of(asset).nav_per_token)
+}
+
+// Appraiser: Submit a valuation of an asset for review
+#[ic_cdk::update]
+pub fn submit_appraisal(asset_id: u64, value: Money, valuation_date: u64, method: ValuationMethod, report_hash: String) -> RwaResult<Appraisal> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::SubmitAppraisals)?;
+    let asset = asset::get_asset(asset_id)?;
+    // Issuers may not appraise their own assets
+    if asset.owner_id == caller {
+        return Err(RwaError::Unauthorized);
+    }
+    if asset.is_closed() {
+        return Err(RwaError::InvalidState(format!("Asset #{} is {:?} and can no longer be valued", asset_id, asset.status)));
+    }
+    value.expect_currency(asset.currency())?;
+    if value.is_zero() {
+        return Err(RwaError::validation("value", "must be greater than zero"));
+    }
+    let now = ic_cdk::api::time();
+    if valuation_date > now {
+        return Err(RwaError::validation("valuation_date", "must not be in the future"));
+    }
+    if let ValuationMethod::Other(name) = &method {
+        if name.trim().is_empty() {
+            return Err(RwaError::validation("method", "must be named"));
+        }
+    }
+    if !is_sha256_hex(&report_hash) {
+        return Err(RwaError::validation("report_hash", "must be a hex SHA-256 digest"));
+    }
+    let id = APPRAISAL_ID_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        let id = *c;
+        *c += 1;
+        id
+    });
+    let appraisal = Appraisal {
+        id,
+        asset_id,
+        appraiser: caller,
+        value,
+        valuation_date,
+        method,
+        report_hash: report_hash.to_ascii_lowercase(),
+        status: AppraisalStatus::Pending,
+        reviewer_id: None,
+        review_note: None,
+        submitted_at: now,
+        reviewed_at: None,
+    };
+    save_appraisal(&appraisal);
+    Ok(appraisal)
+}
+
+// Reviewer: Accept a pending appraisal as the asset's value and tell its holders.
+// Refused if a later valuation has already been accepted.
+#[ic_cdk::update]
+pub fn accept_appraisal(id: u64, note: Option<String>) -> RwaResult<Appraisal> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewAppraisals)?;
+    let mut appraisal = get_appraisal(id)?;
+    if appraisal.status != AppraisalStatus::Pending {
+        return Err(RwaError::InvalidState(format!("Appraisal #{} is already {:?}", id, appraisal.status)));
+    }
+    if let Some(current) = latest_accepted(appraisal.asset_id).filter(|a| a.valuation_date >= appraisal.valuation_date) {
+        return Err(RwaError::InvalidState(format!("Appraisal #{} of asset #{} is as recent or more", current.id, appraisal.asset_id)));
+    }
+    let asset = asset::revalue(appraisal.asset_id, appraisal.value, appraisal.valuation_date)?;
+    appraisal.status = AppraisalStatus::Accepted;
+    appraisal.reviewer_id = Some(caller);
+    appraisal.review_note = note;
+    appraisal.reviewed_at = Some(ic_cdk::api::time());
+    save_appraisal(&appraisal);
+    let mut recipients: Vec<Principal> = token::holdings(asset.id)?.into_keys().collect();
+    if !recipients.contains(&asset.owner_id) {
+        recipients.push(asset.owner_id);
+    }
+    let message = format!("'{}' was appraised at {} ({} per token)", asset.name, appraisal.value, valuation_of(&asset).nav_per_token);
+    for user in recipients {
+        create_notification(user, NotificationType::Investment, message.clone());
+    }
+    Ok(appraisal)
+}
+
+// Reviewer: Turn down a pending appraisal
+#[ic_cdk::update]
+pub fn reject_appraisal(id: u64, note: String) -> RwaResult<Appraisal> {
+    let caller = ic_cdk::caller();
+    require_permission(&caller, Permission::ReviewAppraisals)?;
+    if note.trim().is_empty() {
+        return Err(RwaError::validation("note", "must not be empty"));
+    }
+    let mut appraisal = get_appraisal(id)?;
+    if appraisal.status != AppraisalStatus::Pending {
+        return Err(RwaError::InvalidState(format!("Appraisal #{} is already {:?}", id, appraisal.status)));
+    }
+    appraisal.status = AppraisalStatus::Rejected;
+    appraisal.reviewer_id = Some(caller);
+    appraisal.review_note = Some(note.clone());
+    appraisal.reviewed_at = Some(ic_cdk::api::time());
+    save_appraisal(&appraisal);
+    create_notification(appraisal.appraiser, NotificationType::Admin, format!("Appraisal #{} of asset #{} was rejected: {}", id, appraisal.asset_id, note));
+    Ok(appraisal)
+}
+
+#[ic_cdk::query]
+pub fn get_appraisal(id: u64) -> RwaResult<Appraisal> {
+    APPRAISALS.with(|appraisals| appraisals.borrow().get(&id).cloned())
+        .ok_or_else(|| RwaError::not_found("Appraisal", id))
+}
+
+// Every appraisal of an asset, oldest valuation first
+#[ic_cdk::query]
+pub fn list_appraisals_by_asset(asset_id: u64) -> Vec<Appraisal> {
+    let mut history: Vec<Appraisal> = APPRAISALS.with(|appraisals| {
+        appraisals.borrow().values().filter(|a| a.asset_id == asset_id).cloned().collect()
+    });
+    history.sort_by_key(|a| (a.valuation_date, a.id));
+    history
+}
+
+// Reviewer or appraiser: Appraisals waiting for review
+#[ic_cdk::query]
+pub fn list_pending_appraisals() -> RwaResult<Vec<Appraisal>> {
+    let caller = ic_cdk::caller();
+    if !has_permission(&caller, Permission::ReviewAppraisals) && !has_permission(&caller, Permission::SubmitAppraisals) {
+        return Err(RwaError::Unauthorized);
+    }
+    Ok(APPRAISALS.with(|appraisals| {
+        appraisals.borrow().values().filter(|a| a.status == AppraisalStatus::Pending).cloned().collect()
+    }))
+}
+
+#[ic_cdk::query]
+pub fn get_asset_valuation(asset_id: u64) -> RwaResult<AssetValuation> {
+    Ok(valuation_of(&asset::get_asset(asset_id)?))
+}
+
+// Assets still carrying tokens whose valuation is missing or out of date
+#[ic_cdk::query]
+pub fn list_stale_valuations() -> Vec<AssetValuation> {
+    asset::list_assets().iter()
+        .filter(|asset| asset.is_issuable())
+        .map(valuation_of)
+        .filter(|v| v.stale)
+        .collect()
+}
+
+// Admin: How old a valuation may get before it is flagged stale, in nanoseconds
+#[ic_cdk::update]
+pub fn set_appraisal_max_age(max_age_nanos: u64) -> RwaResult<u64> {
+    require_permission(&ic_cdk::caller(), Permission::ConfigurePlatform)?;
+    if max_age_nanos == 0 {
+        return Err(RwaError::validation("max_age_nanos", "must be greater than zero"));
+    }
+    MAX_AGE_NANOS.with(|max_age| *max_age.borrow_mut() = max_age_nanos);
+    Ok(max_age_nanos)
+}
+
+#[ic_cdk::query]
+pub fn get_appraisal_max_age() -> u64 {
+    MAX_AGE_NANOS.with(|max_age| *max_age.borrow())
+}
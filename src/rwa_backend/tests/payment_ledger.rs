@@ -0,0 +1,358 @@
+// Payment leg against a real ICRC-1/ICRC-2 ledger on PocketIC: trade funds are
+// collected into escrow subaccounts, paid out to the seller or refunded to the
+// buyer, and cash is deposited and withdrawn, with a failed withdrawal credited
+// back. Needs the backend and ledger wasm modules, so the tests are ignored by
+// default; scripts/integration-tests.sh builds and fetches both and runs them.
+
+use candid::types::value::IDLValue;
+use candid::{encode_one, CandidType, Deserialize, Nat, Principal, Reserved};
+use pocket_ic::{query_candid_as, update_candid_as, PocketIc};
+
+const LEDGER_FEE: u64 = 10_000;
+const INITIAL_BALANCE: u64 = 100_000_000_000;
+// One RWA token costs 1 ICP
+const TOKEN_PRICE: u64 = 100_000_000;
+const ESCROW_TRADE: u8 = 2;
+const NANOS_PER_YEAR: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum Currency {
+    ICP,
+    USD,
+    INR,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+struct Money {
+    amount: u64,
+    currency: Currency,
+}
+
+fn icp(amount: u64) -> Money {
+    Money { amount, currency: Currency::ICP }
+}
+
+#[derive(CandidType)]
+struct InitArgs {
+    admins: Vec<Principal>,
+}
+
+#[derive(CandidType)]
+#[allow(dead_code)]
+enum KycTier {
+    Basic,
+    Standard,
+    Enhanced,
+}
+
+#[derive(CandidType)]
+struct KycApproval {
+    tier: KycTier,
+    country: String,
+    accredited: bool,
+    provider_ref: Option<String>,
+    expires_at: u64,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+struct Asset {
+    id: u64,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+enum TradeStatus {
+    Pending,
+    Completed,
+    Cancelled,
+    Expired,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+struct TradeEscrow {
+    asset_locked: bool,
+    payment_locked: bool,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+struct Trade {
+    id: u64,
+    status: TradeStatus,
+    escrow: Option<TradeEscrow>,
+}
+
+#[derive(Debug, CandidType, Deserialize, PartialEq)]
+struct CashBalance {
+    available: u64,
+    reserved: u64,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+struct CashEntry {
+    amount: u64,
+    memo: String,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+}
+
+impl Account {
+    fn of(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+}
+
+#[derive(CandidType)]
+struct ApproveArgs {
+    from_subaccount: Option<[u8; 32]>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Init argument of the ICRC-1 ledger (ic-icrc1-ledger), fields this test sets
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(LedgerInitArgs),
+}
+
+#[derive(CandidType)]
+struct LedgerInitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, Reserved)>,
+    initial_balances: Vec<(Account, Nat)>,
+    feature_flags: Option<FeatureFlags>,
+    archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+type Reply<T> = Result<T, IDLValue>;
+
+fn wasm(var: &str) -> Vec<u8> {
+    let path = std::env::var(var).unwrap_or_else(|_| panic!("{} must point to a wasm module; see scripts/integration-tests.sh", var));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+}
+
+fn user(n: u8) -> Principal {
+    Principal::self_authenticating([n])
+}
+
+struct Env {
+    pic: PocketIc,
+    backend: Principal,
+    ledger: Principal,
+    admin: Principal,
+    buyer: Principal,
+    seller: Principal,
+    asset_id: u64,
+}
+
+impl Env {
+    // Backend with an ICP payment ledger, three KYC-approved users and an
+    // approved ICP-priced asset of which the seller holds 10 tokens
+    fn new() -> Self {
+        let pic = PocketIc::new();
+        let (admin, buyer, seller, minter) = (user(1), user(2), user(3), user(4));
+
+        let ledger = pic.create_canister();
+        let ledger_init = LedgerArg::Init(LedgerInitArgs {
+            minting_account: Account::of(minter),
+            transfer_fee: Nat::from(LEDGER_FEE),
+            token_symbol: "ICP".to_string(),
+            token_name: "Internet Computer".to_string(),
+            metadata: vec![],
+            initial_balances: vec![(Account::of(buyer), Nat::from(INITIAL_BALANCE))],
+            feature_flags: Some(FeatureFlags { icrc2: true }),
+            archive_options: ArchiveOptions { num_blocks_to_archive: 1_000, trigger_threshold: 2_000, controller_id: minter },
+        });
+        pic.install_canister(ledger, wasm("ICRC1_LEDGER_WASM"), encode_one(ledger_init).unwrap(), None);
+
+        let backend = pic.create_canister();
+        let init = Some(InitArgs { admins: vec![admin] });
+        pic.install_canister(backend, wasm("RWA_BACKEND_WASM"), encode_one(init).unwrap(), None);
+
+        let mut env = Env { pic, backend, ledger, admin, buyer, seller, asset_id: 0 };
+        let expires_at = env.pic.get_time().as_nanos_since_unix_epoch() + NANOS_PER_YEAR;
+        for (who, name) in [(admin, "admin"), (buyer, "buyer"), (seller, "seller")] {
+            let _: Reply<IDLValue> = env.call(who, "register_user", (name, format!("{}@example.com", name), name));
+            let approval = KycApproval { tier: KycTier::Basic, country: "US".to_string(), accredited: false, provider_ref: None, expires_at };
+            let _: IDLValue = env.ok(admin, "approve_kyc", (who, approval));
+        }
+        let _: IDLValue = env.ok(admin, "set_payment_ledger", (Currency::ICP, ledger, LEDGER_FEE));
+
+        let asset: Asset = env.ok(admin, "create_asset", (
+            "Warehouse", "Logistics warehouse", "Industrial", "Rotterdam", Vec::<String>::new(), Vec::<String>::new(),
+            icp(1_000 * TOKEN_PRICE), icp(TOKEN_PRICE), 1_000u64, 500u32,
+            None::<u64>, None::<u64>, None::<Money>, None::<String>, None::<Reserved>,
+        ));
+        let _: Asset = env.ok(admin, "approve_asset", (asset.id,));
+        let _: u64 = env.ok(admin, "mint_token", (asset.id, seller, 10u64, TOKEN_PRICE));
+        env.asset_id = asset.id;
+        env
+    }
+
+    fn call<A: candid::utils::ArgumentEncoder, T: for<'de> Deserialize<'de> + CandidType>(&self, sender: Principal, method: &str, args: A) -> T {
+        let (reply,): (T,) = update_candid_as(&self.pic, self.backend, sender, method, args)
+            .unwrap_or_else(|e| panic!("{} was rejected: {}", method, e));
+        reply
+    }
+
+    fn ok<A: candid::utils::ArgumentEncoder, T: for<'de> Deserialize<'de> + CandidType>(&self, sender: Principal, method: &str, args: A) -> T {
+        self.call::<A, Reply<T>>(sender, method, args).unwrap_or_else(|e| panic!("{} failed: {}", method, e))
+    }
+
+    fn query<A: candid::utils::ArgumentEncoder, T: for<'de> Deserialize<'de> + CandidType>(&self, sender: Principal, method: &str, args: A) -> T {
+        let (reply,): (Reply<T>,) = query_candid_as(&self.pic, self.backend, sender, method, args)
+            .unwrap_or_else(|e| panic!("{} was rejected: {}", method, e));
+        reply.unwrap_or_else(|e| panic!("{} failed: {}", method, e))
+    }
+
+    // Same layout as the backend's escrow_subaccount
+    fn trade_escrow(&self, trade_id: u64) -> Account {
+        let mut subaccount = [0; 32];
+        subaccount[0] = ESCROW_TRADE;
+        subaccount[24..].copy_from_slice(&trade_id.to_be_bytes());
+        Account { owner: self.backend, subaccount: Some(subaccount) }
+    }
+
+    fn balance(&self, account: Account) -> u64 {
+        let (balance,): (Nat,) = query_candid_as(&self.pic, self.ledger, self.admin, "icrc1_balance_of", (account,)).unwrap();
+        u64::try_from(balance.0).unwrap()
+    }
+
+    // Lets the backend spend `amount` (plus a transfer fee) of `owner`'s ICP
+    fn approve(&self, owner: Principal, amount: u64) {
+        let args = ApproveArgs {
+            from_subaccount: None,
+            spender: Account::of(self.backend),
+            amount: Nat::from(amount + LEDGER_FEE),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Reply<Nat>,) = update_candid_as(&self.pic, self.ledger, owner, "icrc2_approve", (args,)).unwrap();
+        result.expect("icrc2_approve failed");
+    }
+
+    // Payouts are spawned after the call that triggers them returns
+    fn settle_payouts(&self) {
+        for _ in 0..10 {
+            self.pic.tick();
+        }
+    }
+
+    fn cash(&self, owner: Principal) -> CashBalance {
+        self.query(owner, "get_cash_balance", (owner, Currency::ICP))
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC and the ICRC-1 ledger wasm; run scripts/integration-tests.sh"]
+fn funded_trade_collects_into_escrow_and_pays_the_seller() {
+    let env = Env::new();
+    let quantity = 3;
+    let notional = quantity * TOKEN_PRICE;
+
+    let trade: Trade = env.ok(env.seller, "create_trade", (env.buyer, env.seller, env.asset_id, quantity, icp(TOKEN_PRICE)));
+    assert!(trade.escrow.as_ref().is_some_and(|e| e.asset_locked && !e.payment_locked));
+
+    env.approve(env.buyer, notional + LEDGER_FEE);
+    let trade: Trade = env.ok(env.buyer, "fund_trade", (trade.id,));
+    assert_eq!(trade.status, TradeStatus::Completed);
+    // Approve, then the pull of the notional plus the payout fee, each charged a fee
+    assert_eq!(env.balance(Account::of(env.buyer)), INITIAL_BALANCE - notional - 3 * LEDGER_FEE);
+
+    env.settle_payouts();
+    assert_eq!(env.balance(Account::of(env.seller)), notional);
+    assert_eq!(env.balance(env.trade_escrow(trade.id)), 0);
+    let pending: Vec<IDLValue> = env.query(env.admin, "list_pending_payouts", ());
+    assert!(pending.is_empty());
+}
+
+#[test]
+#[ignore = "needs PocketIC and the ICRC-1 ledger wasm; run scripts/integration-tests.sh"]
+fn cancelled_trade_refunds_the_collected_payment() {
+    let env = Env::new();
+    let quantity = 2;
+    let notional = quantity * TOKEN_PRICE;
+
+    let trade: Trade = env.ok(env.buyer, "create_trade", (env.buyer, env.seller, env.asset_id, quantity, icp(TOKEN_PRICE)));
+    env.approve(env.buyer, notional + LEDGER_FEE);
+    let trade: Trade = env.ok(env.buyer, "fund_trade", (trade.id,));
+    assert_eq!(trade.status, TradeStatus::Pending);
+    assert!(trade.escrow.as_ref().is_some_and(|e| e.payment_locked && !e.asset_locked));
+    assert_eq!(env.balance(env.trade_escrow(trade.id)), notional + LEDGER_FEE);
+
+    let trade: Trade = env.ok(env.buyer, "update_trade_status", (trade.id, TradeStatus::Cancelled, 0u64));
+    assert_eq!(trade.status, TradeStatus::Cancelled);
+    env.settle_payouts();
+    assert_eq!(env.balance(env.trade_escrow(trade.id)), 0);
+    // The buyer is out the approve, pull and refund fees only
+    assert_eq!(env.balance(Account::of(env.buyer)), INITIAL_BALANCE - 3 * LEDGER_FEE);
+}
+
+#[test]
+#[ignore = "needs PocketIC and the ICRC-1 ledger wasm; run scripts/integration-tests.sh"]
+fn deposit_and_withdraw_move_cash_through_the_ledger() {
+    let env = Env::new();
+    let deposit = 5 * TOKEN_PRICE;
+    let withdrawal = 2 * TOKEN_PRICE;
+
+    env.approve(env.buyer, deposit);
+    let cash: CashBalance = env.ok(env.buyer, "deposit_funds", (Currency::ICP, deposit));
+    assert_eq!(cash, CashBalance { available: deposit, reserved: 0 });
+    assert_eq!(env.balance(Account::of(env.backend)), deposit);
+
+    let _: u64 = env.ok(env.buyer, "withdraw", (Currency::ICP, withdrawal, None::<Account>));
+    assert_eq!(env.cash(env.buyer), CashBalance { available: deposit - withdrawal, reserved: 0 });
+    // The ledger fee comes out of the withdrawn amount
+    assert_eq!(env.balance(Account::of(env.buyer)), INITIAL_BALANCE - deposit - 2 * LEDGER_FEE + withdrawal - LEDGER_FEE);
+    assert_eq!(env.balance(Account::of(env.backend)), deposit - withdrawal);
+}
+
+#[test]
+#[ignore = "needs PocketIC and the ICRC-1 ledger wasm; run scripts/integration-tests.sh"]
+fn failed_withdrawal_is_credited_back() {
+    let env = Env::new();
+    let deposit = 5 * TOKEN_PRICE;
+
+    env.approve(env.buyer, deposit);
+    let _: CashBalance = env.ok(env.buyer, "deposit_funds", (Currency::ICP, deposit));
+    let ledger_balance = env.balance(Account::of(env.buyer));
+
+    // A stale fee makes the ledger refuse the transfer with BadFee
+    let _: IDLValue = env.ok(env.admin, "set_payment_ledger", (Currency::ICP, env.ledger, 2 * LEDGER_FEE));
+    let result: Reply<u64> = env.call(env.buyer, "withdraw", (Currency::ICP, TOKEN_PRICE, None::<Account>));
+    let error = result.expect_err("withdraw should fail on a fee mismatch");
+    assert!(error.to_string().contains("BadFee"), "unexpected error: {}", error);
+
+    assert_eq!(env.cash(env.buyer), CashBalance { available: deposit, reserved: 0 });
+    assert_eq!(env.balance(Account::of(env.buyer)), ledger_balance);
+    assert_eq!(env.balance(Account::of(env.backend)), deposit);
+    let journal: Vec<CashEntry> = env.query(env.buyer, "get_cash_journal", (env.buyer, None::<u64>, 10u64));
+    let reversal = journal.last().expect("journal is empty");
+    assert_eq!((reversal.memo.as_str(), reversal.amount), ("Withdrawal reversed", TOKEN_PRICE));
+}
@@ -0,0 +1,265 @@
+// ICRC-1/ICRC-2 ledger canister of a single RWA asset. It serves the standard
+// methods with their standard signatures and forwards each one to the backend,
+// which keeps the balances and applies the asset's transfer rules. Updates
+// name the original caller; reads are composite queries over the backend's.
+
+use candid::types::value::IDLValue;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::cell::RefCell;
+
+pub type Subaccount = [u8; 32];
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+// The backend's `ledger_info` record
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct LedgerInfo {
+    name: String,
+    symbol: String,
+    decimals: u8,
+    fee: Nat,
+    total_supply: Nat,
+    minting_account: Account,
+    metadata: Vec<(String, MetadataValue)>,
+}
+
+// Set at install by the backend that deploys this canister
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LedgerCanisterArgs {
+    pub backend: Principal,
+    pub asset_id: u64,
+}
+
+thread_local! {
+    static CONFIG: RefCell<Option<LedgerCanisterArgs>> = const { RefCell::new(None) };
+}
+
+fn config() -> LedgerCanisterArgs {
+    CONFIG.with(|config| config.borrow().clone()).unwrap_or_else(|| ic_cdk::trap("Ledger canister is not configured"))
+}
+
+#[ic_cdk::init]
+fn init(args: LedgerCanisterArgs) {
+    CONFIG.with(|config| *config.borrow_mut() = Some(args));
+}
+
+// The configuration is passed again on every upgrade; nothing else is kept
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: LedgerCanisterArgs) {
+    init(args);
+}
+
+// Reads a backend query, trapping with the backend's error since the ICRC
+// read methods have no error case
+async fn read<A: candid::utils::ArgumentEncoder, T: CandidType + for<'de> Deserialize<'de>>(method: &str, args: A) -> T {
+    let config = config();
+    let (result,): (Result<T, IDLValue>,) = ic_cdk::call(config.backend, method, args)
+        .await
+        .unwrap_or_else(|(code, message)| ic_cdk::trap(&format!("Backend call {} failed: {:?} {}", method, code, message)));
+    result.unwrap_or_else(|e| ic_cdk::trap(&format!("Backend call {} failed: {}", method, e)))
+}
+
+async fn info() -> LedgerInfo {
+    read("ledger_info", (config().asset_id,)).await
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_name() -> String {
+    info().await.name
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_symbol() -> String {
+    info().await.symbol
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_decimals() -> u8 {
+    info().await.decimals
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_fee() -> Nat {
+    info().await.fee
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_total_supply() -> Nat {
+    info().await.total_supply
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_minting_account() -> Option<Account> {
+    Some(info().await.minting_account)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    info().await.metadata
+}
+
+#[ic_cdk::query]
+fn icrc1_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc1_balance_of(account: Account) -> Nat {
+    read("ledger_balance_of", (config().asset_id, account)).await
+}
+
+#[ic_cdk::query(composite = true)]
+async fn icrc2_allowance(arg: AllowanceArgs) -> Allowance {
+    read("ledger_allowance", (config().asset_id, arg)).await
+}
+
+// A rejected backend call leaves the ledger untouched, so the caller may retry
+fn unavailable(method: &str, code: ic_cdk::api::call::RejectionCode, message: String) {
+    ic_cdk::println!("Backend call {} failed: {:?} {}", method, code, message);
+}
+
+#[ic_cdk::update]
+async fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let config = config();
+    match ic_cdk::call(config.backend, "ledger_transfer", (config.asset_id, ic_cdk::caller(), arg)).await {
+        Ok((result,)) => result,
+        Err((code, message)) => {
+            unavailable("ledger_transfer", code, message);
+            Err(TransferError::TemporarilyUnavailable)
+        }
+    }
+}
+
+#[ic_cdk::update]
+async fn icrc2_approve(arg: ApproveArgs) -> Result<Nat, ApproveError> {
+    let config = config();
+    match ic_cdk::call(config.backend, "ledger_approve", (config.asset_id, ic_cdk::caller(), arg)).await {
+        Ok((result,)) => result,
+        Err((code, message)) => {
+            unavailable("ledger_approve", code, message);
+            Err(ApproveError::TemporarilyUnavailable)
+        }
+    }
+}
+
+#[ic_cdk::update]
+async fn icrc2_transfer_from(arg: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let config = config();
+    match ic_cdk::call(config.backend, "ledger_transfer_from", (config.asset_id, ic_cdk::caller(), arg)).await {
+        Ok((result,)) => result,
+        Err((code, message)) => {
+            unavailable("ledger_transfer_from", code, message);
+            Err(TransferFromError::TemporarilyUnavailable)
+        }
+    }
+}